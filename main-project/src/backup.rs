@@ -8,52 +8,548 @@ use std::io::{Read, Write};
 use std::path::{Path, PathBuf};
 use sha2::{Sha256, Digest};
 use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Instant;
+use rayon::prelude::*;
 use chrono::Local;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::time::UNIX_EPOCH;
+use crate::chunker::Chunker;
+use crate::crypto;
+use crate::paths;
+use crate::replication::{self, ReplicaState};
+use crate::retention::RetentionPolicy;
+use crate::rules::RuleSet;
+use crate::verify::VerifySettings;
+use crate::s3::S3Settings;
+use crate::sftp::SftpSettings;
+use crate::webdav::WebdavSettings;
+use crate::storage::{self, StorageBackend};
 
-//calculates sha256 hash of a file for checking changes in files
-fn calculate_hash(path: &Path) -> Option<String> {
-    let mut file = File::open(path).ok()?;
-    let mut hasher = Sha256::new();
+// files at least this big are split into content-defined chunks instead of stored whole
+const CHUNKING_THRESHOLD: u64 = 8 * 1024 * 1024;
+
+//streams a file through f one buffer at a time
+fn read_blocks<F>(path: &Path, mut f: F) -> std::io::Result<()>
+where
+    F: FnMut(&[u8]) -> std::io::Result<()>,
+{
+    let mut file = File::open(path)?;
     let mut buffer = [0u8; 8192];
     loop {
-        let bytes_read = file.read(&mut buffer).ok()?;
+        let bytes_read = file.read(&mut buffer)?;
         if bytes_read == 0 { break; }
-        hasher.update(&buffer[..bytes_read]);
+        f(&buffer[..bytes_read])?;
     }
+    Ok(())
+}
+
+fn hash_bytes(data: &[u8]) -> String {
+    format!("{:x}", Sha256::digest(data))
+}
+
+//calculates sha256 hash of a file for checking changes in files
+fn calculate_hash(path: &Path) -> Option<String> {
+    let mut hasher = Sha256::new();
+    read_blocks(path, |block| {
+        hasher.update(block);
+        Ok(())
+    })
+    .ok()?;
     Some(format!("{:x}", hasher.finalize()))
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+//size in bytes and modification time (unix seconds) of a file
+fn file_stats(path: &Path) -> (u64, i64) {
+    match fs::metadata(path) {
+        Ok(meta) => {
+            let modified = meta
+                .modified()
+                .ok()
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs() as i64)
+                .unwrap_or(0);
+            (meta.len(), modified)
+        }
+        Err(_) => (0, 0),
+    }
+}
+
+/// what the file system reported about a file when it was last hashed. as long as none of
+/// it changes the contents are taken to be unchanged and the file isn't read again
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    // nanoseconds since the unix epoch
+    pub mtime: i64,
+    pub ctime: i64,
+    pub inode: u64,
+}
+
+impl FileStamp {
+    pub fn read(path: &Path) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+
+        let meta = fs::metadata(path).ok()?;
+        Some(Self {
+            size: meta.size(),
+            mtime: meta.mtime() * 1_000_000_000 + meta.mtime_nsec(),
+            ctime: meta.ctime() * 1_000_000_000 + meta.ctime_nsec(),
+            inode: meta.ino(),
+        })
+    }
+}
+
+pub fn default_destination() -> PathBuf {
+    home_dir().expect("Could not determine home directory").join("Backup")
+}
+
+/// how a stored object is encoded on disk
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Codec {
+    #[default]
+    None,
+    Zstd,
+}
+
+// objects are stored by content hash, e.g. objects/8c/8c105d... in the destination.
+// compressed objects get a .zst suffix so both kinds can be told apart on disk,
+// encrypted ones use a keyed name and a .enc suffix
+pub fn object_key(hash: &str, codec: Codec, encrypted: bool) -> std::io::Result<String> {
+    let mut name = if encrypted { crypto::object_name(hash)? } else { hash.to_string() };
+    if codec == Codec::Zstd {
+        name.push_str(".zst");
+    }
+    if encrypted {
+        name.push_str(".enc");
+    }
+    let prefix = name.get(..2).unwrap_or("00").to_string();
+    Ok(format!("objects/{}/{}", prefix, name))
+}
+
+//every object currently in the store
+pub fn stored_objects(storage: &dyn StorageBackend) -> std::io::Result<Vec<String>> {
+    storage.list("objects/")
+}
+
+//looks for an existing object with this hash, whatever codec it was stored with
+fn find_object(storage: &dyn StorageBackend, hash: &str, encrypted: bool) -> std::io::Result<Option<(String, Codec)>> {
+    for codec in [Codec::None, Codec::Zstd] {
+        let key = object_key(hash, codec, encrypted)?;
+        if storage.stat(&key)?.is_some() {
+            return Ok(Some((key, codec)));
+        }
+    }
+    Ok(None)
+}
+
+/// writes data into the object store unless an object with the same hash is already there.
+/// data is compressed first, then encrypted. returns the object key, the codec it is
+/// stored with and whether new data was written
+fn write_object<R: Read>(
+    storage: &dyn StorageBackend,
+    mut data: R,
+    hash: &str,
+    codec: Codec,
+    level: i32,
+    encrypted: bool,
+) -> std::io::Result<(String, Codec, bool)> {
+    if let Some((key, existing_codec)) = find_object(storage, hash, encrypted)? {
+        return Ok((key, existing_codec, false));
+    }
+
+    // objects are at most CHUNKING_THRESHOLD big, so they are fine to hold in memory
+    let mut bytes = Vec::new();
+    data.read_to_end(&mut bytes)?;
+    if codec == Codec::Zstd {
+        bytes = zstd::stream::encode_all(&bytes[..], level)?;
+    }
+    if encrypted {
+        bytes = crypto::encrypt(&bytes)?;
+    }
+
+    let key = object_key(hash, codec, encrypted)?;
+    storage.put(&key, &bytes)?;
+    Ok((key, codec, true))
+}
+
+//opens a stored object for reading, decrypting and decompressing it as needed
+fn open_object(storage: &dyn StorageBackend, key: &str, codec: Codec, encrypted: bool) -> std::io::Result<Box<dyn Read>> {
+    decode_object(storage.get(key)?, codec, encrypted)
+}
+
+/// turns the stored bytes of an object back into its contents. errors here (or while reading
+/// from the result) mean the object itself is damaged or the key is wrong
+pub fn decode_object(mut data: Vec<u8>, codec: Codec, encrypted: bool) -> std::io::Result<Box<dyn Read>> {
+    if encrypted {
+        data = crypto::decrypt(&data)?;
+    }
+    let reader: Box<dyn Read> = Box::new(std::io::Cursor::new(data));
+    Ok(match codec {
+        Codec::None => reader,
+        Codec::Zstd => Box::new(zstd::stream::read::Decoder::new(reader)?),
+    })
+}
+
+/// splits a file into content-defined chunks and stores the ones not already in the object store.
+/// returns the chunk manifest and how many chunks had to be written
+fn store_chunks(
+    storage: &dyn StorageBackend,
+    source: &Path,
+    codec: Codec,
+    level: i32,
+    encrypted: bool,
+) -> std::io::Result<(Vec<ChunkRef>, usize)> {
+    let mut chunks = Vec::new();
+    let mut written = 0;
+    let mut emit = |data: &[u8]| {
+        let hash = hash_bytes(data);
+        let (_, codec, new) = write_object(storage, data, &hash, codec, level, encrypted)?;
+        if new {
+            written += 1;
+        }
+        chunks.push(ChunkRef { hash, size: data.len() as u64, codec, encrypted });
+        Ok(())
+    };
+
+    let mut chunker = Chunker::new();
+    read_blocks(source, |block| chunker.update(block, &mut emit))?;
+    chunker.finish(&mut emit)?;
+    Ok((chunks, written))
+}
+
+/// a piece of a chunked file, stored as its own object
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ChunkRef {
+    pub hash: String,
+    pub size: u64,
+    #[serde(default)]
+    pub codec: Codec,
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+/// one stored copy of a file, taken during a snapshot
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileVersion {
+    pub snapshot_id: String,
+    pub hash: String,
+    pub size: u64,
+    pub modified: i64,
+    // key of the object in the destination (objects/..), empty for chunked versions, their
+    // data lives in the chunk objects
+    pub backup_path: PathBuf,
+    #[serde(default)]
+    pub chunks: Vec<ChunkRef>,
+    // codec and encryption of the object at backup_path, chunks record their own
+    #[serde(default)]
+    pub codec: Codec,
+    #[serde(default)]
+    pub encrypted: bool,
+}
+
+impl FileVersion {
+    //keys of every object this version needs to be restored. keys of encrypted chunks
+    //need the key
+    pub fn object_keys(&self) -> std::io::Result<Vec<String>> {
+        if self.chunks.is_empty() {
+            Ok(vec![self.backup_path.to_string_lossy().into_owned()])
+        } else {
+            self.chunks
+                .iter()
+                .map(|c| object_key(&c.hash, c.codec, c.encrypted))
+                .collect()
+        }
+    }
+
+    /// writes the contents of this version to destination, joining chunks back together
+    /// and decompressing them if needed
+    pub fn write_to(&self, storage: &dyn StorageBackend, destination: &Path) -> std::io::Result<()> {
+        let mut out = File::create(destination)?;
+        if self.chunks.is_empty() {
+            let key = self.backup_path.to_string_lossy();
+            std::io::copy(&mut open_object(storage, &key, self.codec, self.encrypted)?, &mut out)?;
+        } else {
+            for chunk in &self.chunks {
+                let key = object_key(&chunk.hash, chunk.codec, chunk.encrypted)?;
+                let mut data = open_object(storage, &key, chunk.codec, chunk.encrypted)?;
+                std::io::copy(&mut data, &mut out)?;
+            }
+        }
+        out.flush()
+    }
+}
+
+/// a file that was moved, detected by its hash showing up under a new path
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct FileRename {
+    pub from: PathBuf,
+    pub to: PathBuf,
+}
+
+/// a single backup run that stored, deleted or renamed at least one file
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Snapshot {
+    pub id: String,
+    pub timestamp: i64,
+    pub file_count: usize,
+    #[serde(default)]
+    pub deleted: Vec<PathBuf>,
+    #[serde(default)]
+    pub renamed: Vec<FileRename>,
+    // files that were marked deleted earlier and showed up again unchanged
+    #[serde(default)]
+    pub reappeared: Vec<PathBuf>,
+    // every destination holding all the objects of this snapshot, the main one first
+    #[serde(default)]
+    pub destinations: Vec<PathBuf>,
+}
+
+// what a backup run changed, recorded in its snapshot
+#[derive(Debug, Default)]
+struct RunChanges {
+    stored: Vec<PathBuf>,
+    deleted: Vec<PathBuf>,
+    renamed: Vec<FileRename>,
+    reappeared: Vec<PathBuf>,
+    // not part of the snapshot, but the metadata still has to be saved for them
+    restamped: usize,
+}
+
+impl RunChanges {
+    fn count(&self) -> usize {
+        self.stored.len() + self.deleted.len() + self.renamed.len() + self.reappeared.len()
+    }
+
+    fn needs_save(&self) -> bool {
+        self.count() > 0 || self.restamped > 0
+    }
+}
+
+// backup_path and hash always mirror the newest entry in versions
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct FileInfo {
     pub original_path: PathBuf,
     pub backup_path: PathBuf,
     pub file_type: String,
     #[serde(default)]
     pub hash: String,
+    #[serde(default)]
+    pub versions: Vec<FileVersion>,
+    // snapshot in which the original was found missing, None while it still exists
+    #[serde(default)]
+    pub deleted_in: Option<String>,
+    // taken right before the last hash, None until the file has been hashed once
+    #[serde(default)]
+    pub stamp: Option<FileStamp>,
 }
 
-impl Default for FileInfo {
-    fn default() -> Self {
-        Self {
-            original_path: PathBuf::new(),
-            backup_path: PathBuf::new(),
-            file_type: String::new(),
-            hash: String::new(),
+impl FileInfo {
+    //true if the file looks exactly like it did when its current hash was taken
+    fn matches_stamp(&self, stamp: Option<&FileStamp>) -> bool {
+        !self.hash.is_empty() && !self.versions.is_empty() && stamp.is_some() && self.stamp.as_ref() == stamp
+    }
+
+    pub fn find_version(&self, snapshot_id: &str) -> Option<&FileVersion> {
+        self.versions.iter().find(|v| v.snapshot_id == snapshot_id)
+    }
+
+    //the newest version stored at or before the snapshot at position `at`, see
+    //BackupMetadata::snapshot_order. deletions aren't considered, see BackupMetadata::state_at
+    pub fn version_at(&self, at: i64, order: &HashMap<&str, i64>) -> Option<&FileVersion> {
+        self.versions
+            .iter()
+            .rev()
+            .find(|v| snapshot_position(order, &v.snapshot_id) <= at)
+    }
+
+    //old metadata only had a single overwritten copy, keep it as the first version
+    fn migrate_legacy_copy(&mut self) {
+        if !self.versions.is_empty() || self.backup_path.as_os_str().is_empty() {
+            return;
         }
+        let (size, modified) = file_stats(&self.backup_path);
+        self.versions.push(FileVersion {
+            snapshot_id: LEGACY_SNAPSHOT_ID.to_string(),
+            hash: self.hash.clone(),
+            size,
+            modified,
+            backup_path: self.backup_path.clone(),
+            chunks: Vec::new(),
+            codec: Codec::None,
+            encrypted: false,
+        });
     }
+
+    //older metadata recorded absolute object paths, objects are now looked up by key in
+    //whatever destination is configured
+    fn use_object_keys(&mut self) {
+        let paths = self
+            .versions
+            .iter_mut()
+            .map(|v| &mut v.backup_path)
+            .chain([&mut self.backup_path]);
+        for path in paths {
+            if path.is_absolute()
+                && let Some(key) = storage::object_key_of(path)
+            {
+                *path = PathBuf::from(key);
+            }
+        }
+    }
+
+    //records a version the workers stored as the newest one
+    fn push_version(&mut self, version: FileVersion) {
+        self.backup_path = version.backup_path.clone();
+        self.hash = version.hash.clone();
+        self.versions.push(version);
+    }
+}
+
+fn file_type_of(path: &Path) -> String {
+    path.extension()
+        .map(|e| e.to_string_lossy().to_string())
+        .unwrap_or_else(|| "unknown".to_string())
+}
+
+// stores the current contents of a file in the object store. it only writes objects and
+// doesn't touch the metadata, so the workers can run it for many files at once
+fn store_file(
+    storage: &dyn StorageBackend,
+    path: &Path,
+    file_type: &str,
+    snapshot_id: &str,
+    hash: String,
+    settings: &BackupSettings,
+) -> std::io::Result<FileVersion> {
+    let (size, modified) = file_stats(path);
+    let wanted_codec = settings.codec_for(file_type);
+    let level = settings.compression_level;
+    let encrypted = settings.encryption_enabled;
+
+    // big files are chunked so only the changed parts get written again
+    let (dest_path, chunks, codec) = if size >= CHUNKING_THRESHOLD {
+        let (chunks, written) = store_chunks(storage, path, wanted_codec, level, encrypted)?;
+        println!("Chunked: {} ({} chunks, {} new)", path.display(), chunks.len(), written);
+        (PathBuf::new(), chunks, Codec::None)
+    } else {
+        let source = File::open(path)?;
+        let (dest_path, codec, written) = write_object(storage, source, &hash, wanted_codec, level, encrypted)?;
+        if !written {
+            println!("Deduplicated: {} (object {})", path.display(), hash);
+        }
+        (PathBuf::from(dest_path), Vec::new(), codec)
+    };
+
+    Ok(FileVersion {
+        snapshot_id: snapshot_id.to_string(),
+        hash,
+        size,
+        modified,
+        backup_path: dest_path,
+        chunks,
+        codec,
+        encrypted,
+    })
+}
+
+const LEGACY_SNAPSHOT_ID: &str = "legacy";
+
+// position of a version's snapshot in the history. versions without a snapshot record
+// (legacy copies) count as older than every snapshot
+fn snapshot_position(order: &HashMap<&str, i64>, snapshot_id: &str) -> i64 {
+    order.get(snapshot_id).copied().unwrap_or(-1)
+}
+
+/// a folder the user picked for backup
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct BackupRoot {
+    pub path: PathBuf,
+    // gitignore style rules for this folder only, on top of the global ones
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
 }
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
 pub struct BackupMetadata {
     pub files: HashMap<PathBuf, FileInfo>,
+    #[serde(default)]
+    pub snapshots: Vec<Snapshot>,
+    #[serde(default)]
+    pub roots: Vec<BackupRoot>,
+    // unix time of the last run that rehashed every file regardless of its stamp
+    #[serde(default)]
+    pub last_full_hash: i64,
+    // where the objects of the newest versions are, empty for metadata from before the
+    // destination could be changed (those always went to the default folder)
+    #[serde(default)]
+    pub destination: PathBuf,
+    // sync state of every replica destination
+    #[serde(default)]
+    pub replicas: Vec<ReplicaState>,
+    // unix time of the last verify run, see verify.rs
+    #[serde(default)]
+    pub last_verify: i64,
+}
+
+// formats that are already compressed, zstd would only waste time on them
+const DEFAULT_UNCOMPRESSED_TYPES: &[&str] = &[
+    "png", "jpg", "jpeg", "gif", "webp", "heic", "mp3", "aac", "m4a", "mp4", "mov", "mkv",
+    "avi", "zip", "gz", "tgz", "bz2", "xz", "7z", "rar", "zst", "pdf",
+];
+
+// junk that is never worth backing up, in gitignore syntax
+const DEFAULT_EXCLUDES: &[&str] = &[".DS_Store", ".localized", "Thumbs.db", "target/", "node_modules/"];
+
+/// how the daemon notices changes. polling rescans everything every interval, watch reacts
+/// to file system events and keeps the interval scan as a consistency sweep
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ChangeDetection {
+    #[default]
+    Polling,
+    Watch,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct BackupSettings {
     pub auto_backup_enabled: bool,
     pub interval_minutes: u64,
+    pub change_detection: ChangeDetection,
     pub dark_mode: bool,
+    pub compression_enabled: bool,
+    pub compression_level: i32,
+    pub uncompressed_types: Vec<String>,
+    pub encryption_enabled: bool,
+    pub retention: RetentionPolicy,
+    // rehash every file now and then even if its size, times and inode look unchanged,
+    // catches edits that kept the mtime and silent corruption
+    pub paranoid_hash_enabled: bool,
+    pub paranoid_interval_hours: u64,
+    // rereads part of the stored objects now and then to catch bit rot
+    pub verify: VerifySettings,
+    // threads hashing files and threads storing them. 0 means one per cpu core, on a
+    // spinning disk 1 for both avoids making the drive seek back and forth
+    pub worker_threads: usize,
+    pub io_threads: usize,
+    pub global_excludes: Vec<String>,
+    pub global_includes: Vec<String>,
+    // also apply .gitignore and .backupignore files found in the backup folders
+    pub honor_ignore_files: bool,
+    // where backups go, e.g. a folder on an external drive or a NAS mount
+    pub destination: PathBuf,
+    // more destinations that get a full copy after every run, e.g. a usb drive
+    pub replicas: Vec<PathBuf>,
+    // server and credentials for s3://bucket/prefix destinations
+    pub s3: S3Settings,
+    // ssh key and known hosts for sftp://user@host/path destinations
+    pub sftp: SftpSettings,
+    // login for dav://host/path and davs://host/path destinations
+    pub webdav: WebdavSettings,
 }
 
 impl Default for BackupSettings {
@@ -61,25 +557,65 @@ impl Default for BackupSettings {
         Self {
             auto_backup_enabled: false,
             interval_minutes: 60,
+            change_detection: ChangeDetection::Polling,
             dark_mode: false,
+            compression_enabled: false,
+            compression_level: 3,
+            uncompressed_types: DEFAULT_UNCOMPRESSED_TYPES.iter().map(|t| t.to_string()).collect(),
+            encryption_enabled: false,
+            retention: RetentionPolicy::default(),
+            paranoid_hash_enabled: false,
+            paranoid_interval_hours: 24,
+            verify: VerifySettings::default(),
+            worker_threads: 0,
+            io_threads: 4,
+            global_excludes: DEFAULT_EXCLUDES.iter().map(|p| p.to_string()).collect(),
+            global_includes: Vec::new(),
+            honor_ignore_files: true,
+            destination: default_destination(),
+            replicas: Vec::new(),
+            s3: S3Settings::default(),
+            sftp: SftpSettings::default(),
+            webdav: WebdavSettings::default(),
         }
     }
 }
 
 impl BackupSettings {
+    //whether this run should hash every file instead of trusting unchanged stamps
+    pub fn full_hash_due(&self, metadata: &BackupMetadata) -> bool {
+        let interval = self.paranoid_interval_hours.saturating_mul(3600) as i64;
+        self.paranoid_hash_enabled && Local::now().timestamp() - metadata.last_full_hash >= interval
+    }
+
+    //picks the codec for a file based on its extension
+    pub fn codec_for(&self, file_type: &str) -> Codec {
+        let skip = self
+            .uncompressed_types
+            .iter()
+            .any(|t| t.eq_ignore_ascii_case(file_type));
+        if self.compression_enabled && !skip {
+            Codec::Zstd
+        } else {
+            Codec::None
+        }
+    }
+
     pub fn load_from_file() -> std::io::Result<Self> {
-        let path = "backup_settings.json";
-        if let Ok(mut f) = File::open(path) {
+        let path = paths::settings_file();
+        let settings = if let Ok(mut f) = File::open(path) {
             let mut contents = String::new();
             f.read_to_string(&mut contents)?;
-            Ok(serde_json::from_str(&contents).unwrap_or_default())
+            serde_json::from_str(&contents).unwrap_or_default()
         } else {
-            Ok(BackupSettings::default())
-        }
+            BackupSettings::default()
+        };
+        Ok(settings)
     }
 
     pub fn save_to_file(&self) -> std::io::Result<()> {
-        let path = "backup_settings.json";
+        let path = paths::settings_file();
+        paths::ensure_parent(&path)?;
         let file = File::create(path)?;
         serde_json::to_writer_pretty(&file, self)?;
         Ok(())
@@ -88,58 +624,767 @@ impl BackupSettings {
 
 impl BackupMetadata {
     pub fn load_from_file() -> std::io::Result<Self> {
-        let path = "backup_metadata.json";
+        let path = paths::metadata_file();
         if let Ok(mut f) = File::open(path) {
-            let mut contents = String::new();
-            f.read_to_string(&mut contents)?;
+            let mut raw = Vec::new();
+            f.read_to_end(&mut raw)?;
+
+            // an encrypted metadata file needs the key, fail instead of pretending it's empty
+            if crypto::is_sealed(&raw) {
+                raw = crypto::unseal(&raw)?;
+            }
+            let contents = String::from_utf8_lossy(&raw).to_string();
             
             if let Ok(vec) = serde_json::from_str::<Vec<FileInfo>>(&contents) {
                 let mut files = HashMap::new();
                 for mut file_info in vec {
-                    if file_info.hash.is_empty() {
-                        if let Some(hash) = calculate_hash(&file_info.original_path) {
-                            file_info.hash = hash;
-                        }
+                    if file_info.hash.is_empty()
+                        && let Some(hash) = calculate_hash(&file_info.original_path)
+                    {
+                        file_info.hash = hash;
                     }
+                    file_info.migrate_legacy_copy();
+                    file_info.use_object_keys();
                     files.insert(file_info.original_path.clone(), file_info);
                 }
-                return Ok(BackupMetadata { files });
+                return Ok(BackupMetadata { files, ..Default::default() });
             }
             
-            Ok(serde_json::from_str(&contents).unwrap_or_default())
+            let mut metadata: BackupMetadata = serde_json::from_str(&contents).unwrap_or_default();
+            for file_info in metadata.files.values_mut() {
+                file_info.migrate_legacy_copy();
+                file_info.use_object_keys();
+            }
+            Ok(metadata)
         } else {
             Ok(BackupMetadata::default())
         }
     }
 
-    pub fn save_to_file(&self) -> std::io::Result<()> {
-        let path = "backup_metadata.json";
-        let file = File::create(path)?;
-        serde_json::to_writer_pretty(&file, self)?;
-        Ok(())
+    /// writes the metadata file, sealed with the repository key if encrypted is set (see
+    /// BackupSettings::encryption_enabled)
+    pub fn save_to_file(&self, encrypted: bool) -> std::io::Result<()> {
+        let path = paths::metadata_file();
+        paths::ensure_parent(&path)?;
+        fs::write(path, self.to_bytes(encrypted)?)
+    }
+
+    /// saves the metadata file and its copy in the destination, for operations that just
+    /// used the destination and know it's there
+    pub fn save(&self, storage: &dyn StorageBackend, settings: &BackupSettings) -> std::io::Result<()> {
+        self.save_to_file(settings.encryption_enabled)?;
+        self.save_copy(storage, &settings.destination, settings.encryption_enabled)
+    }
+
+    /// writes a copy of the metadata next to the objects in target, so that destination can
+    /// be restored from on its own. in the copy every snapshot is in destination, which has
+    /// no replicas of its own
+    pub fn save_copy(&self, target: &dyn StorageBackend, destination: &Path, encrypted: bool) -> std::io::Result<()> {
+        let mut copy = self.clone();
+        copy.destination = destination.to_path_buf();
+        copy.replicas.clear();
+        for snapshot in copy.snapshots.iter_mut().filter(|s| !s.destinations.iter().any(|d| d == destination)) {
+            snapshot.destinations.push(destination.to_path_buf());
+        }
+        target.put(paths::METADATA_FILE, &copy.to_bytes(encrypted)?)
+    }
+
+    /// the metadata as json, sealed with the repository key if encrypted is set
+    pub fn to_bytes(&self, encrypted: bool) -> std::io::Result<Vec<u8>> {
+        let json = serde_json::to_vec_pretty(self)?;
+        if encrypted {
+            return crypto::seal(&json);
+        }
+        Ok(json)
+    }
+
+    /// checks the destination from the settings before a run. when it differs from the one the
+    /// files were backed up to so far, the objects are copied over from the old one. if that
+    /// fails the current contents of every file are stored again on this run and older
+    /// versions are only left in the old destination. returns true if the destination changed
+    fn open_destination(&mut self, target: &dyn StorageBackend, settings: &BackupSettings) -> std::io::Result<bool> {
+        target.check(self.destination == settings.destination)?;
+        let previous = if self.destination.as_os_str().is_empty() {
+            default_destination()
+        } else {
+            self.destination.clone()
+        };
+        if previous == settings.destination || self.files.is_empty() {
+            self.destination = settings.destination.clone();
+            return Ok(false);
+        }
+
+        println!(
+            "Backup destination changed from {} to {}",
+            previous.display(),
+            settings.destination.display()
+        );
+        let source = storage::open_with(&previous, settings);
+        let copied = crypto::copy_key_config(source.as_ref(), target).and_then(|_| {
+            replication::copy_objects(source.as_ref(), target, &self.referenced_objects()?, settings.io_threads)
+        });
+        match copied {
+            Ok(count) => println!("Copied {} object(s) from {}", count, previous.display()),
+            Err(e) => {
+                println!("Can't copy the backup from {} ({}), current files will be stored again", previous.display(), e);
+                // an empty hash makes the run store the file no matter what its stamp says
+                for info in self.files.values_mut().filter(|f| f.deleted_in.is_none()) {
+                    info.hash.clear();
+                }
+            }
+        }
+        self.destination = settings.destination.clone();
+        Ok(true)
+    }
+
+    //returns false if the folder was already tracked
+    pub fn add_root(&mut self, path: &Path) -> bool {
+        if self.roots.iter().any(|r| r.path == path) {
+            return false;
+        }
+        self.roots.push(BackupRoot { path: path.to_path_buf(), ..Default::default() });
+        true
+    }
+
+    //the innermost backup folder containing the path
+    pub fn root_for(&self, path: &Path) -> Option<&BackupRoot> {
+        self.roots
+            .iter()
+            .filter(|r| path.starts_with(&r.path))
+            .max_by_key(|r| r.path.components().count())
+    }
+
+    /// moves copies from the old flat layout (~/Backup/<relative path>) into the object store.
+    /// in that layout files with the same name from different folders overwrote each other,
+    /// so a copy whose hash no longer matches is dropped and the file is backed up again.
+    /// returns how many versions were changed
+    fn migrate_flat_copies(&mut self, storage: &dyn StorageBackend, settings: &BackupSettings) -> std::io::Result<usize> {
+        // objects are referenced by key since loading, only the flat copies in the default
+        // folder still have an absolute path
+        let mut changed = 0;
+        let mut old_copies = HashSet::new();
+
+        for info in self.files.values_mut() {
+            let mut kept_versions = Vec::with_capacity(info.versions.len());
+            for mut version in std::mem::take(&mut info.versions) {
+                let flat = version.chunks.is_empty() && version.backup_path.is_absolute();
+                if !flat {
+                    kept_versions.push(version);
+                    continue;
+                }
+
+                changed += 1;
+                old_copies.insert(version.backup_path.clone());
+                match calculate_hash(&version.backup_path) {
+                    Some(actual) if actual == version.hash => {
+                        let source = File::open(&version.backup_path)?;
+                        let (key, codec, _) = write_object(
+                            storage,
+                            source,
+                            &actual,
+                            settings.codec_for(&info.file_type),
+                            settings.compression_level,
+                            settings.encryption_enabled,
+                        )?;
+                        println!("Migrated: {} -> {}", version.backup_path.display(), key);
+                        version.backup_path = PathBuf::from(key);
+                        version.codec = codec;
+                        version.encrypted = settings.encryption_enabled;
+                        kept_versions.push(version);
+                    }
+                    Some(_) => println!(
+                        "Backup copy {} belongs to another file with the same name, {} will be backed up again",
+                        version.backup_path.display(),
+                        info.original_path.display()
+                    ),
+                    None => println!("Backup copy missing: {}", version.backup_path.display()),
+                }
+            }
+            info.versions = kept_versions;
+
+            // nothing usable left means the next run has to store it from scratch
+            match info.versions.last() {
+                Some(latest) => info.backup_path = latest.backup_path.clone(),
+                None => {
+                    info.backup_path = PathBuf::new();
+                    info.hash.clear();
+                }
+            }
+        }
+
+        // the copies are plain files next to the object store, not objects
+        for path in old_copies {
+            if path.exists() {
+                fs::remove_file(&path)?;
+                println!("Deleted: {}", path.display());
+            }
+        }
+        Ok(changed)
+    }
+
+    /// the newest snapshot taken at or before the given unix time
+    pub fn snapshot_at(&self, timestamp: i64) -> Option<&Snapshot> {
+        self.snapshots.iter().rev().find(|s| s.timestamp <= timestamp)
+    }
+
+    /// position of every snapshot in the history. snapshots are appended in the order they
+    /// were taken, so unlike their ids (local time, with -1, -2.. for runs in the same second)
+    /// this stays in order across DST and timezone changes
+    pub fn snapshot_order(&self) -> HashMap<&str, i64> {
+        self.snapshots
+            .iter()
+            .enumerate()
+            .map(|(i, s)| (s.id.as_str(), i as i64))
+            .collect()
     }
+
+    //where a file was right after the snapshot at position `at`, renames recorded by later
+    //snapshots are undone newest first
+    fn path_at(&self, path: &Path, at: usize) -> PathBuf {
+        let mut path = path.to_path_buf();
+        for snapshot in self.snapshots.iter().skip(at + 1).rev() {
+            if let Some(rename) = snapshot.renamed.iter().find(|r| r.to == path) {
+                path = rename.from.clone();
+            }
+        }
+        path
+    }
+
+    //the version a file had right after the snapshot at position `at` and its path back
+    //then, None if it didn't exist at that point. unlike FileInfo::version_at this also
+    //knows about deletions and the file coming back from them
+    fn state_at<'a>(&self, info: &'a FileInfo, at: usize, order: &HashMap<&str, i64>) -> Option<(&'a FileVersion, PathBuf)> {
+        let version = info.version_at(at as i64, order)?;
+        let path = self.path_at(&info.original_path, at);
+        let listed = |paths: &[PathBuf]| paths.contains(&path) || paths.contains(&info.original_path);
+        // replay deletions and reappearances since the version was stored, oldest first
+        let stored_at = snapshot_position(order, &version.snapshot_id);
+        let mut present = true;
+        for snapshot in self.snapshots.iter().take(at + 1).skip((stored_at + 1) as usize) {
+            if listed(&snapshot.deleted) {
+                present = false;
+            }
+            if listed(&snapshot.reappeared) {
+                present = true;
+            }
+        }
+        present.then_some((version, path))
+    }
+
+    //keys of all objects some version still needs
+    pub fn referenced_objects(&self) -> std::io::Result<HashSet<String>> {
+        let mut keys = HashSet::new();
+        for version in self.files.values().flat_map(|f| f.versions.iter()) {
+            keys.extend(version.object_keys()?);
+        }
+        Ok(keys)
+    }
+
+    //starts a new snapshot id, unique even if two runs land in the same second
+    fn next_snapshot_id(&self) -> String {
+        let base = Local::now().format("%Y%m%d-%H%M%S").to_string();
+        let mut id = base.clone();
+        let mut n = 1;
+        while self.snapshots.iter().any(|s| s.id == id) {
+            id = format!("{}-{}", base, n);
+            n += 1;
+        }
+        id
+    }
+
+    fn record_snapshot(&mut self, id: String, changes: RunChanges) {
+        if changes.count() > 0 {
+            self.snapshots.push(Snapshot {
+                id,
+                timestamp: Local::now().timestamp(),
+                file_count: changes.stored.len(),
+                deleted: changes.deleted,
+                renamed: changes.renamed,
+                reappeared: changes.reappeared,
+                destinations: vec![self.destination.clone()],
+            });
+        }
+    }
+
+    //tracked files whose original is gone, keyed by their last hash so renames can be matched.
+    //files under a backup folder that is missing as a whole (unmounted drive) don't count
+    fn missing_files(&self) -> HashMap<String, Vec<PathBuf>> {
+        let unavailable: Vec<&Path> = self
+            .roots
+            .iter()
+            .map(|r| r.path.as_path())
+            .filter(|p| !p.exists())
+            .collect();
+
+        let mut missing: HashMap<String, Vec<PathBuf>> = HashMap::new();
+        for info in self.files.values() {
+            if info.deleted_in.is_some()
+                || info.original_path.exists()
+                || unavailable.iter().any(|root| info.original_path.starts_with(root))
+            {
+                continue;
+            }
+            missing.entry(info.hash.clone()).or_default().push(info.original_path.clone());
+        }
+        missing
+    }
+
+    //moves a file's history to its new path
+    fn rename_file(&mut self, from: &Path, to: &Path) {
+        if let Some(mut info) = self.files.remove(from) {
+            info.original_path = to.to_path_buf();
+            self.files.insert(to.to_path_buf(), info);
+        }
+    }
+
+    //marks every file still missing as deleted in this snapshot
+    fn record_deletions(&mut self, missing: HashMap<String, Vec<PathBuf>>, snapshot_id: &str, changes: &mut RunChanges) {
+        for path in missing.into_values().flatten() {
+            if let Some(info) = self.files.get_mut(&path) {
+                info.deleted_in = Some(snapshot_id.to_string());
+                println!("Deleted since last backup: {}", path.display());
+                changes.deleted.push(path);
+            }
+        }
+    }
+}
+
+pub fn delete_selected(storage: &dyn StorageBackend, key: &str) -> std::io::Result<()> {
+    if key.is_empty() {
+        return Ok(());
+    }
+    if storage.stat(key)?.is_some() {
+        storage.delete(key)?;
+        println!("Deleted: {}", key);
+    }
+    Ok(())
 }
 
-pub fn update_file_info(files: Vec<FileInfo>) -> std::io::Result<()> {
-    let mut metadata = BackupMetadata::default();
-    for file in files {
-        metadata.files.insert(file.original_path.clone(), file);
+// removes every stored version of a file, objects in in_use are kept
+fn delete_versions(storage: &dyn StorageBackend, info: &FileInfo, in_use: &HashSet<String>) -> std::io::Result<()> {
+    for version in &info.versions {
+        for key in version.object_keys()? {
+            if in_use.contains(&key) {
+                println!("Kept shared object: {}", key);
+            } else {
+                delete_selected(storage, &key)?;
+            }
+        }
     }
-    metadata.save_to_file()
+    let key = info.backup_path.to_string_lossy().into_owned();
+    if info.versions.is_empty() && !in_use.contains(&key) {
+        delete_selected(storage, &key)?;
+    }
+    Ok(())
+}
+
+/// stops tracking a file and deletes its stored versions, objects other files share are
+/// kept. works on freshly loaded metadata under the repository lock, so whatever another
+/// process saved meanwhile stays. returns the metadata as saved
+pub fn delete_file(path: &Path) -> std::io::Result<BackupMetadata> {
+    let _lock = paths::lock_repository()?;
+    let settings = BackupSettings::load_from_file()?;
+    let mut metadata = BackupMetadata::load_from_file()?;
+    let info = metadata.files.remove(path).ok_or_else(|| {
+        std::io::Error::new(std::io::ErrorKind::NotFound, format!("{} is not in the backup", path.display()))
+    })?;
+    // metadata first, like pruning: a delete that stops halfway leaves orphans behind
+    // instead of versions whose objects are gone
+    let storage = storage::open(&settings);
+    let in_use = metadata.referenced_objects()?;
+    metadata.save(storage.as_ref(), &settings)?;
+    delete_versions(storage.as_ref(), &info, &in_use)?;
+    Ok(metadata)
 }
 
-pub fn delete_selected(selected_file: PathBuf) -> std::io::Result<()> {
-    if selected_file.exists() {
-        fs::remove_file(&selected_file)?;
-        println!("Deleted: {}", selected_file.display());
+/// copies a stored version back out of the backup.
+/// snapshot_id = None restores the newest version. existing files are never overwritten.
+pub fn restore_file(
+    storage: &dyn StorageBackend,
+    info: &FileInfo,
+    snapshot_id: Option<&str>,
+    destination: &Path,
+) -> std::io::Result<()> {
+    let version = match snapshot_id {
+        Some(id) => info.find_version(id),
+        None => info.versions.last(),
+    }
+    .ok_or_else(|| std::io::Error::new(
+        std::io::ErrorKind::NotFound,
+        format!("No version {} for {}", snapshot_id.unwrap_or("(latest)"), info.original_path.display()),
+    ))?;
+
+    if destination.exists() {
+        return Err(std::io::Error::new(
+            std::io::ErrorKind::AlreadyExists,
+            format!("destination already exists ({})", destination.display()),
+        ));
+    }
+
+    // make sure parent directory exists
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    // a half written file would block the next attempt
+    if let Err(e) = version.write_to(storage, destination) {
+        let _ = fs::remove_file(destination);
+        return Err(e);
     }
+    println!("Restored: {} from snapshot {}", destination.display(), version.snapshot_id);
     Ok(())
 }
 
+/// what a restore does when something is already where a file goes
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ConflictPolicy {
+    #[default]
+    Skip,
+    // the file in the way is renamed to a safety copy first, e.g. notes (before restore 20250101-120000).txt
+    Overwrite,
+    // the restored file goes next to it, named after its snapshot, e.g. notes (20250101-120000).txt
+    KeepBoth,
+    // overwrites like Overwrite, but only if the backed up version was modified later
+    NewerWins,
+    // lets the caller decide for each file
+    Ask,
+}
+
+impl ConflictPolicy {
+    pub const ALL: [ConflictPolicy; 5] = [
+        ConflictPolicy::Skip,
+        ConflictPolicy::Overwrite,
+        ConflictPolicy::KeepBoth,
+        ConflictPolicy::NewerWins,
+        ConflictPolicy::Ask,
+    ];
+}
+
+impl std::fmt::Display for ConflictPolicy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConflictPolicy::Skip => write!(f, "Skip it"),
+            ConflictPolicy::Overwrite => write!(f, "Overwrite, keep a safety copy"),
+            ConflictPolicy::KeepBoth => write!(f, "Keep both"),
+            ConflictPolicy::NewerWins => write!(f, "Overwrite if the backup is newer"),
+            ConflictPolicy::Ask => write!(f, "Ask for each file"),
+        }
+    }
+}
+
+/// a file a restore would replace, handed to the ask callback
+#[derive(Debug, Clone)]
+pub struct RestoreConflict {
+    pub destination: PathBuf,
+    pub snapshot_id: String,
+    // modification times (unix seconds) of the backed up version and of the file in the way
+    pub backup_modified: i64,
+    pub existing_modified: i64,
+}
+
+//a unix time as local date and minute, e.g. 2025-01-07 14:00
+fn local_time(timestamp: i64) -> String {
+    match chrono::DateTime::from_timestamp(timestamp, 0) {
+        Some(time) => time.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string(),
+        None => timestamp.to_string(),
+    }
+}
+
+/// which state of the files a restore brings back. files deleted by then are left out, files
+/// deleted later are included
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum RestorePoint {
+    // the newest versions
+    #[default]
+    Latest,
+    // right after this snapshot
+    Snapshot(String),
+    // at this unix time, i.e. right after the newest snapshot taken by then
+    AsOf(i64),
+}
+
+impl std::fmt::Display for RestorePoint {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RestorePoint::Latest => write!(f, "Newest versions"),
+            RestorePoint::Snapshot(id) => write!(f, "Snapshot {}", id),
+            RestorePoint::AsOf(time) => write!(f, "As of {}", local_time(*time)),
+        }
+    }
+}
+
+/// which files a folder or snapshot restore brings back and where they go
+#[derive(Debug, Clone, Default)]
+pub struct RestoreRequest {
+    // only files that were at or below this path at the restore point, None restores every
+    // tracked file
+    pub folder: Option<PathBuf>,
+    pub point: RestorePoint,
+    // folder to restore into, None puts every file back at its original path
+    pub target: Option<PathBuf>,
+    pub conflicts: ConflictPolicy,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum RestoreOutcome {
+    Restored,
+    // the file that was in the way got moved to this safety copy
+    Replaced(PathBuf),
+    // restored under another name next to the file that was in the way
+    KeptBoth,
+    // the file in the way already has the backed up contents
+    Unchanged,
+    Skipped(String),
+    Failed(String),
+}
+
+/// what happened to one file of a restore
+#[derive(Debug, Clone)]
+pub struct RestoredFile {
+    pub original_path: PathBuf,
+    // where the file ended up, or would have for skipped and failed ones
+    pub destination: PathBuf,
+    pub snapshot_id: String,
+    pub outcome: RestoreOutcome,
+}
+
+impl RestoredFile {
+    pub fn describe(&self) -> String {
+        let destination = self.destination.display();
+        match &self.outcome {
+            RestoreOutcome::Restored => format!("Restored {}", destination),
+            RestoreOutcome::Replaced(safety_copy) => {
+                format!("Replaced {}, the old file is now {}", destination, safety_copy.display())
+            }
+            RestoreOutcome::KeptBoth => format!("Restored {} next to the existing file", destination),
+            RestoreOutcome::Unchanged => format!("Unchanged {} (already the same)", destination),
+            RestoreOutcome::Skipped(reason) => format!("Skipped {} ({})", destination, reason),
+            RestoreOutcome::Failed(reason) => format!("Failed {} ({})", destination, reason),
+        }
+    }
+}
+
+/// the per file results of a restore, sorted by original path
+#[derive(Debug, Clone, Default)]
+pub struct RestoreReport {
+    pub files: Vec<RestoredFile>,
+}
+
+impl RestoreReport {
+    fn count(&self, f: impl Fn(&RestoreOutcome) -> bool) -> usize {
+        self.files.iter().filter(|file| f(&file.outcome)).count()
+    }
+
+    //everything that was written, whether something was in the way or not
+    pub fn restored(&self) -> usize {
+        self.count(|o| matches!(o, RestoreOutcome::Restored | RestoreOutcome::Replaced(_) | RestoreOutcome::KeptBoth))
+    }
+
+    pub fn skipped(&self) -> usize {
+        self.count(|o| matches!(o, RestoreOutcome::Skipped(_)))
+    }
+
+    pub fn failed(&self) -> usize {
+        self.count(|o| matches!(o, RestoreOutcome::Failed(_)))
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "Restored {} file(s) ({} replaced, {} kept next to an existing file), {} unchanged, skipped {}, failed {}",
+            self.restored(),
+            self.count(|o| matches!(o, RestoreOutcome::Replaced(_))),
+            self.count(|o| *o == RestoreOutcome::KeptBoth),
+            self.count(|o| *o == RestoreOutcome::Unchanged),
+            self.skipped(),
+            self.failed()
+        )
+    }
+}
+
+//a free path next to path with a label before the extension, e.g. notes (label).txt. a
+//number is added to the label while that is taken too
+fn labeled_path(path: &Path, label: &str) -> PathBuf {
+    let stem = path
+        .file_stem()
+        .map(|s| s.to_string_lossy().to_string())
+        .unwrap_or_default();
+    (1..)
+        .map(|n| {
+            let label = if n == 1 { label.to_string() } else { format!("{} {}", label, n) };
+            let name = match path.extension() {
+                Some(ext) => format!("{} ({}).{}", stem, label, ext.to_string_lossy()),
+                None => format!("{} ({})", stem, label),
+            };
+            path.with_file_name(name)
+        })
+        .find(|candidate| !candidate.exists())
+        .expect("some numbered name is free")
+}
+
+//restores version over whatever is at destination according to policy
+fn settle_conflict(
+    storage: &dyn StorageBackend,
+    info: &FileInfo,
+    version: &FileVersion,
+    destination: &Path,
+    policy: ConflictPolicy,
+    ask: &(dyn Fn(&RestoreConflict) -> ConflictPolicy + Sync),
+) -> std::io::Result<(PathBuf, RestoreOutcome)> {
+    let (size, existing_modified) = file_stats(destination);
+    if destination.is_file() && size == version.size && calculate_hash(destination).as_deref() == Some(version.hash.as_str()) {
+        return Ok((destination.to_path_buf(), RestoreOutcome::Unchanged));
+    }
+
+    let policy = match policy {
+        ConflictPolicy::Ask => ask(&RestoreConflict {
+            destination: destination.to_path_buf(),
+            snapshot_id: version.snapshot_id.clone(),
+            backup_modified: version.modified,
+            existing_modified,
+        }),
+        policy => policy,
+    };
+    match policy {
+        ConflictPolicy::Skip | ConflictPolicy::Ask => {
+            Ok((destination.to_path_buf(), RestoreOutcome::Skipped(String::from("already exists"))))
+        }
+        ConflictPolicy::NewerWins if version.modified <= existing_modified => {
+            Ok((destination.to_path_buf(), RestoreOutcome::Skipped(String::from("current file is newer"))))
+        }
+        ConflictPolicy::Overwrite | ConflictPolicy::NewerWins => {
+            let label = format!("before restore {}", Local::now().format("%Y%m%d-%H%M%S"));
+            let safety_copy = labeled_path(destination, &label);
+            fs::rename(destination, &safety_copy)?;
+            if let Err(e) = restore_file(storage, info, Some(&version.snapshot_id), destination) {
+                let _ = fs::rename(&safety_copy, destination);
+                return Err(e);
+            }
+            Ok((destination.to_path_buf(), RestoreOutcome::Replaced(safety_copy)))
+        }
+        ConflictPolicy::KeepBoth => {
+            let path = labeled_path(destination, &version.snapshot_id);
+            restore_file(storage, info, Some(&version.snapshot_id), &path)?;
+            Ok((path, RestoreOutcome::KeptBoth))
+        }
+    }
+}
+
+/// restores one version of a file to destination. if something is already there the policy
+/// decides what happens, ConflictPolicy::Ask calls ask for the policy to use
+pub fn restore_version(
+    storage: &dyn StorageBackend,
+    info: &FileInfo,
+    version: &FileVersion,
+    destination: &Path,
+    policy: ConflictPolicy,
+    ask: &(dyn Fn(&RestoreConflict) -> ConflictPolicy + Sync),
+) -> RestoredFile {
+    let result = if destination.exists() {
+        settle_conflict(storage, info, version, destination, policy, ask)
+    } else {
+        restore_file(storage, info, Some(&version.snapshot_id), destination)
+            .map(|_| (destination.to_path_buf(), RestoreOutcome::Restored))
+    };
+    let (destination, outcome) = result.unwrap_or_else(|e| (destination.to_path_buf(), RestoreOutcome::Failed(e.to_string())));
+    RestoredFile {
+        original_path: info.original_path.clone(),
+        destination,
+        snapshot_id: version.snapshot_id.clone(),
+        outcome,
+    }
+}
+
+//where a file goes when restoring into target. the restored folder (or the file's backup
+//folder when restoring everything) is recreated inside target with everything below it
+fn restore_destination(metadata: &BackupMetadata, path: &Path, folder: Option<&Path>, target: &Path) -> PathBuf {
+    let base = match folder {
+        Some(folder) => folder.parent(),
+        None => metadata.root_for(path).and_then(|root| root.path.parent()),
+    };
+    let below = path
+        .strip_prefix(base.unwrap_or(Path::new("/")))
+        .or_else(|_| path.strip_prefix("/"))
+        .unwrap_or(path);
+    target.join(below)
+}
+
+/// restores a folder or a whole snapshot, see RestoreRequest. files are written on the io
+/// threads and progress(done, total) is called after each one. conflicts are settled by the
+/// request's policy, ask is only called for ConflictPolicy::Ask. deleted files restored to
+/// their original path are tracked again by the next backup run, which records that they
+/// reappeared
+pub fn restore_tree(
+    request: &RestoreRequest,
+    progress: impl Fn(usize, usize) + Sync,
+    ask: impl Fn(&RestoreConflict) -> ConflictPolicy + Sync,
+) -> std::io::Result<RestoreReport> {
+    let settings = BackupSettings::load_from_file()?;
+    let metadata = BackupMetadata::load_from_file()?;
+    let storage = storage::open(&settings);
+
+    let order = metadata.snapshot_order();
+    let at = match &request.point {
+        RestorePoint::Latest => None,
+        RestorePoint::Snapshot(id) => {
+            let position = order.get(id.as_str()).ok_or_else(|| {
+                std::io::Error::new(std::io::ErrorKind::NotFound, format!("No snapshot {}", id))
+            })?;
+            Some(*position as usize)
+        }
+        RestorePoint::AsOf(time) => {
+            let snapshot = metadata.snapshot_at(*time).ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::NotFound,
+                    format!("No backup was taken by {}", local_time(*time)),
+                )
+            })?;
+            Some(order[snapshot.id.as_str()] as usize)
+        }
+    };
+
+    // each file with the version to restore and the path it had at the restore point
+    let mut planned: Vec<(&FileInfo, &FileVersion, PathBuf)> = metadata
+        .files
+        .values()
+        .filter_map(|info| {
+            let (version, path) = match at {
+                Some(at) => metadata.state_at(info, at, &order)?,
+                None if info.deleted_in.is_some() => return None,
+                None => (info.versions.last()?, info.original_path.clone()),
+            };
+            Some((info, version, path))
+        })
+        .filter(|(_, _, path)| match &request.folder {
+            Some(folder) => path.starts_with(folder),
+            None => true,
+        })
+        .collect();
+    planned.sort_by(|a, b| a.2.cmp(&b.2));
+
+    let total = planned.len();
+    let done = AtomicUsize::new(0);
+    progress(0, total);
+    let files: Vec<RestoredFile> = worker_pool(settings.io_threads).install(|| {
+        planned
+            .par_iter()
+            .map(|(info, version, path)| {
+                let destination = match &request.target {
+                    Some(target) => restore_destination(&metadata, path, request.folder.as_deref(), target),
+                    None => path.clone(),
+                };
+                let mut file = restore_version(storage.as_ref(), info, version, &destination, request.conflicts, &ask);
+                file.original_path = path.clone();
+                progress(done.fetch_add(1, Ordering::SeqCst) + 1, total);
+                file
+            })
+            .collect()
+    });
+    Ok(RestoreReport { files })
+}
+
 pub fn select_folder() -> Option<PathBuf> {
     if let Some(home) = home_dir() {
-        let backup_folder = home.join("Backup");
-        fs::create_dir_all(&backup_folder).unwrap();
         FileDialog::new().set_directory(&home).pick_folder()
     } else {
         println!("Could not determine home directory.");
@@ -147,64 +1392,207 @@ pub fn select_folder() -> Option<PathBuf> {
     }
 }
 
-//does the initial backup of a selected folder
-pub fn backup(selected_folder: &Path) -> std::io::Result<()> {
-    let home = home_dir().expect("Could not determine home directory");
-    let backup_folder = home.join("Backup");
-    fs::create_dir_all(&backup_folder)?;
+//asks for the folder backups should go to, starting at the current one
+pub fn select_destination(current: &Path) -> Option<PathBuf> {
+    let start = if current.is_dir() { current.to_path_buf() } else { home_dir()? };
+    FileDialog::new().set_directory(start).pick_folder()
+}
+
+/// checks the destination in these settings the same way a backup run would
+pub fn check_settings_destination(settings: &BackupSettings) -> std::io::Result<()> {
+    storage::open_with(&settings.destination, settings).check(settings_destination_used(settings))
+}
 
-    let mut metadata = BackupMetadata::load_from_file().unwrap_or_default();
+/// the same without writing anything, for showing whether the destination is usable
+pub fn inspect_settings_destination(settings: &BackupSettings) -> std::io::Result<()> {
+    storage::open_with(&settings.destination, settings).check_readonly(settings_destination_used(settings))
+}
 
-    for entry in WalkDir::new(selected_folder).into_iter().filter_map(|e| e.ok()) {
-        let path = entry.path();
-        let relative_path = path.strip_prefix(selected_folder).unwrap();
-        let dest_path = backup_folder.join(relative_path);
+// whether earlier backups went to the configured destination
+fn settings_destination_used(settings: &BackupSettings) -> bool {
+    BackupMetadata::load_from_file()
+        .map(|m| m.destination == settings.destination)
+        .unwrap_or(false)
+}
 
-        if path.is_dir() {
-            fs::create_dir_all(&dest_path)?;
-            continue;
-        }
+//a pool of worker threads, 0 threads means one per cpu core
+pub fn worker_pool(threads: usize) -> rayon::ThreadPool {
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .build()
+        .expect("Failed to start backup worker threads")
+}
 
-        if path.is_file() {
-            if let Some(parent) = dest_path.parent() {
-                fs::create_dir_all(parent)?;
+/// walks a backup folder and lists its files. files and folders excluded by the rules
+/// are skipped without being read
+fn collect_files(root: &BackupRoot, settings: &BackupSettings) -> Vec<PathBuf> {
+    let mut rules = RuleSet::new(root, settings);
+    WalkDir::new(&root.path)
+        .into_iter()
+        .filter_entry(|e| e.path() == root.path || !rules.is_excluded(e.path(), e.file_type().is_dir()))
+        .filter_map(|e| e.ok())
+        .filter(|e| e.path().is_file())
+        .map(|e| e.into_path())
+        .collect()
+}
+
+/// backs up a batch of existing files. new files are added, or matched against a missing
+/// file with the same hash as a rename of it, and tracked files get a new version if they
+/// changed. a tracked file is only rehashed if its stamp changed, or always with full_hash.
+/// hashing and storing run on the worker pools, the metadata is only updated from here
+#[allow(clippy::too_many_arguments)]
+fn process_files(
+    storage: &dyn StorageBackend,
+    metadata: &mut BackupMetadata,
+    paths: Vec<PathBuf>,
+    snapshot_id: &str,
+    settings: &BackupSettings,
+    full_hash: bool,
+    missing: &mut HashMap<String, Vec<PathBuf>>,
+    changes: &mut RunChanges,
+) {
+    let mut to_hash = Vec::new();
+    for path in paths {
+        let stamp = FileStamp::read(&path);
+        match metadata.files.get_mut(&path) {
+            Some(info) if !full_hash && info.matches_stamp(stamp.as_ref()) => {
+                // a deleted file that came back is tracked again
+                if info.deleted_in.take().is_some() {
+                    println!("Reappeared: {}", path.display());
+                    changes.reappeared.push(path.clone());
+                }
+                println!("No changes in {}", path.display());
             }
+            _ => to_hash.push((path, stamp)),
+        }
+    }
 
-            let new_hash = calculate_hash(path);
-            let existing = metadata.files.get(&path.to_path_buf());
+    let hashed: Vec<(PathBuf, Option<FileStamp>, Option<String>)> = worker_pool(settings.worker_threads).install(|| {
+        to_hash
+            .into_par_iter()
+            .map(|(path, stamp)| {
+                let hash = calculate_hash(&path);
+                (path, stamp, hash)
+            })
+            .collect()
+    });
 
-            //only copy if file changed or don't exist in backup
-            let should_copy = match existing {
-                Some(old) if !old.hash.is_empty() => Some(&old.hash) != new_hash.as_ref(),
-                _ => true,
-            };
+    let mut to_store = Vec::new();
+    for (path, stamp, hash) in hashed {
+        let Some(hash) = hash else {
+            println!("Hash check failed for {}", path.display());
+            continue;
+        };
 
-            if should_copy {
-                fs::copy(path, &dest_path)?;
-                println!("Copied: {}", dest_path.display());
+        let Some(info) = metadata.files.get_mut(&path) else {
+            if let Some(old_path) = missing.get_mut(&hash).and_then(|paths| paths.pop()) {
+                println!("Renamed: {} -> {}", old_path.display(), path.display());
+                metadata.rename_file(&old_path, &path);
+                if let Some(info) = metadata.files.get_mut(&path) {
+                    info.stamp = stamp;
+                }
+                changes.renamed.push(FileRename { from: old_path, to: path });
             } else {
-                println!("Skipped (unchanged): {}", path.display());
+                to_store.push((path, stamp, hash));
             }
+            continue;
+        };
 
-            let file_type = path
-                .extension()
-                .map(|e| e.to_string_lossy().to_string())
-                .unwrap_or_else(|| "unknown".to_string());
-
-            // update metadata with  anew hash
-            if let Some(hash) = new_hash {
-                let file_info = FileInfo {
-                    original_path: path.to_path_buf(),
-                    backup_path: dest_path,
-                    file_type,
-                    hash,
-                };
-                metadata.files.insert(path.to_path_buf(), file_info);
+        if info.deleted_in.take().is_some() {
+            println!("Reappeared: {}", path.display());
+            changes.reappeared.push(path.clone());
+        }
+        if info.matches_stamp(stamp.as_ref()) && info.hash != hash {
+            println!("Changed without a new modification time: {}", path.display());
+        }
+
+        //only store a new version if file changed or don't exist in backup
+        if info.hash.is_empty() || info.hash != hash || info.versions.is_empty() {
+            to_store.push((path, stamp, hash));
+        } else {
+            // e.g. only touched, remember the new stamp so it isn't read again
+            if info.stamp != stamp {
+                info.stamp = stamp;
+                changes.restamped += 1;
             }
+            println!("No changes in {}", path.display());
+        }
+    }
+
+    let stored: Vec<_> = worker_pool(settings.io_threads).install(|| {
+        to_store
+            .into_par_iter()
+            .map(|(path, stamp, hash)| {
+                let file_type = file_type_of(&path);
+                let version = store_file(storage, &path, &file_type, snapshot_id, hash, settings);
+                (path, file_type, stamp, version)
+            })
+            .collect()
+    });
+
+    for (path, file_type, stamp, version) in stored {
+        let version = match version {
+            Ok(version) => version,
+            Err(e) => {
+                println!("Backup error ({}): {}", path.display(), e);
+                continue;
+            }
+        };
+        let info = metadata.files.entry(path.clone()).or_insert_with(|| FileInfo {
+            original_path: path.clone(),
+            file_type,
+            ..Default::default()
+        });
+        let is_new = info.versions.is_empty();
+        info.deleted_in = None;
+        info.push_version(version);
+        info.stamp = stamp;
+
+        if is_new {
+            println!("New file: {}", path.display());
+        } else {
+            println!(
+                "[{}] Backed up: {}",
+                Local::now().format("%Y-%m-%d %H:%M:%S"),
+                path.display()
+            );
         }
+        changes.stored.push(path);
+    }
+}
+
+//does the initial backup of a selected folder
+pub fn backup(selected_folder: &Path) -> std::io::Result<()> {
+    let settings = BackupSettings::load_from_file().unwrap_or_default();
+    if settings.encryption_enabled && !crypto::is_unlocked() {
+        return Err(crypto::locked("Encryption is enabled but the backup is locked"));
+    }
+    let _lock = paths::lock_repository()?;
+    let storage = storage::open(&settings);
+    let mut metadata = BackupMetadata::load_from_file()?;
+    metadata.open_destination(storage.as_ref(), &settings)?;
+    let snapshot_id = metadata.next_snapshot_id();
+    metadata.migrate_flat_copies(storage.as_ref(), &settings)?;
+
+    // remember the folder so later runs pick up files added to it
+    if metadata.add_root(selected_folder) {
+        println!("Tracking new backup folder: {}", selected_folder.display());
     }
+    let root = metadata
+        .roots
+        .iter()
+        .find(|r| r.path == selected_folder)
+        .cloned()
+        .unwrap_or_default();
+    let files = collect_files(&root, &settings);
+    let mut missing = metadata.missing_files();
+    let mut changes = RunChanges::default();
+    process_files(storage.as_ref(), &mut metadata, files, &snapshot_id, &settings, false, &mut missing, &mut changes);
+    metadata.record_deletions(missing, &snapshot_id, &mut changes);
 
-    metadata.save_to_file()?;
+    metadata.record_snapshot(snapshot_id, changes);
+    replication::replicate(&mut metadata, storage.as_ref(), &settings);
+    metadata.save(storage.as_ref(), &settings)?;
     println!("Metadata updated successfully.");
 
     Ok(())
@@ -212,58 +1600,150 @@ pub fn backup(selected_folder: &Path) -> std::io::Result<()> {
 
 //backup files that have changes
 pub fn backup_now(metadata_arc: Arc<Mutex<BackupMetadata>>) -> Result<usize, String> {
-    let mut backed_up_count = 0;
-    
-    let mut metadata = metadata_arc.lock().map_err(|e| format!("Lock error: {}", e))?;
+    let _lock = paths::lock_repository().map_err(|e| format!("Lock error: {}", e))?;
+    // the run works on a fresh copy, the shared one may be older than what another process
+    // saved meanwhile. the mutex is only held to put the result back
+    let mut metadata = BackupMetadata::load_from_file().map_err(|e| format!("Failed to load metadata: {}", e))?;
     println!("[{}] Running immediate backup...", Local::now().format("%Y-%m-%d %H:%M:%S"));
+    let started = Instant::now();
+    let snapshot_id = metadata.next_snapshot_id();
+    let settings = BackupSettings::load_from_file().unwrap_or_default();
+    if settings.encryption_enabled && !crypto::is_unlocked() {
+        return Err("Encryption is enabled but the backup is locked".to_string());
+    }
+    let storage = storage::open(&settings);
+    let moved = metadata.open_destination(storage.as_ref(), &settings).map_err(|e| e.to_string())?;
 
-    for info in metadata.files.values_mut() {
-        if !info.original_path.exists() {
-            println!("Original file missing: {}", info.original_path.display());
-            continue;
+    let migrated = match metadata.migrate_flat_copies(storage.as_ref(), &settings) {
+        Ok(count) => count,
+        Err(e) => {
+            println!("Failed to migrate old backup copies: {}", e);
+            0
         }
+    };
 
-        if let Some(parent) = info.backup_path.parent() {
-            if let Err(e) = fs::create_dir_all(parent) {
-                println!("Failed to create backup directory: {}", e);
-                continue;
-            }
-        }
+    // normally only files whose stamp changed get read, a paranoid run reads them all
+    let full_hash = settings.full_hash_due(&metadata);
+    if full_hash {
+        println!("Running full hash check of all files");
+    }
 
-        match calculate_hash(&info.original_path) {
-            Some(current_hash) => {
-                // if hash is empty or different, we need to backup
-                let needs_backup = info.hash.is_empty() || current_hash != info.hash;
-                
-                if needs_backup {
-                    if let Err(e) = fs::copy(&info.original_path, &info.backup_path) {
-                        println!("Backup error ({}): {}", info.original_path.display(), e);
-                    } else {
-                        println!(
-                            "[{}] Backed up: {}",
-                            Local::now().format("%Y-%m-%d %H:%M:%S"),
-                            info.original_path.display()
-                        );
-                        info.hash = current_hash;
-                        backed_up_count += 1;
-                    }
-                } else {
-                    println!("No changes in {}", info.original_path.display());
+    // rescan the backup folders so files created or renamed since the last run get picked
+    // up, tracked files from before folders were remembered are checked as well. whatever
+    // is still missing afterwards was deleted
+    let mut files: Vec<PathBuf> = metadata
+        .roots
+        .iter()
+        .flat_map(|root| collect_files(root, &settings))
+        .collect();
+    files.extend(
+        metadata
+            .files
+            .keys()
+            .filter(|path| metadata.root_for(path).is_none() && path.is_file())
+            .cloned(),
+    );
+    // nested backup folders would list their files twice
+    files.sort();
+    files.dedup();
+
+    let mut missing = metadata.missing_files();
+    let mut changes = RunChanges::default();
+    process_files(storage.as_ref(), &mut metadata, files, &snapshot_id, &settings, full_hash, &mut missing, &mut changes);
+    metadata.record_deletions(missing, &snapshot_id, &mut changes);
+
+    if full_hash {
+        metadata.last_full_hash = Local::now().timestamp();
+    }
+
+    // only save if we actually backed up something (or learned new stamps)
+    let backed_up_count = changes.stored.len();
+    let mut needs_save = changes.needs_save() || migrated > 0 || full_hash || moved;
+    metadata.record_snapshot(snapshot_id, changes);
+
+    // copy the new state to the replicas, catching up any that were offline before
+    needs_save |= replication::replicate(&mut metadata, storage.as_ref(), &settings);
+
+    let mut shared = metadata_arc.lock().map_err(|e| format!("Lock error: {}", e))?;
+    *shared = metadata;
+    let saved = if needs_save {
+        shared.save(storage.as_ref(), &settings)
+    } else {
+        // the copy in the destination is written anyway, the destination may not have one yet
+        shared.save_copy(storage.as_ref(), &settings.destination, settings.encryption_enabled)
+    };
+    if let Err(e) = saved {
+        println!("Failed to save updated metadata: {}", e);
+        return Err(format!("Failed to save metadata: {}", e));
+    }
+
+    println!(
+        "Backup complete: {} file(s) backed up in {:.1}s",
+        backed_up_count,
+        started.elapsed().as_secs_f64()
+    );
+    Ok(backed_up_count)
+}
+
+/// backs up only the given paths instead of scanning everything, used by the watcher.
+/// folders are scanned as a whole, tracked files under a path that is gone count as
+/// deleted (or renamed, if the same content shows up elsewhere in the batch)
+pub fn backup_changed(paths: &[PathBuf]) -> std::io::Result<usize> {
+    let settings = BackupSettings::load_from_file().unwrap_or_default();
+    if settings.encryption_enabled && !crypto::is_unlocked() {
+        return Err(crypto::locked("Encryption is enabled but the backup is locked"));
+    }
+    let _lock = paths::lock_repository()?;
+    let storage = storage::open(&settings);
+    let mut metadata = BackupMetadata::load_from_file()?;
+    metadata.open_destination(storage.as_ref(), &settings)?;
+    let snapshot_id = metadata.next_snapshot_id();
+
+    // only paths inside a backup folder that the rules don't exclude are looked at
+    let mut rules: HashMap<PathBuf, RuleSet> = HashMap::new();
+    let mut candidates: Vec<PathBuf> = Vec::new();
+    let mut gone: HashSet<PathBuf> = HashSet::new();
+    for path in paths {
+        let Some(root) = metadata.root_for(path) else { continue };
+        let rules = rules
+            .entry(root.path.clone())
+            .or_insert_with(|| RuleSet::new(root, &settings));
+
+        if path.is_dir() {
+            let walker = WalkDir::new(path)
+                .into_iter()
+                .filter_entry(|e| !rules.is_excluded(e.path(), e.file_type().is_dir()));
+            for entry in walker.filter_map(|e| e.ok()) {
+                if entry.file_type().is_file() {
+                    candidates.push(entry.path().to_path_buf());
                 }
             }
-            None => println!("Hash check failed for {}", info.original_path.display()),
+        } else if path.is_file() {
+            if !rules.is_excluded(path, false) {
+                candidates.push(path.clone());
+            }
+        } else {
+            gone.extend(metadata.files.keys().filter(|f| f.starts_with(path)).cloned());
         }
     }
+    candidates.sort();
+    candidates.dedup();
 
-    // only save if we actually backed up something
-    if backed_up_count > 0 {
-        if let Err(e) = metadata.save_to_file() {
-            println!("Failed to save updated metadata: {}", e);
-            return Err(format!("Failed to save metadata: {}", e));
-        }
+    let mut missing = metadata.missing_files();
+    for paths in missing.values_mut() {
+        paths.retain(|p| gone.contains(p));
     }
+    let mut changes = RunChanges::default();
+    process_files(storage.as_ref(), &mut metadata, candidates, &snapshot_id, &settings, false, &mut missing, &mut changes);
+    metadata.record_deletions(missing, &snapshot_id, &mut changes);
 
-    println!("Backup complete: {} file(s) backed up", backed_up_count);
+    // replicas get the new objects right away, copying only what they lack keeps that cheap
+    let backed_up_count = changes.stored.len();
+    if changes.needs_save() {
+        metadata.record_snapshot(snapshot_id, changes);
+        replication::replicate(&mut metadata, storage.as_ref(), &settings);
+        metadata.save(storage.as_ref(), &settings)?;
+    }
     Ok(backed_up_count)
 }
 
@@ -281,7 +1761,414 @@ pub fn auto_backup() -> std::io::Result<()> {
         Err(e) => {
             eprintln!("[{}] Auto-backup failed: {}", 
                 Local::now().format("%Y-%m-%d %H:%M:%S"), e);
-            Err(std::io::Error::new(std::io::ErrorKind::Other, e))
+            Err(std::io::Error::other(e))
+        }
+    }
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::StorageBackend;
+
+    fn tracked(path: &str, snapshot_id: &str) -> FileInfo {
+        let mut info = FileInfo { original_path: PathBuf::from(path), ..Default::default() };
+        info.push_version(FileVersion {
+            snapshot_id: snapshot_id.to_string(),
+            hash: format!("hash of {}", path),
+            ..Default::default()
+        });
+        info
+    }
+
+    #[test]
+    fn restore_as_of_includes_files_that_reappeared() {
+        let path = PathBuf::from("/share/docs/a.txt");
+        let mut metadata = BackupMetadata::default();
+        metadata.files.insert(path.clone(), tracked("/share/docs/a.txt", "20250101-100000"));
+        metadata.record_snapshot(
+            "20250101-100000".to_string(),
+            RunChanges { stored: vec![path.clone()], ..Default::default() },
+        );
+        // the share was gone for one run and came back unchanged
+        metadata.record_snapshot(
+            "20250102-100000".to_string(),
+            RunChanges { deleted: vec![path.clone()], ..Default::default() },
+        );
+        metadata.record_snapshot(
+            "20250103-100000".to_string(),
+            RunChanges { reappeared: vec![path.clone()], ..Default::default() },
+        );
+        metadata.record_snapshot(
+            "20250104-100000".to_string(),
+            RunChanges { stored: vec![PathBuf::from("/share/other.txt")], ..Default::default() },
+        );
+
+        let order = metadata.snapshot_order();
+        let info = &metadata.files[&path];
+        assert!(metadata.state_at(info, 0, &order).is_some());
+        assert!(metadata.state_at(info, 1, &order).is_none());
+        let (version, restored_path) = metadata.state_at(info, 3, &order).unwrap();
+        assert_eq!(version.snapshot_id, "20250101-100000");
+        assert_eq!(restored_path, path);
+    }
+
+    #[test]
+    fn history_is_ordered_by_position_not_by_id() {
+        let path = PathBuf::from("/home/me/notes.txt");
+        let mut metadata = BackupMetadata::default();
+        let mut info = tracked("/home/me/notes.txt", "20251026-025900");
+        // an hour later on the clock, but after the switch back from summer time
+        let after_dst = "20251026-020500";
+        // the eleventh run in one second sorts before the third one as a string
+        let same_second = ["20251026-021000", "20251026-021000-1", "20251026-021000-2", "20251026-021000-10"];
+        for id in [after_dst].iter().chain(&same_second) {
+            info.push_version(FileVersion { snapshot_id: id.to_string(), ..Default::default() });
+        }
+        metadata.files.insert(path.clone(), info);
+        for id in ["20251026-025900", after_dst].iter().chain(&same_second) {
+            metadata.record_snapshot(id.to_string(), RunChanges { stored: vec![path.clone()], ..Default::default() });
+        }
+
+        let order = metadata.snapshot_order();
+        let info = &metadata.files[&path];
+        assert_eq!(metadata.state_at(info, 0, &order).unwrap().0.snapshot_id, "20251026-025900");
+        assert_eq!(metadata.state_at(info, 1, &order).unwrap().0.snapshot_id, after_dst);
+        assert_eq!(metadata.state_at(info, 4, &order).unwrap().0.snapshot_id, "20251026-021000-2");
+        assert_eq!(metadata.state_at(info, 5, &order).unwrap().0.snapshot_id, "20251026-021000-10");
+    }
+
+    #[test]
+    fn renames_after_the_restore_point_are_undone() {
+        let mut metadata = BackupMetadata::default();
+        metadata.files.insert(PathBuf::from("/d/new.txt"), tracked("/d/new.txt", "b"));
+        metadata.record_snapshot("b".to_string(), RunChanges { stored: vec![PathBuf::from("/d/old.txt")], ..Default::default() });
+        let rename = FileRename { from: PathBuf::from("/d/old.txt"), to: PathBuf::from("/d/new.txt") };
+        // "a" sorts before "b" but was taken after it
+        metadata.record_snapshot("a".to_string(), RunChanges { renamed: vec![rename], ..Default::default() });
+
+        let order = metadata.snapshot_order();
+        let info = &metadata.files[Path::new("/d/new.txt")];
+        assert_eq!(metadata.state_at(info, 0, &order).unwrap().1, PathBuf::from("/d/old.txt"));
+        assert_eq!(metadata.state_at(info, 1, &order).unwrap().1, PathBuf::from("/d/new.txt"));
+    }
+
+    #[test]
+    fn backup_restore_and_delete_go_through_the_storage_backend() {
+        let sandbox = storage::testing::sandbox();
+        let folder = sandbox.dir.join("docs");
+        let file = folder.join("a.txt");
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(&file, "first").unwrap();
+
+        backup(&folder).unwrap();
+        fs::write(&file, "second!").unwrap();
+        let metadata = BackupMetadata::load_from_file().unwrap();
+        assert_eq!(backup_now(Arc::new(Mutex::new(metadata))).unwrap(), 1);
+
+        // both versions are in the backend, nothing was written to the destination folder
+        let metadata = BackupMetadata::load_from_file().unwrap();
+        let info = &metadata.files[&file];
+        let mut stored = sandbox.backend.list("objects/").unwrap();
+        stored.sort();
+        let mut expected: Vec<String> = info.versions.iter().flat_map(|v| v.object_keys().unwrap()).collect();
+        expected.sort();
+        assert_eq!(stored, expected);
+        assert!(!sandbox.dir.join("dest/objects").exists());
+
+        let restored = sandbox.dir.join("restored.txt");
+        restore_file(sandbox.backend.as_ref(), info, Some(&info.versions[0].snapshot_id), &restored).unwrap();
+        assert_eq!(fs::read_to_string(&restored).unwrap(), "first");
+
+        delete_file(&file).unwrap();
+        assert!(sandbox.backend.list("objects/").unwrap().is_empty());
+        assert!(BackupMetadata::load_from_file().unwrap().files.is_empty());
+    }
+
+    #[test]
+    fn deleting_a_file_keeps_everything_else() {
+        let sandbox = storage::testing::sandbox();
+        let folder = sandbox.dir.join("docs");
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(folder.join("a.txt"), "same").unwrap();
+        fs::write(folder.join("copy.txt"), "same").unwrap();
+        backup(&folder).unwrap();
+        // e.g. the gui's copy, from before the next backup
+        let stale = BackupMetadata::load_from_file().unwrap();
+        fs::write(folder.join("new.txt"), "new").unwrap();
+        backup_now(Arc::new(Mutex::new(stale))).unwrap();
+
+        let metadata = delete_file(&folder.join("a.txt")).unwrap();
+
+        let mut left: Vec<_> = metadata.files.keys().cloned().collect();
+        left.sort();
+        assert_eq!(left, vec![folder.join("copy.txt"), folder.join("new.txt")]);
+        assert_eq!(BackupMetadata::load_from_file().unwrap().files.len(), 2);
+        // the object a.txt shared with copy.txt is still there
+        let mut stored = sandbox.backend.list("objects/").unwrap();
+        stored.sort();
+        let mut expected: Vec<String> = metadata.referenced_objects().unwrap().into_iter().collect();
+        expected.sort();
+        assert_eq!(stored, expected);
+        assert_eq!(delete_file(&folder.join("a.txt")).unwrap_err().kind(), std::io::ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn metadata_with_namespaces_from_older_versions_still_loads() {
+        let _sandbox = storage::testing::sandbox();
+        let json = r#"{"files": {"/d/a.txt": {"original_path": "/d/a.txt", "backup_path": "", "file_type": "txt",
+            "relative_path": "d-3f2a9c1e/a.txt", "versions": []}}, "roots": [{"path": "/d", "id": "d-3f2a9c1e"}]}"#;
+        fs::write(paths::metadata_file(), json).unwrap();
+        let metadata = BackupMetadata::load_from_file().unwrap();
+        assert!(metadata.files.contains_key(Path::new("/d/a.txt")));
+        assert_eq!(metadata.roots[0].path, PathBuf::from("/d"));
+    }
+
+    #[test]
+    fn labeled_paths_skip_names_that_are_taken() {
+        let sandbox = storage::testing::sandbox();
+        let notes = sandbox.dir.join("notes.txt");
+        let first = labeled_path(&notes, "restored");
+        assert_eq!(first, sandbox.dir.join("notes (restored).txt"));
+        fs::write(&first, "").unwrap();
+        let second = labeled_path(&notes, "restored");
+        assert_eq!(second, sandbox.dir.join("notes (restored 2).txt"));
+        fs::write(&second, "").unwrap();
+        assert_eq!(labeled_path(&notes, "restored"), sandbox.dir.join("notes (restored 3).txt"));
+
+        assert_eq!(labeled_path(&sandbox.dir.join("Makefile"), "old"), sandbox.dir.join("Makefile (old)"));
+        assert_eq!(labeled_path(&sandbox.dir.join(".bashrc"), "old"), sandbox.dir.join(".bashrc (old)"));
+        assert_eq!(labeled_path(&sandbox.dir.join("a.tar.gz"), "old"), sandbox.dir.join("a.tar (old).gz"));
+    }
+
+    #[test]
+    fn identical_content_is_stored_once() {
+        let sandbox = storage::testing::sandbox();
+        let folder = sandbox.dir.join("docs");
+        fs::create_dir_all(folder.join("old")).unwrap();
+        fs::write(folder.join("a.txt"), "same").unwrap();
+        fs::write(folder.join("old/a copy.txt"), "same").unwrap();
+        backup(&folder).unwrap();
+        assert_eq!(sandbox.backend.list("objects/").unwrap().len(), 1);
+
+        // going back to earlier content needs no new object either
+        fs::write(folder.join("a.txt"), "changed").unwrap();
+        backup_now(Arc::new(Mutex::new(BackupMetadata::load_from_file().unwrap()))).unwrap();
+        fs::write(folder.join("a.txt"), "same").unwrap();
+        backup_now(Arc::new(Mutex::new(BackupMetadata::load_from_file().unwrap()))).unwrap();
+
+        let metadata = BackupMetadata::load_from_file().unwrap();
+        let versions = &metadata.files[&folder.join("a.txt")].versions;
+        assert_eq!(versions.len(), 3);
+        assert_eq!(versions[0].object_keys().unwrap(), versions[2].object_keys().unwrap());
+        let mut stored = sandbox.backend.list("objects/").unwrap();
+        stored.sort();
+        let mut expected: Vec<String> = metadata.referenced_objects().unwrap().into_iter().collect();
+        expected.sort();
+        assert_eq!(stored.len(), 2);
+        assert_eq!(stored, expected);
+    }
+
+    #[test]
+    fn text_is_compressed_and_already_compressed_formats_are_not() {
+        let sandbox = storage::testing::sandbox();
+        let settings = BackupSettings {
+            destination: sandbox.dir.join("dest"),
+            compression_enabled: true,
+            ..Default::default()
+        };
+        settings.save_to_file().unwrap();
+        let folder = sandbox.dir.join("docs");
+        fs::create_dir_all(&folder).unwrap();
+        let text = "the same line over and over\n".repeat(1000);
+        fs::write(folder.join("notes.txt"), &text).unwrap();
+        // different content, the same would be deduplicated into the text's object
+        fs::write(folder.join("photo.JPG"), text.to_uppercase()).unwrap();
+        backup(&folder).unwrap();
+
+        let metadata = BackupMetadata::load_from_file().unwrap();
+        let notes = &metadata.files[&folder.join("notes.txt")];
+        let photo = &metadata.files[&folder.join("photo.JPG")];
+        assert_eq!(notes.versions[0].codec, Codec::Zstd);
+        assert_eq!(photo.versions[0].codec, Codec::None);
+        let key = &notes.versions[0].object_keys().unwrap()[0];
+        assert!(key.ends_with(".zst"));
+        assert!(sandbox.backend.get(key).unwrap().len() < text.len() / 10);
+
+        let restored = sandbox.dir.join("restored.txt");
+        restore_file(sandbox.backend.as_ref(), notes, None, &restored).unwrap();
+        assert_eq!(fs::read_to_string(&restored).unwrap(), text);
+    }
+
+    #[test]
+    fn files_added_to_a_tracked_folder_are_picked_up() {
+        let sandbox = storage::testing::sandbox();
+        let folder = sandbox.dir.join("docs");
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(folder.join("a.txt"), "a").unwrap();
+        backup(&folder).unwrap();
+
+        fs::create_dir_all(folder.join("new folder")).unwrap();
+        fs::write(folder.join("b.txt"), "b").unwrap();
+        fs::write(folder.join("new folder/c.txt"), "c").unwrap();
+        let metadata = BackupMetadata::load_from_file().unwrap();
+        assert_eq!(backup_now(Arc::new(Mutex::new(metadata))).unwrap(), 2);
+
+        let metadata = BackupMetadata::load_from_file().unwrap();
+        assert_eq!(metadata.roots.len(), 1);
+        for name in ["a.txt", "b.txt", "new folder/c.txt"] {
+            assert_eq!(metadata.files[&folder.join(name)].versions.len(), 1, "{}", name);
+        }
+    }
+
+    #[test]
+    fn deletions_and_renames_are_recorded() {
+        let sandbox = storage::testing::sandbox();
+        let folder = sandbox.dir.join("docs");
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(folder.join("old name.txt"), "renamed").unwrap();
+        fs::write(folder.join("gone.txt"), "deleted").unwrap();
+        backup(&folder).unwrap();
+        let objects = sandbox.backend.list("objects/").unwrap().len();
+
+        fs::rename(folder.join("old name.txt"), folder.join("new name.txt")).unwrap();
+        fs::remove_file(folder.join("gone.txt")).unwrap();
+        let metadata = BackupMetadata::load_from_file().unwrap();
+        assert_eq!(backup_now(Arc::new(Mutex::new(metadata))).unwrap(), 0);
+
+        let metadata = BackupMetadata::load_from_file().unwrap();
+        let snapshot = metadata.snapshots.last().unwrap();
+        assert_eq!(snapshot.deleted, vec![folder.join("gone.txt")]);
+        assert_eq!(snapshot.renamed.len(), 1);
+        assert_eq!(snapshot.renamed[0].from, folder.join("old name.txt"));
+        assert_eq!(snapshot.renamed[0].to, folder.join("new name.txt"));
+        // the renamed file keeps its history, the deleted one stays restorable
+        assert!(!metadata.files.contains_key(&folder.join("old name.txt")));
+        assert_eq!(metadata.files[&folder.join("new name.txt")].versions.len(), 1);
+        assert!(metadata.files[&folder.join("gone.txt")].deleted_in.is_some());
+        assert_eq!(sandbox.backend.list("objects/").unwrap().len(), objects);
+    }
+
+    #[test]
+    fn only_files_with_a_new_stamp_are_hashed_again() {
+        let sandbox = storage::testing::sandbox();
+        let folder = sandbox.dir.join("docs");
+        let file = folder.join("a.txt");
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(&file, "content").unwrap();
+        backup(&folder).unwrap();
+        let backup_again = || backup_now(Arc::new(Mutex::new(BackupMetadata::load_from_file().unwrap()))).unwrap();
+
+        // a wrong hash goes unnoticed as long as the stamp is the same, so the file wasn't read
+        let mut metadata = BackupMetadata::load_from_file().unwrap();
+        metadata.files.get_mut(&file).unwrap().hash = "not the hash".to_string();
+        metadata.save_to_file(false).unwrap();
+        assert_eq!(backup_again(), 0);
+        assert_eq!(BackupMetadata::load_from_file().unwrap().files[&file].hash, "not the hash");
+
+        // a full hash run reads it anyway
+        let settings = BackupSettings {
+            destination: sandbox.dir.join("dest"),
+            paranoid_hash_enabled: true,
+            paranoid_interval_hours: 0,
+            ..Default::default()
+        };
+        settings.save_to_file().unwrap();
+        assert_eq!(backup_again(), 1);
+        let metadata = BackupMetadata::load_from_file().unwrap();
+        assert_eq!(metadata.files[&file].hash, metadata.files[&file].versions[0].hash);
+        assert!(metadata.last_full_hash > 0);
+
+        // rewritten with the same content, the new stamp is remembered without a new version
+        BackupSettings { destination: sandbox.dir.join("dest"), ..Default::default() }.save_to_file().unwrap();
+        let stamp = metadata.files[&file].stamp;
+        fs::write(&file, "content").unwrap();
+        assert_eq!(backup_again(), 0);
+        let info = &BackupMetadata::load_from_file().unwrap().files[&file];
+        assert_ne!(info.stamp, stamp);
+        assert_eq!(info.versions.len(), 2);
+    }
+
+    #[test]
+    fn folders_and_snapshots_are_restored_to_another_location() {
+        let sandbox = storage::testing::sandbox();
+        let folder = sandbox.dir.join("docs");
+        fs::create_dir_all(folder.join("sub")).unwrap();
+        fs::write(folder.join("a.txt"), "a first").unwrap();
+        fs::write(folder.join("sub/b.txt"), "b").unwrap();
+        backup(&folder).unwrap();
+        let first = BackupMetadata::load_from_file().unwrap().snapshots[0].id.clone();
+        fs::write(folder.join("a.txt"), "a second").unwrap();
+        backup_now(Arc::new(Mutex::new(BackupMetadata::load_from_file().unwrap()))).unwrap();
+
+        let request = RestoreRequest {
+            folder: Some(folder.join("sub")),
+            target: Some(sandbox.dir.join("out")),
+            ..Default::default()
+        };
+        let report = restore_tree(&request, |_, _| {}, |_| ConflictPolicy::Skip).unwrap();
+        assert_eq!((report.restored(), report.failed()), (1, 0));
+        assert_eq!(fs::read_to_string(sandbox.dir.join("out/sub/b.txt")).unwrap(), "b");
+        assert!(!sandbox.dir.join("out/a.txt").exists());
+
+        let request = RestoreRequest {
+            point: RestorePoint::Snapshot(first),
+            target: Some(sandbox.dir.join("old")),
+            ..Default::default()
+        };
+        let report = restore_tree(&request, |_, _| {}, |_| ConflictPolicy::Skip).unwrap();
+        assert_eq!((report.restored(), report.failed()), (2, 0));
+        assert_eq!(fs::read_to_string(sandbox.dir.join("old/docs/a.txt")).unwrap(), "a first");
+        assert_eq!(fs::read_to_string(sandbox.dir.join("old/docs/sub/b.txt")).unwrap(), "b");
+        // the originals weren't touched
+        assert_eq!(fs::read_to_string(folder.join("a.txt")).unwrap(), "a second");
+    }
+
+    // compares the serial pipeline (one hashing and one storing thread) with the default
+    // pools over a generated tree, storing into a local destination folder. not run by
+    // default, use cargo test --release process_files_throughput -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn process_files_throughput() {
+        const FILES: usize = 1000;
+        const FILE_SIZE: usize = 256 * 1024;
+        let sandbox = storage::testing::sandbox();
+        storage::set_override(None);
+        let folder = sandbox.dir.join("tree");
+        let mut state = 0x9e37_79b9_7f4a_7c15u64;
+        let mut paths = Vec::with_capacity(FILES);
+        for i in 0..FILES {
+            let path = folder.join(format!("{:02}", i % 20)).join(format!("file-{}.bin", i));
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            // xorshift, so zstd can't shrink the files to nothing
+            let data: Vec<u8> = (0..FILE_SIZE / 8)
+                .flat_map(|_| {
+                    state ^= state << 13;
+                    state ^= state >> 7;
+                    state ^= state << 17;
+                    state.to_le_bytes()
+                })
+                .collect();
+            fs::write(&path, data).unwrap();
+            paths.push(path);
+        }
+
+        let defaults = BackupSettings::load_from_file().unwrap();
+        let serial = BackupSettings { worker_threads: 1, io_threads: 1, ..defaults.clone() };
+        let megabytes = (FILES * FILE_SIZE) as f64 / (1024.0 * 1024.0);
+        for (label, settings) in [("serial", &serial), ("default pools", &defaults)] {
+            let _ = fs::remove_dir_all(&settings.destination);
+            fs::create_dir_all(&settings.destination).unwrap();
+            let mut metadata = BackupMetadata::default();
+            let mut changes = RunChanges::default();
+            let started = Instant::now();
+            let storage = storage::open(settings);
+            process_files(storage.as_ref(), &mut metadata, paths.clone(), "1", settings, false, &mut HashMap::new(), &mut changes);
+            let elapsed = started.elapsed().as_secs_f64();
+            assert_eq!(changes.stored.len(), FILES);
+            println!(
+                "process_files, {} (worker_threads {}, io_threads {}): {} files, {:.0} MiB in {:.2}s, {:.1} MiB/s",
+                label, settings.worker_threads, settings.io_threads, FILES, megabytes, elapsed, megabytes / elapsed
+            );
         }
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,144 @@
+// content-defined chunking using a gear rolling hash (the same hash FastCDC uses).
+// chunk boundaries depend on the bytes around them, not their offset, so inserting
+// or changing a few bytes only changes the chunks near the edit.
+
+pub const MIN_CHUNK_SIZE: usize = 256 * 1024;
+pub const MAX_CHUNK_SIZE: usize = 4 * 1024 * 1024;
+
+// top 20 bits of the hash must be zero to cut, giving ~1 MiB chunks on average
+const BOUNDARY_SHIFT: u32 = 44;
+
+const GEAR: [u64; 256] = gear_table();
+
+// fixed pseudo random table (splitmix64) so boundaries are the same on every run
+const fn gear_table() -> [u64; 256] {
+    let mut table = [0u64; 256];
+    let mut state: u64 = 0x9E37_79B9_7F4A_7C15;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+}
+
+#[derive(Default)]
+pub struct Chunker {
+    buffer: Vec<u8>,
+    hash: u64,
+}
+
+impl Chunker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// feeds more bytes in, emit is called with every chunk that is complete
+    pub fn update<F>(&mut self, data: &[u8], emit: &mut F) -> std::io::Result<()>
+    where
+        F: FnMut(&[u8]) -> std::io::Result<()>,
+    {
+        for &byte in data {
+            self.buffer.push(byte);
+            self.hash = (self.hash << 1).wrapping_add(GEAR[byte as usize]);
+
+            let len = self.buffer.len();
+            let at_boundary = len >= MIN_CHUNK_SIZE && (self.hash >> BOUNDARY_SHIFT) == 0;
+            if at_boundary || len >= MAX_CHUNK_SIZE {
+                emit(&self.buffer)?;
+                self.buffer.clear();
+                self.hash = 0;
+            }
+        }
+        Ok(())
+    }
+
+    /// emits whatever is left over as the last chunk
+    pub fn finish<F>(self, emit: &mut F) -> std::io::Result<()>
+    where
+        F: FnMut(&[u8]) -> std::io::Result<()>,
+    {
+        if !self.buffer.is_empty() {
+            emit(&self.buffer)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+
+    // xorshift, the same bytes on every run
+    fn noise(len: usize, mut state: u64) -> Vec<u8> {
+        let mut data = Vec::with_capacity(len + 8);
+        while data.len() < len {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            data.extend_from_slice(&state.to_le_bytes());
+        }
+        data.truncate(len);
+        data
+    }
+
+    fn chunks(data: &[u8], piece: usize) -> Vec<Vec<u8>> {
+        let mut chunks = Vec::new();
+        let mut emit = |chunk: &[u8]| {
+            chunks.push(chunk.to_vec());
+            Ok(())
+        };
+        let mut chunker = Chunker::new();
+        for part in data.chunks(piece) {
+            chunker.update(part, &mut emit).unwrap();
+        }
+        chunker.finish(&mut emit).unwrap();
+        chunks
+    }
+
+    #[test]
+    fn chunks_stay_within_the_size_limits() {
+        let data = noise(24 * 1024 * 1024, 1);
+        let found = chunks(&data, 64 * 1024);
+        assert!(found.len() > 4);
+        assert_eq!(found.concat(), data);
+        let (last, rest) = found.split_last().unwrap();
+        assert!(rest.iter().all(|c| (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&c.len())));
+        assert!(!last.is_empty() && last.len() <= MAX_CHUNK_SIZE);
+
+        // without any boundary in the content every chunk is cut at the maximum size
+        let zeros = chunks(&vec![0u8; 2 * MAX_CHUNK_SIZE + 1000], 1024 * 1024);
+        let sizes: Vec<usize> = zeros.iter().map(|c| c.len()).collect();
+        assert_eq!(sizes, vec![MAX_CHUNK_SIZE, MAX_CHUNK_SIZE, 1000]);
+
+        assert!(chunks(&[], 1).is_empty());
+        assert_eq!(chunks(b"tiny", 1), vec![b"tiny".to_vec()]);
+    }
+
+    #[test]
+    fn boundaries_dont_depend_on_how_the_data_is_fed() {
+        let data = noise(8 * 1024 * 1024, 2);
+        assert_eq!(chunks(&data, 4097), chunks(&data, data.len()));
+    }
+
+    #[test]
+    fn an_insertion_only_changes_the_chunks_around_it() {
+        let original = noise(16 * 1024 * 1024, 3);
+        let mut edited = original.clone();
+        let at = 5 * 1024 * 1024 + 123;
+        edited.splice(at..at, b"a few inserted bytes".iter().copied());
+
+        let before = chunks(&original, 1024 * 1024);
+        let after = chunks(&edited, 1024 * 1024);
+        let known: HashSet<&Vec<u8>> = before.iter().collect();
+        let changed = after.iter().filter(|c| !known.contains(c)).count();
+        assert!((1..=2).contains(&changed), "{} of {} chunks changed", changed, after.len());
+        // everything after the edit lines up again
+        assert_eq!(before.last(), after.last());
+    }
+}
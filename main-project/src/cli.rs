@@ -0,0 +1,852 @@
+// command line interface, used whenever the program is started with arguments.
+// every subcommand prints a short human readable report, or a json document with --json
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+use serde_json::{json, Value};
+use std::io::{ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::backup::{self, BackupMetadata, BackupSettings, ConflictPolicy, RestoreOutcome, RestorePoint, RestoredFile};
+use crate::crypto;
+use crate::daemon::DaemonManager;
+use crate::paths;
+use crate::replication;
+use crate::storage;
+use crate::verify;
+
+// exit codes, 2 is what clap uses for bad arguments
+const EXIT_FAILURE: u8 = 1;
+const EXIT_NOT_RUNNING: u8 = 3;
+const EXIT_NOT_FOUND: u8 = 4;
+const EXIT_LOCKED: u8 = 5;
+const EXIT_EXISTS: u8 = 6;
+const EXIT_UNAVAILABLE: u8 = 7;
+// no access to a file or the destination, a locked backup is EXIT_LOCKED
+const EXIT_DENIED: u8 = 8;
+
+// lets scripts unlock an encrypted backup that has no key file
+const PASSPHRASE_ENV: &str = "FASS_BACKUP_PASSPHRASE";
+
+#[derive(Parser)]
+#[command(
+    name = "fass-backup",
+    version,
+    about = "Versioned file backups. Starts the GUI when run without arguments.",
+    after_help = "Exit codes: 0 ok, 1 error, 2 bad arguments, 3 daemon not running, \
+                  4 not found, 5 backup locked, 6 restore target exists, 7 backup destination unavailable, \
+                  8 permission denied"
+)]
+struct Cli {
+    /// print machine readable json instead of text
+    #[arg(long, global = true)]
+    json: bool,
+
+    /// keep settings, metadata and daemon files in this folder instead of the XDG folders
+    #[arg(long, global = true, value_name = "DIR")]
+    config_dir: Option<PathBuf>,
+
+    // without a subcommand the GUI starts
+    #[command(subcommand)]
+    command: Option<Command>,
+}
+
+// logins for remote destinations, only init takes them
+#[derive(Args)]
+struct LoginArgs {
+    /// server for s3://bucket/prefix destinations, e.g. http://nas:9000 (default AWS)
+    #[arg(long, value_name = "URL")]
+    s3_endpoint: Option<String>,
+    #[arg(long, value_name = "REGION")]
+    s3_region: Option<String>,
+    /// s3 access key id, the secret key is read from AWS_SECRET_ACCESS_KEY
+    #[arg(long, value_name = "KEY")]
+    s3_access_key: Option<String>,
+    /// private key for sftp://user@host/path destinations when the ssh agent has none
+    #[arg(long, value_name = "FILE")]
+    ssh_key: Option<PathBuf>,
+    /// login for dav://host/path destinations, the password is read from FASS_WEBDAV_PASSWORD
+    #[arg(long, value_name = "NAME")]
+    webdav_user: Option<String>,
+}
+
+// the conflict policies that work without asking
+#[derive(Clone, Copy, ValueEnum)]
+enum OnConflict {
+    Skip,
+    Overwrite,
+    KeepBoth,
+    NewerWins,
+}
+
+impl OnConflict {
+    fn policy(self) -> ConflictPolicy {
+        match self {
+            OnConflict::Skip => ConflictPolicy::Skip,
+            OnConflict::Overwrite => ConflictPolicy::Overwrite,
+            OnConflict::KeepBoth => ConflictPolicy::KeepBoth,
+            OnConflict::NewerWins => ConflictPolicy::NewerWins,
+        }
+    }
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// create the backup folder and settings, optionally with encryption
+    Init {
+        /// encrypt the backup, the passphrase is read from FASS_BACKUP_PASSPHRASE or stdin
+        #[arg(long)]
+        encrypt: bool,
+        /// where backups go, e.g. a folder on an external drive (default ~/Backup)
+        #[arg(long, value_name = "DIR")]
+        destination: Option<PathBuf>,
+        /// also copy every backup to this folder, can be given more than once
+        #[arg(long = "replica", value_name = "DIR")]
+        replicas: Vec<PathBuf>,
+        #[command(flatten)]
+        login: LoginArgs,
+    },
+    /// start tracking a folder and back it up
+    Add { folder: PathBuf },
+    /// back up everything that changed since the last run
+    Backup,
+    /// bring every replica up to date without running a backup
+    Replicate,
+    /// list tracked files and their versions
+    List,
+    /// restore a file or a whole folder, by default the newest versions back to where they were
+    Restore {
+        path: PathBuf,
+        /// folder to restore into instead of the original location
+        #[arg(long)]
+        to: Option<PathBuf>,
+        /// snapshot id of the version to restore, for a folder the state after that snapshot
+        #[arg(long)]
+        version: Option<String>,
+        /// restore everything under the path as it was at this local time, e.g. "2025-01-07 14:00"
+        #[arg(long, value_name = "TIME", value_parser = parse_time, conflicts_with = "version")]
+        at: Option<i64>,
+        /// what to do with files that are in the way (default keep-both for --version, skip otherwise)
+        #[arg(long, value_enum, value_name = "POLICY")]
+        on_conflict: Option<OnConflict>,
+    },
+    /// check that every stored object exists and still matches its recorded hash
+    Verify {
+        /// only reread this share of the objects, picked at random (default 100)
+        #[arg(long, value_name = "PERCENT", value_parser = clap::value_parser!(u8).range(1..=100))]
+        sample: Option<u8>,
+    },
+    /// show what is backed up and whether the daemon is running
+    Status,
+    /// control the background daemon
+    Daemon {
+        #[command(subcommand)]
+        action: DaemonAction,
+    },
+}
+
+#[derive(Subcommand)]
+enum DaemonAction {
+    Start,
+    Stop {
+        /// kill the daemon right away instead of asking it to stop
+        #[arg(long)]
+        force: bool,
+    },
+    Restart,
+    Status,
+    // the process `daemon start` spawns, it detaches and becomes the daemon
+    #[command(hide = true)]
+    Run,
+}
+
+struct CliError {
+    code: u8,
+    message: String,
+}
+
+impl CliError {
+    fn new(code: u8, message: impl Into<String>) -> Self {
+        Self { code, message: message.into() }
+    }
+}
+
+impl From<std::io::Error> for CliError {
+    fn from(e: std::io::Error) -> Self {
+        let code = match e.kind() {
+            _ if crypto::is_locked_error(&e) => EXIT_LOCKED,
+            ErrorKind::NotFound => EXIT_NOT_FOUND,
+            ErrorKind::PermissionDenied => EXIT_DENIED,
+            ErrorKind::AlreadyExists => EXIT_EXISTS,
+            _ => EXIT_FAILURE,
+        };
+        Self::new(code, e.to_string())
+    }
+}
+
+impl From<String> for CliError {
+    fn from(message: String) -> Self {
+        Self::new(EXIT_FAILURE, message)
+    }
+}
+
+// what a subcommand prints, code is non zero for reports like "daemon not running"
+struct Report {
+    text: String,
+    json: Value,
+    code: u8,
+}
+
+impl Report {
+    fn new(text: impl Into<String>, json: Value) -> Self {
+        Self { text: text.into(), json, code: 0 }
+    }
+}
+
+/// parses the arguments and runs the subcommand
+pub fn run() -> ExitCode {
+    let cli = Cli::parse();
+    if let Some(dir) = cli.config_dir {
+        paths::set_config_dir(dir);
+    }
+    let Some(command) = cli.command else {
+        return crate::run_gui();
+    };
+    paths::migrate_from_working_dir();
+
+    // the backup code reports progress on stdout, with --json that goes to stderr instead
+    // so stdout only carries the json document
+    let saved_stdout = if cli.json { redirect_stdout() } else { None };
+    let result = run_command(command);
+    if let Some(fd) = saved_stdout {
+        restore_stdout(fd);
+    }
+
+    let (code, output) = render(result, cli.json);
+    match output {
+        Ok(text) if !text.is_empty() => println!("{}", text),
+        Ok(_) => {}
+        Err(text) => eprintln!("{}", text),
+    }
+    ExitCode::from(code)
+}
+
+// the exit code and what to print, Err for text that goes to stderr. with --json errors
+// are a json document on stdout too
+fn render(result: Result<Report, CliError>, json: bool) -> (u8, Result<String, String>) {
+    match result {
+        Ok(report) if json => (report.code, Ok(report.json.to_string())),
+        Ok(report) => (report.code, Ok(report.text)),
+        Err(e) if json => (e.code, Ok(json!({ "error": e.message, "code": e.code }).to_string())),
+        Err(e) => (e.code, Err(format!("Error: {}", e.message))),
+    }
+}
+
+fn redirect_stdout() -> Option<i32> {
+    let _ = std::io::stdout().flush();
+    let saved = nix::unistd::dup(1).ok()?;
+    nix::unistd::dup2(2, 1).ok()?;
+    Some(saved)
+}
+
+fn restore_stdout(saved: i32) {
+    let _ = std::io::stdout().flush();
+    let _ = nix::unistd::dup2(saved, 1);
+    let _ = nix::unistd::close(saved);
+}
+
+fn run_command(command: Command) -> Result<Report, CliError> {
+    // init sets up encryption itself and the daemon commands only look at the pid file
+    if !matches!(command, Command::Init { .. } | Command::Daemon { .. }) {
+        unlock_if_needed()?;
+    }
+    match command {
+        Command::Init { encrypt, destination, replicas, login } => init(encrypt, destination, replicas, login),
+        Command::Add { folder } => add(&folder),
+        Command::Backup => run_backup(),
+        Command::Replicate => replicate(),
+        Command::List => list(),
+        Command::Restore { path, to, version, at, on_conflict } => {
+            // like the GUI used to: an old version goes next to the current file by default
+            let policy = match (on_conflict, &version) {
+                (Some(choice), _) => choice.policy(),
+                (None, Some(_)) => ConflictPolicy::KeepBoth,
+                (None, None) => ConflictPolicy::Skip,
+            };
+            match at {
+                Some(time) => restore_folder(&path, to.as_deref(), RestorePoint::AsOf(time), policy),
+                None => restore(&path, to.as_deref(), version.as_deref(), policy),
+            }
+        }
+        Command::Verify { sample } => verify(sample.unwrap_or(100)),
+        Command::Status => status(),
+        Command::Daemon { action } => daemon(action),
+    }
+}
+
+fn read_passphrase() -> Result<String, CliError> {
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    eprint!("Passphrase: ");
+    let mut line = String::new();
+    std::io::stdin().read_line(&mut line)?;
+    Ok(line.trim_end_matches(['\r', '\n']).to_string())
+}
+
+//an encrypted backup without a key file can still be used with the passphrase in the env
+fn unlock_if_needed() -> Result<(), CliError> {
+    let storage = storage::open(&BackupSettings::load_from_file()?);
+    if crypto::is_unlocked() || !crypto::is_initialized(storage.as_ref()) {
+        return Ok(());
+    }
+    match std::env::var(PASSPHRASE_ENV) {
+        Ok(passphrase) => Ok(crypto::unlock(storage.as_ref(), &passphrase)?),
+        Err(_) => Err(CliError::new(
+            EXIT_LOCKED,
+            format!("Backup is encrypted and locked, set {} or save a key file from the GUI", PASSPHRASE_ENV),
+        )),
+    }
+}
+
+//a missing or read only destination gets its own exit code, whatever the io error was
+fn check_destination(settings: &BackupSettings) -> Result<(), CliError> {
+    backup::check_settings_destination(settings).map_err(|e| CliError::new(EXIT_UNAVAILABLE, e.to_string()))
+}
+
+//folders are made absolute, urls are left alone
+fn absolute_destination(path: &Path) -> std::io::Result<PathBuf> {
+    if storage::is_remote(path) {
+        return Ok(path.to_path_buf());
+    }
+    std::path::absolute(path)
+}
+
+fn init(
+    encrypt: bool,
+    destination: Option<PathBuf>,
+    replicas: Vec<PathBuf>,
+    login: LoginArgs,
+) -> Result<Report, CliError> {
+    let mut settings = BackupSettings::load_from_file()?;
+    if let Some(destination) = destination {
+        settings.destination = absolute_destination(&destination)?;
+    }
+    if let Some(endpoint) = login.s3_endpoint {
+        settings.s3.endpoint = endpoint;
+    }
+    if let Some(region) = login.s3_region {
+        settings.s3.region = region;
+    }
+    if let Some(access_key) = login.s3_access_key {
+        settings.s3.access_key_id = access_key;
+        settings.s3.secret_access_key = std::env::var("AWS_SECRET_ACCESS_KEY").map_err(|_| {
+            CliError::new(EXIT_FAILURE, "--s3-access-key needs the secret key in AWS_SECRET_ACCESS_KEY")
+        })?;
+    }
+    if let Some(ssh_key) = login.ssh_key {
+        settings.sftp.private_key = std::path::absolute(&ssh_key)?;
+    }
+    if let Some(user) = login.webdav_user {
+        settings.webdav.username = user;
+        settings.webdav.password = std::env::var("FASS_WEBDAV_PASSWORD").map_err(|_| {
+            CliError::new(EXIT_FAILURE, "--webdav-user needs the password in FASS_WEBDAV_PASSWORD")
+        })?;
+    }
+    for replica in replicas {
+        let replica = absolute_destination(&replica)?;
+        if replica != settings.destination && !settings.replicas.contains(&replica) {
+            settings.replicas.push(replica);
+        }
+    }
+    check_destination(&settings)?;
+    // saved right away, the encryption setup below writes into the destination
+    settings.save_to_file()?;
+    let storage = storage::open(&settings);
+    if encrypt && !crypto::is_initialized(storage.as_ref()) {
+        crypto::init(storage.as_ref(), &read_passphrase()?)?;
+        crypto::save_key_file()?;
+        settings.encryption_enabled = true;
+    }
+    settings.save_to_file()?;
+    if !paths::metadata_file().exists() {
+        BackupMetadata::default().save_to_file(settings.encryption_enabled)?;
+    }
+
+    let backup_dir = settings.destination;
+    Ok(Report::new(
+        format!(
+            "Initialized backup in {}{}",
+            backup_dir.display(),
+            if settings.encryption_enabled { " (encrypted)" } else { "" }
+        ),
+        json!({
+            "backup_dir": backup_dir,
+            "replicas": settings.replicas,
+            "encrypted": settings.encryption_enabled,
+        }),
+    ))
+}
+
+fn add(folder: &Path) -> Result<Report, CliError> {
+    let folder = folder.canonicalize()?;
+    if !folder.is_dir() {
+        return Err(CliError::new(EXIT_NOT_FOUND, format!("{} is not a folder", folder.display())));
+    }
+    check_destination(&BackupSettings::load_from_file()?)?;
+    backup::backup(&folder)?;
+
+    let metadata = BackupMetadata::load_from_file()?;
+    let tracked = metadata.files.keys().filter(|p| p.starts_with(&folder)).count();
+    Ok(Report::new(
+        format!("Backed up {} ({} file(s) tracked)", folder.display(), tracked),
+        json!({ "folder": folder, "tracked_files": tracked }),
+    ))
+}
+
+fn run_backup() -> Result<Report, CliError> {
+    check_destination(&BackupSettings::load_from_file()?)?;
+    let metadata = BackupMetadata::load_from_file()?;
+    let count = backup::backup_now(Arc::new(Mutex::new(metadata)))?;
+    Ok(Report::new(
+        format!("{} file(s) backed up", count),
+        json!({ "backed_up": count }),
+    ))
+}
+
+fn replicate() -> Result<Report, CliError> {
+    let settings = BackupSettings::load_from_file()?;
+    if settings.replicas.is_empty() {
+        return Err(CliError::new(EXIT_NOT_FOUND, "No replicas configured, add one with init --replica"));
+    }
+    check_destination(&settings)?;
+    let _lock = paths::lock_repository()?;
+    let mut metadata = BackupMetadata::load_from_file()?;
+    let storage = storage::open(&settings);
+    replication::replicate(&mut metadata, storage.as_ref(), &settings);
+    metadata.save(storage.as_ref(), &settings)?;
+
+    let lines: Vec<String> = metadata
+        .replicas
+        .iter()
+        .map(|r| format!("{}: {}", r.path.display(), r.describe()))
+        .collect();
+    let mut report = Report::new(lines.join("\n"), json!({ "replicas": replica_json(&metadata) }));
+    // the ones that worked are up to date, but the caller should know some didn't
+    if metadata.replicas.iter().any(|r| r.last_error.is_some()) {
+        report.code = EXIT_UNAVAILABLE;
+    }
+    Ok(report)
+}
+
+fn replica_json(metadata: &BackupMetadata) -> Value {
+    metadata
+        .replicas
+        .iter()
+        .map(|r| json!({ "path": r.path, "last_sync": r.last_sync, "error": r.last_error }))
+        .collect()
+}
+
+fn verify(sample_percent: u8) -> Result<Report, CliError> {
+    check_destination(&BackupSettings::load_from_file()?)?;
+    let report = verify::verify_now(sample_percent)?;
+
+    let mut text = report.problems();
+    text.push(report.summary());
+    let damaged_json = |objects: &[verify::DamagedObject]| -> Value {
+        objects
+            .iter()
+            .map(|o| json!({
+                "key": o.key,
+                "reason": o.reason,
+                "files": o.files.iter().map(|(path, snapshot)| json!({ "path": path, "snapshot_id": snapshot })).collect::<Vec<_>>(),
+            }))
+            .collect()
+    };
+    let mut result = Report::new(
+        text.join("\n"),
+        json!({
+            "total": report.total,
+            "checked": report.checked,
+            "checked_bytes": report.checked_bytes,
+            "missing": damaged_json(&report.missing),
+            "corrupted": damaged_json(&report.corrupted),
+            "unreadable": damaged_json(&report.unreadable),
+            "orphaned": report.orphaned,
+        }),
+    );
+    // orphans only waste space, everything else means some version can't be restored
+    if !report.missing.is_empty() || !report.corrupted.is_empty() {
+        result.code = EXIT_FAILURE;
+    } else if !report.unreadable.is_empty() {
+        result.code = EXIT_UNAVAILABLE;
+    }
+    Ok(result)
+}
+
+fn list() -> Result<Report, CliError> {
+    let metadata = BackupMetadata::load_from_file()?;
+    let mut files: Vec<_> = metadata.files.values().collect();
+    files.sort_by(|a, b| a.original_path.cmp(&b.original_path));
+
+    let mut lines = Vec::new();
+    let mut entries = Vec::new();
+    for info in files {
+        let latest = info.versions.last().map(|v| v.snapshot_id.as_str()).unwrap_or("-");
+        lines.push(format!(
+            "{}  ({} version(s), latest {}){}",
+            info.original_path.display(),
+            info.versions.len(),
+            latest,
+            if info.deleted_in.is_some() { " [deleted]" } else { "" }
+        ));
+        entries.push(json!({
+            "path": info.original_path,
+            "deleted_in": info.deleted_in,
+            "versions": info.versions.iter().map(|v| json!({
+                "snapshot_id": v.snapshot_id,
+                "hash": v.hash,
+                "size": v.size,
+                "modified": v.modified,
+            })).collect::<Vec<_>>(),
+        }));
+    }
+    Ok(Report::new(lines.join("\n"), Value::Array(entries)))
+}
+
+fn restore(path: &Path, to: Option<&Path>, version: Option<&str>, policy: ConflictPolicy) -> Result<Report, CliError> {
+    let metadata = BackupMetadata::load_from_file()?;
+    // the file may be gone, so the path can't always be canonicalized
+    let path = std::path::absolute(path)?;
+    let Some(info) = metadata.files.get(&path).cloned() else {
+        if metadata.files.keys().any(|p| p.starts_with(&path)) {
+            let point = version.map_or(RestorePoint::Latest, |id| RestorePoint::Snapshot(id.to_string()));
+            return restore_folder(&path, to, point, policy);
+        }
+        return Err(CliError::new(EXIT_NOT_FOUND, format!("{} is not in the backup", path.display())));
+    };
+    let stored = match version {
+        Some(id) => info.find_version(id),
+        None => info.versions.last(),
+    }
+    .ok_or_else(|| {
+        CliError::new(
+            EXIT_NOT_FOUND,
+            format!("No version {} for {}", version.unwrap_or("(latest)"), path.display()),
+        )
+    })?;
+
+    let destination = match to {
+        Some(dir) => dir.join(path.file_name().unwrap_or_default()),
+        None => path.clone(),
+    };
+    let storage = storage::open(&BackupSettings::load_from_file()?);
+    let file = backup::restore_version(storage.as_ref(), &info, stored, &destination, policy, &|_| ConflictPolicy::Skip);
+
+    let mut report = Report::new(
+        format!("{}, snapshot {}", file.describe(), file.snapshot_id),
+        restored_file_json(&file),
+    );
+    report.code = match file.outcome {
+        RestoreOutcome::Skipped(_) => EXIT_EXISTS,
+        RestoreOutcome::Failed(_) => EXIT_FAILURE,
+        _ => 0,
+    };
+    Ok(report)
+}
+
+// local time as given on the command line, a date alone means its midnight
+fn parse_time(text: &str) -> Result<i64, String> {
+    use chrono::{NaiveDate, NaiveDateTime, TimeZone};
+
+    if let Ok(time) = chrono::DateTime::parse_from_rfc3339(text) {
+        return Ok(time.timestamp());
+    }
+    let naive = ["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%dT%H:%M:%S", "%Y-%m-%dT%H:%M"]
+        .iter()
+        .find_map(|format| NaiveDateTime::parse_from_str(text, format).ok())
+        .or_else(|| NaiveDate::parse_from_str(text, "%Y-%m-%d").ok().and_then(|d| d.and_hms_opt(0, 0, 0)))
+        .ok_or_else(|| format!("'{}' is not a time like \"2025-01-07 14:00\"", text))?;
+    chrono::Local
+        .from_local_datetime(&naive)
+        .earliest()
+        .map(|time| time.timestamp())
+        .ok_or_else(|| format!("{} doesn't exist in the local time zone", text))
+}
+
+fn restored_file_json(file: &RestoredFile) -> Value {
+    let (outcome, reason, safety_copy) = match &file.outcome {
+        RestoreOutcome::Restored => ("restored", None, None),
+        RestoreOutcome::Replaced(copy) => ("replaced", None, Some(copy)),
+        RestoreOutcome::KeptBoth => ("kept-both", None, None),
+        RestoreOutcome::Unchanged => ("unchanged", None, None),
+        RestoreOutcome::Skipped(reason) => ("skipped", Some(reason), None),
+        RestoreOutcome::Failed(reason) => ("failed", Some(reason), None),
+    };
+    json!({
+        "path": file.original_path,
+        "destination": file.destination,
+        "snapshot_id": file.snapshot_id,
+        "outcome": outcome,
+        "reason": reason,
+        "safety_copy": safety_copy,
+    })
+}
+
+// restores everything that was under folder at the restore point, the folder itself may be
+// gone by now
+fn restore_folder(folder: &Path, to: Option<&Path>, point: RestorePoint, policy: ConflictPolicy) -> Result<Report, CliError> {
+    if let RestorePoint::Snapshot(id) = &point
+        && !BackupMetadata::load_from_file()?.snapshots.iter().any(|s| &s.id == id)
+    {
+        return Err(CliError::new(EXIT_NOT_FOUND, format!("No snapshot {}", id)));
+    }
+    let folder = std::path::absolute(folder)?;
+    let request = backup::RestoreRequest {
+        folder: Some(folder.clone()),
+        point: point.clone(),
+        target: to.map(std::path::absolute).transpose()?,
+        conflicts: policy,
+    };
+    let result = backup::restore_tree(&request, |_, _| {}, |_| ConflictPolicy::Skip)?;
+    if result.files.is_empty() {
+        let when = match &point {
+            RestorePoint::Latest => String::new(),
+            point => format!(" ({})", point.to_string().to_lowercase()),
+        };
+        return Err(CliError::new(EXIT_NOT_FOUND, format!("Nothing under {} is in the backup{}", folder.display(), when)));
+    }
+
+    // plain restores were already printed as they happened
+    let mut lines: Vec<String> = result
+        .files
+        .iter()
+        .filter(|f| f.outcome != RestoreOutcome::Restored)
+        .map(|f| f.describe())
+        .collect();
+    lines.push(result.summary());
+    let mut report = Report::new(
+        lines.join("\n"),
+        json!({
+            "folder": folder,
+            "restored": result.restored(),
+            "skipped": result.skipped(),
+            "failed": result.failed(),
+            "files": result.files.iter().map(restored_file_json).collect::<Vec<_>>(),
+        }),
+    );
+    if result.failed() > 0 {
+        report.code = EXIT_FAILURE;
+    } else if result.skipped() > 0 {
+        report.code = EXIT_EXISTS;
+    }
+    Ok(report)
+}
+
+fn status() -> Result<Report, CliError> {
+    let settings = BackupSettings::load_from_file()?;
+    let metadata = BackupMetadata::load_from_file()?;
+    let manager = DaemonManager::new();
+    let daemon_pid = manager.running_pid();
+    let deleted = metadata.files.values().filter(|f| f.deleted_in.is_some()).count();
+    let last = metadata.snapshots.last();
+
+    let destination_error = backup::inspect_settings_destination(&settings).err().map(|e| e.to_string());
+
+    let mut lines = vec![
+        match &destination_error {
+            None => format!("Backup folder: {}", settings.destination.display()),
+            Some(e) => format!("Backup folder: {} ✗ {}", settings.destination.display(), e),
+        },
+        format!("Tracked folders: {}", metadata.roots.len()),
+    ];
+    lines.extend(metadata.roots.iter().map(|r| format!("  {}", r.path.display())));
+    lines.push(format!("Files: {} ({} deleted)", metadata.files.len(), deleted));
+    lines.push(format!(
+        "Snapshots: {} (latest {})",
+        metadata.snapshots.len(),
+        last.map(|s| s.id.as_str()).unwrap_or("none")
+    ));
+    for replica in &settings.replicas {
+        let state = metadata.replicas.iter().find(|r| &r.path == replica);
+        lines.push(format!(
+            "Replica: {} ({})",
+            replica.display(),
+            state.map(|r| r.describe()).unwrap_or_else(|| String::from("not synced yet"))
+        ));
+    }
+    lines.push(format!("Encryption: {}", if settings.encryption_enabled { "on" } else { "off" }));
+    lines.push(format!("Settings: {}", paths::settings_file().display()));
+    lines.push(format!("Metadata: {}", paths::metadata_file().display()));
+    lines.push(manager.status());
+
+    Ok(Report::new(
+        lines.join("\n"),
+        json!({
+            "backup_dir": settings.destination,
+            "destination_available": destination_error.is_none(),
+            "destination_error": destination_error,
+            "replicas": replica_json(&metadata),
+            "settings_file": paths::settings_file(),
+            "metadata_file": paths::metadata_file(),
+            "roots": metadata.roots.iter().map(|r| &r.path).collect::<Vec<_>>(),
+            "files": metadata.files.len(),
+            "deleted_files": deleted,
+            "snapshots": metadata.snapshots.len(),
+            "last_snapshot": last.map(|s| json!({ "id": s.id, "timestamp": s.timestamp })),
+            "encryption_enabled": settings.encryption_enabled,
+            "auto_backup_enabled": settings.auto_backup_enabled,
+            "interval_minutes": settings.interval_minutes,
+            "daemon": { "running": daemon_pid.is_some(), "pid": daemon_pid },
+        }),
+    ))
+}
+
+//DaemonManager::start turns the calling process into the daemon, so it runs in a child
+//process and this one waits for the pid file to show up
+fn spawn_daemon(manager: &DaemonManager) -> Result<i32, CliError> {
+    if manager.is_running() {
+        return Err(CliError::new(EXIT_FAILURE, "Daemon is already running. Use 'restart' to restart it."));
+    }
+    let mut child = std::process::Command::new(std::env::current_exe()?);
+    if let Some(dir) = paths::config_dir_override() {
+        child.arg("--config-dir").arg(dir);
+    }
+    let output = child.args(["daemon", "run"]).output()?;
+    if !output.status.success() {
+        // the child reports its failure like any other command, as "Error: ..."
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        let message = stderr.trim().lines().last().unwrap_or_default();
+        return Err(CliError::new(EXIT_FAILURE, message.trim_start_matches("Error: ")));
+    }
+
+    for _ in 0..20 {
+        if let Some(pid) = manager.running_pid() {
+            return Ok(pid);
+        }
+        std::thread::sleep(Duration::from_millis(250));
+    }
+    Err(CliError::new(
+        EXIT_FAILURE,
+        format!("Daemon did not start, check {}", paths::err_file().display()),
+    ))
+}
+
+fn daemon(action: DaemonAction) -> Result<Report, CliError> {
+    let manager = DaemonManager::new();
+    match action {
+        DaemonAction::Start => {
+            let pid = spawn_daemon(&manager)?;
+            Ok(Report::new(format!("Daemon started (PID {})", pid), json!({ "running": true, "pid": pid })))
+        }
+        DaemonAction::Stop { force } => {
+            if !manager.is_running() {
+                return Err(CliError::new(EXIT_NOT_RUNNING, "Daemon is not running"));
+            }
+            if force {
+                manager.kill()?;
+            } else {
+                manager.stop()?;
+            }
+            Ok(Report::new("Daemon stopped", json!({ "running": false })))
+        }
+        DaemonAction::Restart => {
+            if manager.is_running() {
+                manager.stop()?;
+            }
+            let pid = spawn_daemon(&manager)?;
+            Ok(Report::new(format!("Daemon restarted (PID {})", pid), json!({ "running": true, "pid": pid })))
+        }
+        DaemonAction::Status => {
+            let pid = manager.running_pid();
+            let mut report = Report::new(manager.status(), json!({ "running": pid.is_some(), "pid": pid }));
+            if pid.is_none() {
+                report.code = EXIT_NOT_RUNNING;
+            }
+            Ok(report)
+        }
+        DaemonAction::Run => {
+            unlock_if_needed()?;
+            manager.start()?;
+            Ok(Report::new("", Value::Null))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn arguments_are_parsed_and_checked() {
+        let cli = Cli::try_parse_from(["fass-backup", "status", "--json", "--config-dir", "/tmp/fass"]).unwrap();
+        assert!(cli.json);
+        assert_eq!(cli.config_dir, Some(PathBuf::from("/tmp/fass")));
+        assert!(matches!(cli.command, Some(Command::Status)));
+
+        let cli = Cli::try_parse_from(["fass-backup", "restore", "docs", "--at", "2025-01-07 14:00", "--on-conflict", "newer-wins"])
+            .unwrap();
+        let expected = parse_time("2025-01-07 14:00").unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Restore { at: Some(at), version: None, on_conflict: Some(OnConflict::NewerWins), .. }) if at == expected
+        ));
+        assert!(Cli::try_parse_from(["fass-backup"]).unwrap().command.is_none());
+
+        let conflict = Cli::try_parse_from(["fass-backup", "restore", "docs", "--version", "s1", "--at", "2025-01-07 14:00"]);
+        assert_eq!(conflict.err().unwrap().kind(), clap::error::ErrorKind::ArgumentConflict);
+        assert!(Cli::try_parse_from(["fass-backup", "verify", "--sample", "0"]).is_err());
+        assert!(Cli::try_parse_from(["fass-backup", "restore", "docs", "--at", "yesterday"]).is_err());
+    }
+
+    #[test]
+    fn only_a_locked_backup_exits_as_locked() {
+        let code = |e: std::io::Error| CliError::from(e).code;
+        assert_eq!(code(crypto::locked("Backup repository is locked")), EXIT_LOCKED);
+        assert_eq!(code(std::io::Error::new(ErrorKind::PermissionDenied, "Permission denied")), EXIT_DENIED);
+        assert_eq!(code(std::io::Error::new(ErrorKind::NotFound, "gone")), EXIT_NOT_FOUND);
+        assert_eq!(code(std::io::Error::new(ErrorKind::AlreadyExists, "there")), EXIT_EXISTS);
+        assert_eq!(code(std::io::Error::other("broken")), EXIT_FAILURE);
+
+        let sandbox = storage::testing::sandbox();
+        crypto::init(sandbox.backend.as_ref(), "secret").unwrap();
+        let wrong = crypto::unlock(sandbox.backend.as_ref(), "guess");
+        crypto::forget_key();
+        assert_eq!(code(wrong.unwrap_err()), EXIT_LOCKED);
+
+        let Err(e) = run_command(Command::List) else { panic!("listing a locked backup worked") };
+        assert_eq!(e.code, EXIT_LOCKED);
+        let (code, output) = render(Err(e), true);
+        let document: Value = serde_json::from_str(&output.unwrap()).unwrap();
+        assert_eq!((code, document["code"].as_u64()), (EXIT_LOCKED, Some(EXIT_LOCKED as u64)));
+        assert!(document["error"].as_str().unwrap().contains("locked"));
+    }
+
+    #[test]
+    fn status_reports_the_destination_without_touching_it() {
+        let sandbox = storage::testing::sandbox();
+        let destination = sandbox.dir.join("dest");
+
+        let (code, output) = render(run_command(Command::Status), true);
+        let document: Value = serde_json::from_str(&output.unwrap()).unwrap();
+        assert_eq!(code, 0);
+        assert_eq!(document["destination_available"], true);
+        assert_eq!(document["backup_dir"], destination.to_str().unwrap());
+        assert_eq!(document["files"], 0);
+        assert_eq!(fs::read_dir(&destination).unwrap().count(), 0);
+
+        // used before but the marker is gone, e.g. the drive isn't mounted
+        let metadata = BackupMetadata { destination: destination.clone(), ..Default::default() };
+        metadata.save_to_file(false).unwrap();
+        let report = run_command(Command::Status).ok().unwrap();
+        assert_eq!(report.json["destination_available"], false);
+        assert!(report.json["destination_error"].as_str().unwrap().contains("not available"));
+        assert!(report.text.starts_with(&format!("Backup folder: {} ✗", destination.display())));
+        assert_eq!(fs::read_dir(&destination).unwrap().count(), 0);
+
+        let (_, text) = render(Err(CliError::new(EXIT_NOT_FOUND, "nothing there")), false);
+        assert_eq!(text, Err(String::from("Error: nothing there")));
+    }
+}
@@ -0,0 +1,356 @@
+use serde::{Serialize, Deserialize};
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use argon2::Argon2;
+use sha2::{Sha256, Digest};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::Path;
+use std::sync::Mutex;
+use crate::paths;
+use crate::storage::{self, StorageBackend};
+
+// the derived key, kept in memory once the repository has been unlocked
+lazy_static! {
+    static ref REPO_KEY: Mutex<Option<[u8; 32]>> = Mutex::new(None);
+}
+
+// whether remote destinations have encryption set up, by backend name. the gui asks on
+// every redraw and a request each time would make it crawl
+lazy_static! {
+    static ref REMOTE_INITIALIZED: Mutex<HashMap<String, bool>> = Mutex::new(HashMap::new());
+}
+
+const NONCE_LEN: usize = 24;
+// marks files (like the metadata) that are stored encrypted
+const ENCRYPTED_MAGIC: &[u8] = b"FASSENC1";
+// encrypted with the key so a wrong passphrase can be detected
+const VERIFIER_PLAINTEXT: &[u8] = b"fass-backup-key-check";
+
+/// stored next to the backups, holds nothing secret
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct KeyConfig {
+    salt: String,
+    verifier: String,
+}
+
+const KEY_CONFIG_FILE: &str = "encryption.json";
+
+/// takes the encryption settings along when backups go to another destination, without
+/// them the passphrase couldn't unlock it
+pub fn copy_key_config(from: &dyn StorageBackend, to: &dyn StorageBackend) -> std::io::Result<()> {
+    if to.stat(KEY_CONFIG_FILE)?.is_none() && from.stat(KEY_CONFIG_FILE)?.is_some() {
+        to.put(KEY_CONFIG_FILE, &from.get(KEY_CONFIG_FILE)?)?;
+        REMOTE_INITIALIZED.lock().unwrap().remove(&to.name());
+    }
+    Ok(())
+}
+
+/// the payload of errors that come from a missing or wrong passphrase, so they can be told
+/// apart from other permission errors like an unreadable file
+#[derive(Debug)]
+pub struct Locked(String);
+
+impl std::fmt::Display for Locked {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+impl std::error::Error for Locked {}
+
+/// a PermissionDenied error saying the key is needed for what was asked
+pub fn locked(message: &str) -> Error {
+    Error::new(ErrorKind::PermissionDenied, Locked(message.to_string()))
+}
+
+pub fn is_locked_error(e: &Error) -> bool {
+    e.get_ref().is_some_and(|inner| inner.is::<Locked>())
+}
+
+fn locked_error() -> Error {
+    locked("Backup repository is locked, enter the passphrase first")
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn from_hex(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim();
+    if !text.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+//argon2id with the crate defaults (19 MiB memory, 2 passes)
+fn derive_key(passphrase: &str, salt: &[u8]) -> std::io::Result<[u8; 32]> {
+    let mut key = [0u8; 32];
+    Argon2::default()
+        .hash_password_into(passphrase.as_bytes(), salt, &mut key)
+        .map_err(|e| Error::other(format!("Key derivation failed: {}", e)))?;
+    Ok(key)
+}
+
+fn encrypt_with(key: &[u8; 32], data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let cipher = XChaCha20Poly1305::new(key.into());
+    let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+    let ciphertext = cipher
+        .encrypt(&nonce, data)
+        .map_err(|_| Error::other("Encryption failed"))?;
+
+    let mut out = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+    out.extend_from_slice(&nonce);
+    out.extend_from_slice(&ciphertext);
+    Ok(out)
+}
+
+fn decrypt_with(key: &[u8; 32], data: &[u8]) -> std::io::Result<Vec<u8>> {
+    if data.len() < NONCE_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, "Encrypted data is truncated"));
+    }
+    let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+    let nonce: [u8; NONCE_LEN] = nonce.try_into().expect("split_at gives NONCE_LEN bytes");
+    let cipher = XChaCha20Poly1305::new(key.into());
+    cipher
+        .decrypt(&XNonce::from(nonce), ciphertext)
+        .map_err(|_| Error::new(ErrorKind::InvalidData, "Decryption failed (wrong key or corrupted data)"))
+}
+
+fn set_key(key: [u8; 32]) {
+    *REPO_KEY.lock().unwrap() = Some(key);
+}
+
+// the key is global, tests that unlock must leave it locked for the next one
+#[cfg(test)]
+pub fn forget_key() {
+    *REPO_KEY.lock().unwrap() = None;
+}
+
+//returns the key, loading it from the key file if nobody unlocked the repository yet
+fn current_key() -> Option<[u8; 32]> {
+    let mut guard = REPO_KEY.lock().unwrap();
+    if guard.is_none() {
+        let contents = fs::read_to_string(paths::key_file()).ok()?;
+        let bytes = from_hex(&contents)?;
+        *guard = Some(bytes.try_into().ok()?);
+    }
+    *guard
+}
+
+/// whether encryption is set up in the destination of backend
+pub fn is_initialized(backend: &dyn StorageBackend) -> bool {
+    // remote backends are named by their url
+    if !storage::is_remote(Path::new(&backend.name())) {
+        return matches!(backend.stat(KEY_CONFIG_FILE), Ok(Some(_)));
+    }
+    if let Some(known) = REMOTE_INITIALIZED.lock().unwrap().get(&backend.name()) {
+        return *known;
+    }
+    // a failed request isn't remembered, the next call asks again
+    match backend.stat(KEY_CONFIG_FILE) {
+        Ok(stat) => {
+            REMOTE_INITIALIZED.lock().unwrap().insert(backend.name(), stat.is_some());
+            stat.is_some()
+        }
+        Err(_) => false,
+    }
+}
+
+pub fn is_unlocked() -> bool {
+    current_key().is_some()
+}
+
+/// sets up encryption for the repository in backend with a new passphrase and unlocks it
+pub fn init(backend: &dyn StorageBackend, passphrase: &str) -> std::io::Result<()> {
+    // asked again without the cache, an existing key config must never be replaced
+    if backend.stat(KEY_CONFIG_FILE)?.is_some() {
+        return Err(Error::new(ErrorKind::AlreadyExists, "Encryption is already set up for this backup"));
+    }
+    if passphrase.is_empty() {
+        return Err(Error::new(ErrorKind::InvalidInput, "Passphrase must not be empty"));
+    }
+
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let key = derive_key(passphrase, &salt)?;
+
+    let config = KeyConfig {
+        salt: to_hex(&salt),
+        verifier: to_hex(&encrypt_with(&key, VERIFIER_PLAINTEXT)?),
+    };
+    backend.put(KEY_CONFIG_FILE, &serde_json::to_vec_pretty(&config)?)?;
+    REMOTE_INITIALIZED.lock().unwrap().remove(&backend.name());
+
+    set_key(key);
+    Ok(())
+}
+
+/// derives the key from the passphrase and checks it against the stored verifier
+pub fn unlock(backend: &dyn StorageBackend, passphrase: &str) -> std::io::Result<()> {
+    let config: KeyConfig = serde_json::from_slice(&backend.get(KEY_CONFIG_FILE)?)?;
+    let salt = from_hex(&config.salt)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid salt in encryption.json"))?;
+    let verifier = from_hex(&config.verifier)
+        .ok_or_else(|| Error::new(ErrorKind::InvalidData, "Invalid verifier in encryption.json"))?;
+
+    let key = derive_key(passphrase, &salt)?;
+    match decrypt_with(&key, &verifier) {
+        Ok(plain) if plain == VERIFIER_PLAINTEXT => {
+            set_key(key);
+            Ok(())
+        }
+        _ => Err(locked("Wrong passphrase")),
+    }
+}
+
+/// writes the unlocked key to the key file (mode 0600) so the daemon can run without a prompt
+pub fn save_key_file() -> std::io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let key = current_key().ok_or_else(locked_error)?;
+    let path = paths::key_file();
+    paths::ensure_parent(&path)?;
+    // the mode only applies to new files, so the key goes into a fresh temp file that then
+    // replaces the old one. a key file that was readable by others before never gets the key
+    let temp_path = path.with_extension("key.tmp");
+    match fs::remove_file(&temp_path) {
+        Err(e) if e.kind() != ErrorKind::NotFound => return Err(e),
+        _ => {}
+    }
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .mode(0o600)
+        .open(&temp_path)?;
+    file.write_all(to_hex(&key).as_bytes())?;
+    file.sync_all()?;
+    fs::rename(&temp_path, &path)
+}
+
+// object names are keyed so the file names don't reveal hashes of the plain contents
+pub fn object_name(hash: &str) -> std::io::Result<String> {
+    let key = current_key().ok_or_else(locked_error)?;
+    let mut hasher = Sha256::new();
+    hasher.update(key);
+    hasher.update(hash.as_bytes());
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+pub fn encrypt(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let key = current_key().ok_or_else(locked_error)?;
+    encrypt_with(&key, data)
+}
+
+pub fn decrypt(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let key = current_key().ok_or_else(locked_error)?;
+    decrypt_with(&key, data)
+}
+
+pub fn is_sealed(data: &[u8]) -> bool {
+    data.starts_with(ENCRYPTED_MAGIC)
+}
+
+/// encrypts a whole file body and tags it so load code can tell it apart from plain json
+pub fn seal(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    let mut out = ENCRYPTED_MAGIC.to_vec();
+    out.extend(encrypt(data)?);
+    Ok(out)
+}
+
+pub fn unseal(data: &[u8]) -> std::io::Result<Vec<u8>> {
+    decrypt(data.strip_prefix(ENCRYPTED_MAGIC).unwrap_or(data))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::fs::PermissionsExt;
+
+    #[test]
+    fn key_file_is_private_even_if_it_existed_before() {
+        let _sandbox = storage::testing::sandbox();
+        let path = paths::key_file();
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o644)).unwrap();
+
+        set_key([7; 32]);
+        let saved = save_key_file();
+        forget_key();
+        saved.unwrap();
+
+        assert_eq!(fs::metadata(&path).unwrap().permissions().mode() & 0o777, 0o600);
+        assert_eq!(fs::read_to_string(&path).unwrap(), to_hex(&[7; 32]));
+        assert!(!path.with_extension("key.tmp").exists());
+    }
+
+    #[test]
+    fn data_encrypted_after_init_is_readable_after_a_fresh_unlock() {
+        let sandbox = storage::testing::sandbox();
+        let backend = sandbox.backend.as_ref();
+        assert!(!is_initialized(backend));
+        init(backend, "correct horse").unwrap();
+        assert!(is_initialized(backend) && is_unlocked());
+
+        let encrypted = encrypt(b"file contents").unwrap();
+        let sealed = seal(b"{\"files\": []}").unwrap();
+        let name = object_name("8c105d").unwrap();
+        assert!(!encrypted.windows(13).any(|w| w == b"file contents"));
+        assert!(is_sealed(&sealed) && !is_sealed(&encrypted));
+        assert_ne!(name, "8c105d");
+
+        forget_key();
+        assert!(!is_unlocked());
+        unlock(backend, "correct horse").unwrap();
+        assert_eq!(decrypt(&encrypted).unwrap(), b"file contents");
+        assert_eq!(unseal(&sealed).unwrap(), b"{\"files\": []}");
+        assert_eq!(object_name("8c105d").unwrap(), name);
+    }
+
+    #[test]
+    fn a_wrong_passphrase_is_refused() {
+        let sandbox = storage::testing::sandbox();
+        let backend = sandbox.backend.as_ref();
+        init(backend, "correct horse").unwrap();
+        forget_key();
+
+        let error = unlock(backend, "battery staple").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+        assert!(is_locked_error(&error));
+        assert!(!is_unlocked());
+    }
+
+    #[test]
+    fn init_keeps_an_existing_key_config() {
+        let sandbox = storage::testing::sandbox();
+        let backend = sandbox.backend.as_ref();
+        init(backend, "correct horse").unwrap();
+        let config = backend.get(KEY_CONFIG_FILE).unwrap();
+        forget_key();
+
+        assert_eq!(init(backend, "battery staple").unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(backend.get(KEY_CONFIG_FILE).unwrap(), config);
+        assert!(!is_unlocked());
+        unlock(backend, "correct horse").unwrap();
+    }
+
+    #[test]
+    fn encrypted_object_names_need_the_key() {
+        let _sandbox = storage::testing::sandbox();
+        let error = object_name("8c105d").unwrap_err();
+        assert!(is_locked_error(&error));
+        assert!(crate::backup::object_key("8c105d", crate::backup::Codec::None, true).is_err());
+        assert_eq!(
+            crate::backup::object_key("8c105d", crate::backup::Codec::Zstd, false).unwrap(),
+            "objects/8c/8c105d.zst"
+        );
+    }
+}
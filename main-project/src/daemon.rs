@@ -3,22 +3,16 @@ use signal_hook::consts::signal::*;
 use signal_hook::flag;
 use nix::unistd::Pid;
 use nix::sys::signal as nix_signal;
-use std::fs::{File, OpenOptions, remove_file};
+use std::fs::{self, File, OpenOptions, remove_file};
 use std::io::{Write, Read};
 use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
 use std::thread;
-use std::time::Duration;
+use std::time::{Duration, Instant, SystemTime};
 use std::path::PathBuf;
-use std::env;
-
-fn get_project_dir() -> PathBuf {
-    env::current_dir().unwrap_or_else(|_| PathBuf::from("."))
-}
-
-fn pid_file() -> PathBuf { get_project_dir().join("fass_backup_daemon.pid") }
-fn log_file() -> PathBuf { get_project_dir().join("fass_backup_daemon.log") }
-fn err_file() -> PathBuf { get_project_dir().join("fass_backup_daemon.err") }
+use crate::backup::{BackupMetadata, BackupSettings, ChangeDetection};
+use crate::watcher::ChangeWatcher;
+use crate::paths::{self, pid_file, log_file, err_file};
 
 pub struct DaemonManager {
     pid_path: PathBuf,
@@ -46,6 +40,11 @@ impl DaemonManager {
         }
     }
 
+    /// pid of the daemon, None if it isn't running
+    pub fn running_pid(&self) -> Option<i32> {
+        self.get_pid().filter(|_| self.is_running())
+    }
+
     fn get_pid(&self) -> Option<i32> {
         let mut file = File::open(&self.pid_path).ok()?;
         let mut contents = String::new();
@@ -113,7 +112,10 @@ impl DaemonManager {
             if self.is_running() {
                 // show the backup frequency
                 if let Ok(settings) = crate::backup::BackupSettings::load_from_file() {
-                    if settings.auto_backup_enabled {
+                    if settings.auto_backup_enabled && settings.change_detection == ChangeDetection::Watch {
+                        format!("✓ Daemon is running (PID: {}, watching for changes, full scan every {} min)",
+                            pid, settings.interval_minutes)
+                    } else if settings.auto_backup_enabled {
                         format!("✓ Daemon is running (PID: {}, Interval: {} min)", 
                             pid, settings.interval_minutes)
                     } else {
@@ -149,20 +151,24 @@ impl DaemonManager {
         let metadata = crate::backup::BackupMetadata::load_from_file()
             .map_err(|e| format!("Failed to load metadata: {}", e))?;
         
-        if metadata.files.is_empty() {
+        if metadata.files.is_empty() && metadata.roots.is_empty() {
             return Err("No files to backup. Please perform an initial backup first.".to_string());
         }
 
         println!("Starting FASS Backup daemon...");
-        println!("Working directory: {}", get_project_dir().display());
+        println!("State directory: {}", paths::state_dir().display());
         println!("Backup interval: {} minutes", settings.interval_minutes);
 
+        for file in [pid_file(), log_file()] {
+            paths::ensure_parent(&file).map_err(|e| format!("Failed to create {}: {}", file.display(), e))?;
+        }
         let stdout = File::create(log_file())
             .map_err(|e| format!("Failed to create log file: {}", e))?;
         let stderr = File::create(err_file())
             .map_err(|e| format!("Failed to create error file: {}", e))?;
 
-        let work_dir = get_project_dir();
+        // every file the daemon uses has an absolute path, so the folder only needs to exist
+        let work_dir = paths::state_dir();
 
         let daemon = Daemon::new()
             .pid_file(&self.pid_path, Some(false))
@@ -197,6 +203,35 @@ impl DaemonManager {
     }
 }
 
+// when the metadata file was last written, a change may mean other backup folders
+fn metadata_modified() -> Option<SystemTime> {
+    fs::metadata(paths::metadata_file()).and_then(|m| m.modified()).ok()
+}
+
+//starts, stops or restarts the watcher so it matches the settings and the backup folders
+fn refresh_watcher(watcher: Option<ChangeWatcher>, settings: &BackupSettings, log: &mut File) -> Option<ChangeWatcher> {
+    if !settings.auto_backup_enabled || settings.change_detection != ChangeDetection::Watch {
+        return None;
+    }
+    let roots: Vec<PathBuf> = BackupMetadata::load_from_file()
+        .map(|m| m.roots.into_iter().map(|r| r.path).collect())
+        .unwrap_or_default();
+    if let Some(current) = &watcher && current.roots() == roots.as_slice() {
+        return watcher;
+    }
+
+    match ChangeWatcher::new(&roots, &settings.destination) {
+        Ok(new_watcher) => {
+            writeln!(log, "[{}] Watching {} folder(s) for changes", chrono::Local::now(), roots.len()).unwrap();
+            Some(new_watcher)
+        }
+        Err(e) => {
+            writeln!(log, "[{}] Failed to start watcher, only polling: {}", chrono::Local::now(), e).unwrap();
+            None
+        }
+    }
+}
+
 fn run_daemon(pid_path: &PathBuf) {
     let running = Arc::new(AtomicBool::new(true));
     let r = running.clone();
@@ -219,23 +254,78 @@ fn run_daemon(pid_path: &PathBuf) {
     writeln!(log, "{:=<60}\n", "").unwrap();
     log.flush().unwrap();
 
+    // only set in watch mode
+    let mut watcher: Option<ChangeWatcher> = None;
+
     //keep running backups until told to stop
     while running.load(Ordering::Relaxed) {
-        let settings = crate::backup::BackupSettings::load_from_file()
+        let settings = BackupSettings::load_from_file()
             .unwrap_or_default();
 
         if settings.auto_backup_enabled {
-            writeln!(log, "[{}] Running auto-backup...", chrono::Local::now()).unwrap();
-            let _ = crate::backup::auto_backup();
+            // an unplugged drive means skipping the run, not backing up to its empty mount point
+            if let Err(e) = crate::backup::check_settings_destination(&settings) {
+                writeln!(log, "[{}] Skipping backup: {}", chrono::Local::now(), e).unwrap();
+            } else {
+                writeln!(log, "[{}] Running auto-backup...", chrono::Local::now()).unwrap();
+                match crate::backup::auto_backup() {
+                    // thin out old snapshots once the new one is safely stored
+                    Ok(_) if settings.retention.is_enabled() => match crate::retention::prune_now(false) {
+                        Ok(report) => writeln!(log, "[{}] Retention: {}",
+                            chrono::Local::now(), report.summary()).unwrap(),
+                        Err(e) => writeln!(log, "[{}] Retention failed: {}",
+                            chrono::Local::now(), e).unwrap(),
+                    },
+                    Ok(_) => {}
+                    Err(e) => writeln!(log, "[{}] Auto-backup failed: {}", chrono::Local::now(), e).unwrap(),
+                }
+                let last_verify = BackupMetadata::load_from_file().map(|m| m.last_verify).unwrap_or_default();
+                if settings.verify.is_due(last_verify) {
+                    writeln!(log, "[{}] Verifying {}% of the backup...",
+                        chrono::Local::now(), settings.verify.sample_percent).unwrap();
+                    match crate::verify::verify_now(settings.verify.sample_percent) {
+                        Ok(report) => {
+                            writeln!(log, "[{}] Verify: {}", chrono::Local::now(), report.summary()).unwrap();
+                            for problem in report.problems() {
+                                writeln!(log, "    {}", problem).unwrap();
+                            }
+                        }
+                        Err(e) => writeln!(log, "[{}] Verify failed: {}", chrono::Local::now(), e).unwrap(),
+                    }
+                }
+            }
         } else {
             writeln!(log, "[{}] Auto-backup disabled; sleeping...", chrono::Local::now()).unwrap();
         }
 
         log.flush().unwrap();
 
-        for _ in 0..(settings.interval_minutes * 60) {
-            if !running.load(Ordering::Relaxed) { break; }
-            thread::sleep(Duration::from_secs(1));
+        // in watch mode changes are backed up as they come in, the full run above is
+        // just a periodic sweep for anything the watcher missed
+        watcher = refresh_watcher(watcher, &settings, &mut log);
+        let mut metadata_stamp = metadata_modified();
+        let next_sweep = Instant::now() + Duration::from_secs(settings.interval_minutes * 60);
+        while running.load(Ordering::Relaxed) && Instant::now() < next_sweep {
+            // a folder added or removed in the meantime is watched from now on, not from the
+            // next sweep
+            let stamp = metadata_modified();
+            if stamp != metadata_stamp {
+                metadata_stamp = stamp;
+                watcher = refresh_watcher(watcher, &settings, &mut log);
+            }
+            let Some(active) = watcher.as_mut() else {
+                thread::sleep(Duration::from_secs(1));
+                continue;
+            };
+            if let Some(paths) = active.next_batch(Duration::from_secs(1)) {
+                match crate::backup::backup_changed(&paths) {
+                    Ok(count) => writeln!(log, "[{}] {} change(s) seen, {} file(s) backed up",
+                        chrono::Local::now(), paths.len(), count).unwrap(),
+                    Err(e) => writeln!(log, "[{}] Backup of watched changes failed: {}",
+                        chrono::Local::now(), e).unwrap(),
+                }
+                log.flush().unwrap();
+            }
         }
     }
 
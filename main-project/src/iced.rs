@@ -1,12 +1,13 @@
 use std::path::PathBuf;
-use dirs_next::home_dir;
 use std::process;
 use iced::widget::{
-    button, column, text, container, scrollable, row, text_input, toggler
+    button, column, text, container, scrollable, row, text_input, toggler, pick_list, text_editor,
+    progress_bar
 };
+use iced::futures::SinkExt;
 use iced::{executor, Application, Command, Element, Settings, Theme, Alignment, Length};
 use iced::window::Id;
-use std::sync::{Arc, Mutex};
+use std::sync::{mpsc, Arc, Mutex};
 
 pub fn ui() -> iced::Result {
     Backup::run(Settings::default()) 
@@ -19,6 +20,111 @@ enum Page {
     Edit,
     Upload,
     Settings,
+    Unlock,
+    Restore,
+}
+
+const RETENTION_LABELS: [&str; 5] = [
+    "Keep last snapshots:",
+    "Keep hourly snapshots:",
+    "Keep daily snapshots:",
+    "Keep weekly snapshots:",
+    "Keep monthly snapshots:",
+];
+
+fn retention_values(policy: &super::retention::RetentionPolicy) -> [usize; 5] {
+    [
+        policy.keep_last,
+        policy.keep_hourly,
+        policy.keep_daily,
+        policy.keep_weekly,
+        policy.keep_monthly,
+    ]
+}
+
+//dry run report as shown on the settings page, lists the first few snapshots affected
+fn describe_prune(report: &super::retention::PruneReport) -> String {
+    let mut lines = vec![report.summary()];
+    for id in report.removed_snapshots.iter().take(10) {
+        lines.push(format!("  snapshot {}", id));
+    }
+    if report.removed_snapshots.len() > 10 {
+        lines.push(format!("  ... and {} more", report.removed_snapshots.len() - 10));
+    }
+    lines.join("\n")
+}
+
+//verify result as shown on the settings page, lists the first few problems
+fn describe_verify(report: &super::verify::VerifyReport) -> String {
+    let problems = report.problems();
+    let mut lines = vec![report.summary()];
+    lines.extend(problems.iter().take(10).map(|p| format!("  {}", p)));
+    if problems.len() > 10 {
+        lines.push(format!("  ... and {} more", problems.len() - 10));
+    }
+    if report.is_healthy() && report.orphaned.is_empty() {
+        lines.push(String::from("  Everything checked is intact"));
+    }
+    lines.join("\n")
+}
+
+// fields of the s3 connection, shown for s3:// destinations. same order as in s3_field
+const S3_LABELS: [&str; 4] = ["S3 Endpoint:", "Region:", "Access Key ID:", "Secret Access Key:"];
+
+fn s3_field(s3: &mut super::s3::S3Settings, index: usize) -> &mut String {
+    match index {
+        0 => &mut s3.endpoint,
+        1 => &mut s3.region,
+        2 => &mut s3.access_key_id,
+        _ => &mut s3.secret_access_key,
+    }
+}
+
+// fields of the ssh login, shown for sftp:// destinations
+const SFTP_LABELS: [&str; 2] = ["SSH Key File:", "Known Hosts File:"];
+
+// login for dav:// and davs:// destinations
+const WEBDAV_LABELS: [&str; 2] = ["WebDAV User:", "Password:"];
+
+//local time of a unix timestamp as shown on the pages
+fn format_time(timestamp: i64) -> String {
+    chrono::DateTime::from_timestamp(timestamp, 0)
+        .map(|t| t.with_timezone(&chrono::Local).format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| String::from("unknown"))
+}
+
+// number of excluded paths the rules preview lists before cutting off
+const RULES_PREVIEW_LIMIT: usize = 50;
+
+// number of per file results the restore page lists before cutting off
+const RESTORE_REPORT_LIMIT: usize = 200;
+
+//which rules the editor on the settings page is showing
+#[derive(Debug, Clone, Default, PartialEq)]
+enum RuleTarget {
+    #[default]
+    Global,
+    Root(PathBuf),
+}
+
+impl std::fmt::Display for RuleTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RuleTarget::Global => write!(f, "All folders"),
+            RuleTarget::Root(path) => write!(f, "{}", path.display()),
+        }
+    }
+}
+
+//one pattern per line, blank lines are dropped
+fn rules_from_editor(content: &text_editor::Content) -> Vec<String> {
+    content
+        .text()
+        .lines()
+        .map(|line| line.trim())
+        .filter(|line| !line.is_empty())
+        .map(String::from)
+        .collect()
 }
 
 #[derive(Default)]
@@ -29,8 +135,47 @@ struct Backup {
     selected_file: Option<PathBuf>,
     settings: super::backup::BackupSettings,
     interval_input: String,
+    paranoid_interval_input: String,
+    verify_interval_input: String,
+    verify_sample_input: String,
+    verify_running: bool,
+    verify_report: String,
+    worker_threads_input: String,
+    io_threads_input: String,
+    compression_level_input: String,
+    passphrase_input: String,
+    remember_key: bool,
+    // whether the saved destination has encryption set up, asked once instead of on every redraw
+    encryption_initialized: bool,
+    encryption_status: String,
+    retention_inputs: Vec<String>,
+    prune_report: String,
+    rules_target: RuleTarget,
+    exclude_editor: text_editor::Content,
+    include_editor: text_editor::Content,
+    rules_preview: String,
+    destination_status: String,
     daemon_status: String,
     dark_mode_enabled: bool,
+    restore_points: Vec<super::backup::RestorePoint>,
+    restore_point: super::backup::RestorePoint,
+    // the point in time picker on the manage files page
+    restore_dates: Vec<chrono::NaiveDate>,
+    restore_date: Option<chrono::NaiveDate>,
+    restore_hour: u32,
+    restore_minute: u32,
+    restore_folder_input: String,
+    restore_to_original: bool,
+    restore_target: Option<PathBuf>,
+    // (done, total) while a restore is running
+    restore_progress: Option<(usize, usize)>,
+    restore_report: Option<super::backup::RestoreReport>,
+    restore_status: String,
+    conflict_policy: super::backup::ConflictPolicy,
+    // the conflict the running restore is waiting on an answer for
+    conflict_question: Option<super::backup::RestoreConflict>,
+    conflict_for_all: bool,
+    conflict_reply: Option<mpsc::Sender<(super::backup::ConflictPolicy, bool)>>,
 }
 
 #[derive(Debug, Clone)]
@@ -45,15 +190,68 @@ enum Message {
     DeleteFile,
     OpenFolder,
     Restore,
+    RestoreVersion(String),
+    RestoreDeleted,
+    RestoreFolder,
+    ToRestore,
+    RestorePointSelected(super::backup::RestorePoint),
+    RestoreDateSelected(chrono::NaiveDate),
+    RestoreHourSelected(u32),
+    RestoreMinuteSelected(u32),
+    RestoreAsOf,
+    RestoreFolderChanged(String),
+    ToggleRestoreToOriginal(bool),
+    ChooseRestoreTarget,
+    StartRestore,
+    ConflictPolicySelected(super::backup::ConflictPolicy),
+    RestoreConflict(super::backup::RestoreConflict),
+    ToggleConflictForAll(bool),
+    AnswerConflict(super::backup::ConflictPolicy),
+    RestoreProgress(usize, usize),
+    RestoreFinished(Result<super::backup::RestoreReport, String>),
     RefreshFiles,
     ToggleAutoBackup(bool),
     IntervalInputChanged(String),
+    ToggleWatchMode(bool),
+    ToggleParanoidHash(bool),
+    ParanoidIntervalChanged(String),
+    ToggleScheduledVerify(bool),
+    VerifyIntervalChanged(String),
+    VerifySampleChanged(String),
+    VerifyNow,
+    VerifyFinished(Result<super::verify::VerifyReport, String>),
+    WorkerThreadsChanged(String),
+    IoThreadsChanged(String),
+    ToggleCompression(bool),
+    CompressionLevelChanged(String),
+    PassphraseChanged(String),
+    ToggleRememberKey(bool),
+    Unlock,
+    EnableEncryption,
+    ToggleEncryption(bool),
+    RetentionInputChanged(usize, String),
+    PreviewPrune,
+    PruneNow,
+    SelectRuleTarget(RuleTarget),
+    ExcludeRulesEdited(text_editor::Action),
+    IncludeRulesEdited(text_editor::Action),
+    ToggleIgnoreFiles(bool),
+    PreviewRules,
+    SaveRules,
     SaveSettings,
     StartDaemon,
     StopDaemon,
     RestartDaemon,
     RefreshDaemonStatus,
     ToggleDarkMode(bool),
+    ChooseDestination,
+    DestinationChanged(String),
+    S3InputChanged(usize, String),
+    SftpInputChanged(usize, String),
+    WebdavInputChanged(usize, String),
+    CheckDestination,
+    AddReplica,
+    RemoveReplica(PathBuf),
 }
 
 impl Application for Backup {
@@ -86,17 +284,46 @@ impl Application for Backup {
 
         let daemon_status = super::daemon::daemon_status();
 
+        // ask for the passphrase first if the backup is encrypted and no key file was found
+        let encryption_initialized = super::crypto::is_initialized(super::storage::open(&settings).as_ref());
+        let locked = encryption_initialized && !super::crypto::is_unlocked();
+        let current_page = if locked { Page::Unlock } else { Page::Menu };
+
         let dark_mode_enabled = settings.dark_mode;
         (
             Self {
-                current_page: Page::Menu,
+                current_page,
                 metadata,
                 files,
                 selected_file: None,
                 interval_input: settings.interval_minutes.to_string(),
+                paranoid_interval_input: settings.paranoid_interval_hours.to_string(),
+                verify_interval_input: settings.verify.interval_hours.to_string(),
+                verify_sample_input: settings.verify.sample_percent.to_string(),
+                verify_running: false,
+                verify_report: String::new(),
+                worker_threads_input: settings.worker_threads.to_string(),
+                io_threads_input: settings.io_threads.to_string(),
+                compression_level_input: settings.compression_level.to_string(),
+                passphrase_input: String::new(),
+                remember_key: true,
+                encryption_initialized,
+                encryption_status: String::new(),
+                retention_inputs: retention_values(&settings.retention)
+                    .iter()
+                    .map(|n| n.to_string())
+                    .collect(),
+                prune_report: String::new(),
+                rules_target: RuleTarget::Global,
+                exclude_editor: text_editor::Content::with_text(&settings.global_excludes.join("\n")),
+                include_editor: text_editor::Content::with_text(&settings.global_includes.join("\n")),
+                rules_preview: String::new(),
+                destination_status: String::new(),
                 settings,
                 daemon_status,
                 dark_mode_enabled,
+                conflict_policy: super::backup::ConflictPolicy::Ask,
+                ..Default::default()
             },
             Command::none(),
         )
@@ -129,7 +356,10 @@ impl Application for Backup {
                     println!("No metadata available. Perform initial backup first.");
                 }
             }
-            Message::ToEdit => self.current_page = Page::Edit,
+            Message::ToEdit => {
+                self.load_restore_dates();
+                self.current_page = Page::Edit;
+            }
             Message::ToSettings => self.current_page = Page::Settings,
             Message::ToMenu => {
                 self.current_page = Page::Menu;
@@ -162,56 +392,116 @@ impl Application for Backup {
             }
             Message::DeleteFile => {
                 if let Some(selected_path) = self.selected_file.take() {
-                    if let Some(pos) = self.files.iter().position(|f| f.original_path == selected_path) {
-                        let backup_path = self.files[pos].backup_path.clone();
-                        let _ = super::backup::delete_selected(backup_path);
-                        self.files.remove(pos);
-                        let _ = super::backup::update_file_info(self.files.clone());
-                    } else {
-                        eprintln!("DeleteFile: selected file not found in files list");
+                    match super::backup::delete_file(&selected_path) {
+                        Ok(meta) => {
+                            self.files = meta.files.values().cloned().collect();
+                            self.metadata = Some(Arc::new(Mutex::new(meta)));
+                        }
+                        Err(e) => eprintln!("Failed to delete {}: {}", selected_path.display(), e),
                     }
                 }
             }
             Message::OpenFolder => {
-                if let Some(home) = home_dir() {
-                    let backup_folder = home.join("Backup");
+                let backup_folder = super::backup::BackupSettings::load_from_file().unwrap_or_default().destination;
+                if super::storage::is_remote(&backup_folder) {
+                    eprintln!("Backup destination {} is not a local folder", backup_folder.display());
+                } else if backup_folder.is_dir() {
                     let _ = process::Command::new("open")
                         .arg(backup_folder)
                         .status();
+                } else {
+                    eprintln!("Backup folder {} is not available", backup_folder.display());
                 }
             }
-            Message::Restore => {
-                if let Some(selected_path) = &self.selected_file {
-                    if let Some(file) = self.files.iter().find(|f| f.original_path == *selected_path) {
-                        let source = &file.backup_path;
-                        let destination = &file.original_path;
-                        
-                        // make sure parent directory exists
-                        if let Some(parent) = destination.parent() {
-                            if let Err(e) = std::fs::create_dir_all(parent) {
-                                eprintln!("Failed to create directory {}: {}", parent.display(), e);
-                                return Command::none();
-                            }
-                        }
+            // single files go through the restore page too, it shows conflicts and the result
+            Message::Restore => return self.restore_selected(super::backup::RestorePoint::Latest),
+            Message::RestoreDeleted => {
+                // deleted files aren't part of the newest state, take the last version they had
+                let last = self
+                    .selected_file
+                    .as_ref()
+                    .and_then(|path| self.files.iter().find(|f| &f.original_path == path))
+                    .and_then(|file| file.versions.last());
+                if let Some(version) = last {
+                    return self.restore_selected(super::backup::RestorePoint::Snapshot(version.snapshot_id.clone()));
+                }
+            }
+            Message::RestoreVersion(snapshot_id) => return self.restore_selected(super::backup::RestorePoint::Snapshot(snapshot_id)),
+            Message::RestoreFolder => {
+                // the folder the selected file is in, deleted folders can be restored too
+                let folder = self
+                    .selected_file
+                    .as_ref()
+                    .and_then(|path| path.parent())
+                    .map(|p| p.display().to_string())
+                    .unwrap_or_default();
+                self.open_restore_page(folder, super::backup::RestorePoint::Latest);
+            }
+            Message::ToRestore => self.open_restore_page(String::new(), super::backup::RestorePoint::Latest),
+            Message::RestoreDateSelected(day) => self.restore_date = Some(day),
+            Message::RestoreHourSelected(hour) => self.restore_hour = hour,
+            Message::RestoreMinuteSelected(minute) => self.restore_minute = minute,
+            Message::RestoreAsOf => {
+                use chrono::TimeZone;
 
-                        //dont overwrite existing files
-                        if destination.exists() {
-                            eprintln!("Skipped restore: destination already exists ({})", destination.display());
-                        } else {
-                            match std::fs::copy(source, destination) {
-                                Ok(_) => println!("Restored: {}", destination.display()),
-                                Err(e) => eprintln!(
-                                    "Failed to restore {} from {}: {}",
-                                    destination.display(),
-                                    source.display(),
-                                    e
-                                ),
-                            }
-                        }
-                    } else {
-                        eprintln!("RestoreFile: selected file not found in metadata");
+                let time = self
+                    .restore_date
+                    .and_then(|day| day.and_hms_opt(self.restore_hour, self.restore_minute, 0))
+                    .and_then(|time| chrono::Local.from_local_datetime(&time).earliest());
+                if let Some(time) = time {
+                    // the selected file's folder, everything if nothing is selected
+                    let folder = self
+                        .selected_file
+                        .as_ref()
+                        .and_then(|path| path.parent())
+                        .map(|p| p.display().to_string())
+                        .unwrap_or_default();
+                    self.open_restore_page(folder, super::backup::RestorePoint::AsOf(time.timestamp()));
+                }
+            }
+            Message::RestorePointSelected(point) => self.restore_point = point,
+            Message::RestoreFolderChanged(value) => self.restore_folder_input = value,
+            Message::ToggleRestoreToOriginal(enabled) => self.restore_to_original = enabled,
+            Message::ChooseRestoreTarget => {
+                if let Some(path) = super::backup::select_folder() {
+                    self.restore_target = Some(path);
+                }
+            }
+            Message::StartRestore => return self.start_restore(),
+            Message::ConflictPolicySelected(policy) => self.conflict_policy = policy,
+            Message::RestoreConflict(conflict) => self.conflict_question = Some(conflict),
+            Message::ToggleConflictForAll(enabled) => self.conflict_for_all = enabled,
+            Message::AnswerConflict(policy) => {
+                self.conflict_question = None;
+                if let Some(reply) = &self.conflict_reply {
+                    let _ = reply.send((policy, self.conflict_for_all));
+                }
+            }
+            Message::RestoreProgress(done, total) => {
+                if self.restore_progress.is_some() {
+                    self.restore_progress = Some((done, total));
+                }
+            }
+            Message::RestoreFinished(result) => {
+                self.restore_progress = None;
+                self.conflict_question = None;
+                self.conflict_reply = None;
+                self.conflict_for_all = false;
+                match result {
+                    Ok(report) => {
+                        println!("{}", report.summary());
+                        self.restore_status = report.summary();
+                        self.restore_report = Some(report);
+                    }
+                    Err(e) => {
+                        eprintln!("Restore failed: {}", e);
+                        self.restore_status = format!("Restore failed: {}", e);
                     }
                 }
+                // files restored to where they were are no longer deleted
+                if let Ok(meta) = super::backup::BackupMetadata::load_from_file() {
+                    self.files = meta.files.values().cloned().collect();
+                }
             }
             Message::RefreshFiles => {
                 if let Ok(meta) = super::backup::BackupMetadata::load_from_file() {
@@ -224,24 +514,267 @@ impl Application for Backup {
             Message::IntervalInputChanged(value) => {
                 self.interval_input = value;
             }
-            Message::SaveSettings => {
-                if let Ok(interval) = self.interval_input.parse::<u64>() {
-                    if interval > 0 {
-                        self.settings.interval_minutes = interval;
+            Message::ToggleWatchMode(enabled) => {
+                self.settings.change_detection = if enabled {
+                    super::backup::ChangeDetection::Watch
+                } else {
+                    super::backup::ChangeDetection::Polling
+                };
+            }
+            Message::ToggleParanoidHash(enabled) => {
+                self.settings.paranoid_hash_enabled = enabled;
+            }
+            Message::ParanoidIntervalChanged(value) => {
+                self.paranoid_interval_input = value;
+            }
+            Message::ToggleScheduledVerify(enabled) => {
+                self.settings.verify.enabled = enabled;
+            }
+            Message::VerifyIntervalChanged(value) => {
+                self.verify_interval_input = value;
+            }
+            Message::VerifySampleChanged(value) => {
+                self.verify_sample_input = value;
+            }
+            Message::VerifyNow => {
+                if self.verify_running {
+                    return Command::none();
+                }
+                let sample_percent = match self.verify_sample_input.trim().parse::<u8>() {
+                    Ok(percent) if (1..=100).contains(&percent) => percent,
+                    _ => {
+                        self.verify_report = String::from("Sample must be a percentage from 1 to 100");
+                        return Command::none();
+                    }
+                };
+                self.verify_running = true;
+                self.verify_report = format!("Verifying {}% of the backup...", sample_percent);
+                // rereading objects can take minutes, keep the window responsive meanwhile
+                return Command::perform(
+                    async move {
+                        let (finished, result) = iced::futures::channel::oneshot::channel();
+                        std::thread::spawn(move || {
+                            let report = super::verify::verify_now(sample_percent);
+                            let _ = finished.send(report.map_err(|e| e.to_string()));
+                        });
+                        result
+                            .await
+                            .unwrap_or_else(|_| Err(String::from("Verify stopped unexpectedly")))
+                    },
+                    Message::VerifyFinished,
+                );
+            }
+            Message::VerifyFinished(result) => {
+                self.verify_running = false;
+                self.verify_report = match result {
+                    Ok(report) => {
+                        println!("{}", report.summary());
+                        describe_verify(&report)
+                    }
+                    Err(e) => format!("Verify failed: {}", e),
+                };
+            }
+            Message::WorkerThreadsChanged(value) => {
+                self.worker_threads_input = value;
+            }
+            Message::IoThreadsChanged(value) => {
+                self.io_threads_input = value;
+            }
+            Message::ToggleCompression(enabled) => {
+                self.settings.compression_enabled = enabled;
+            }
+            Message::CompressionLevelChanged(value) => {
+                self.compression_level_input = value;
+            }
+            Message::PassphraseChanged(value) => {
+                self.passphrase_input = value;
+            }
+            Message::ToggleRememberKey(enabled) => {
+                self.remember_key = enabled;
+            }
+            Message::Unlock => {
+                match super::crypto::unlock(super::storage::open(&self.settings).as_ref(), &self.passphrase_input) {
+                    Ok(_) => {
+                        self.passphrase_input.clear();
+                        self.encryption_status.clear();
+                        // the daemon reads the key file since it can't prompt
+                        if self.remember_key && let Err(e) = super::crypto::save_key_file() {
+                            eprintln!("Failed to save key file: {}", e);
+                        }
+                        if let Ok(meta) = super::backup::BackupMetadata::load_from_file() {
+                            self.files = meta.files.values().cloned().collect();
+                            self.metadata = Some(Arc::new(Mutex::new(meta)));
+                        }
+                        self.current_page = Page::Menu;
+                    }
+                    Err(e) => self.encryption_status = format!("Unlock failed: {}", e),
+                }
+            }
+            Message::EnableEncryption => {
+                // the encryption settings are stored in the destination. it's only looked at
+                // here, saving the settings prepares it
+                if let Err(e) = super::backup::inspect_settings_destination(&self.settings) {
+                    self.encryption_status = format!("Failed to enable encryption: {}", e);
+                    return Command::none();
+                }
+                match super::crypto::init(super::storage::open(&self.settings).as_ref(), &self.passphrase_input) {
+                    Ok(_) => {
+                        self.passphrase_input.clear();
+                        self.encryption_initialized = true;
+                        if let Err(e) = super::crypto::save_key_file() {
+                            eprintln!("Failed to save key file: {}", e);
+                        }
+                        self.settings.encryption_enabled = true;
+                        self.encryption_status = String::from("Encryption enabled");
+                        self.apply_encryption_setting();
+                    }
+                    Err(e) => self.encryption_status = format!("Failed to enable encryption: {}", e),
+                }
+            }
+            Message::ToggleEncryption(enabled) => {
+                self.settings.encryption_enabled = enabled;
+            }
+            Message::RetentionInputChanged(index, value) => {
+                self.retention_inputs[index] = value;
+            }
+            Message::PreviewPrune => {
+                self.prune_report = match super::retention::prune_now(true) {
+                    Ok(report) => describe_prune(&report),
+                    Err(e) => format!("Preview failed: {}", e),
+                };
+            }
+            Message::PruneNow => {
+                self.prune_report = match super::retention::prune_now(false) {
+                    Ok(report) => {
+                        if let Ok(meta) = super::backup::BackupMetadata::load_from_file() {
+                            self.files = meta.files.values().cloned().collect();
+                            self.metadata = Some(Arc::new(Mutex::new(meta)));
+                        }
+                        report.summary()
+                    }
+                    Err(e) => format!("Prune failed: {}", e),
+                };
+            }
+            Message::SelectRuleTarget(target) => {
+                self.rules_target = target;
+                self.rules_preview.clear();
+                self.load_rules_editors();
+            }
+            Message::ExcludeRulesEdited(action) => self.exclude_editor.perform(action),
+            Message::IncludeRulesEdited(action) => self.include_editor.perform(action),
+            Message::ToggleIgnoreFiles(enabled) => {
+                self.settings.honor_ignore_files = enabled;
+            }
+            Message::PreviewRules => self.rules_preview = self.preview_rules(),
+            Message::SaveRules => {
+                let excludes = rules_from_editor(&self.exclude_editor);
+                let includes = rules_from_editor(&self.include_editor);
+                match &self.rules_target {
+                    RuleTarget::Global => {
+                        self.settings.global_excludes = excludes;
+                        self.settings.global_includes = includes;
                         if let Err(e) = self.settings.save_to_file() {
                             eprintln!("Failed to save settings: {}", e);
-                        } else {
-                            println!("Settings saved successfully");
-                            // restart daemon to apply new interval if its running
-                            if super::daemon::is_daemon_running() {
-                                let _ = super::daemon::restart_daemon();
+                        }
+                    }
+                    RuleTarget::Root(path) => {
+                        if let Some(meta) = &self.metadata {
+                            let mut metadata = meta.lock().unwrap();
+                            if let Some(root) = metadata.roots.iter_mut().find(|r| &r.path == path) {
+                                root.exclude = excludes;
+                                root.include = includes;
+                            }
+                            if let Err(e) = metadata.save_to_file(self.settings.encryption_enabled) {
+                                eprintln!("Failed to save metadata: {}", e);
                             }
                         }
-                    } else {
+                    }
+                }
+                self.rules_preview = String::from("Rules saved");
+            }
+            Message::SaveSettings => {
+                // every field is checked before any of them is applied, so one bad value
+                // doesn't leave the others half saved
+
+                // every retention field has to be a whole number, 0 turns the rule off
+                let parsed: Result<Vec<usize>, _> =
+                    self.retention_inputs.iter().map(|v| v.trim().parse::<usize>()).collect();
+                let Ok(retention) = parsed else {
+                    eprintln!("Retention values must be whole numbers");
+                    return Command::none();
+                };
+
+                let paranoid_hours = match self.paranoid_interval_input.trim().parse::<u64>() {
+                    Ok(hours) if hours > 0 => hours,
+                    _ => {
+                        eprintln!("Full hash interval must be a whole number of hours above 0");
+                        return Command::none();
+                    }
+                };
+
+                let (verify_hours, verify_percent) = match (
+                    self.verify_interval_input.trim().parse::<u64>(),
+                    self.verify_sample_input.trim().parse::<u8>(),
+                ) {
+                    (Ok(hours), Ok(percent)) if hours > 0 && (1..=100).contains(&percent) => (hours, percent),
+                    _ => {
+                        eprintln!("Verify interval must be hours above 0 and the sample a percentage from 1 to 100");
+                        return Command::none();
+                    }
+                };
+
+                let (workers, io) = match (
+                    self.worker_threads_input.trim().parse::<usize>(),
+                    self.io_threads_input.trim().parse::<usize>(),
+                ) {
+                    (Ok(workers), Ok(io)) => (workers, io),
+                    _ => {
+                        eprintln!("Thread counts must be whole numbers");
+                        return Command::none();
+                    }
+                };
+
+                // zstd accepts levels 1 (fastest) to 22 (smallest)
+                let level = match self.compression_level_input.parse::<i32>() {
+                    Ok(level) if (1..=22).contains(&level) => level,
+                    _ => {
+                        eprintln!("Compression level must be between 1 and 22");
+                        return Command::none();
+                    }
+                };
+
+                let interval = match self.interval_input.parse::<u64>() {
+                    Ok(interval) if interval > 0 => interval,
+                    Ok(_) => {
                         eprintln!("Interval must be greater than 0");
+                        return Command::none();
+                    }
+                    Err(_) => {
+                        eprintln!("Invalid interval value");
+                        return Command::none();
+                    }
+                };
+
+                let policy = &mut self.settings.retention;
+                policy.keep_last = retention[0];
+                policy.keep_hourly = retention[1];
+                policy.keep_daily = retention[2];
+                policy.keep_weekly = retention[3];
+                policy.keep_monthly = retention[4];
+                self.settings.paranoid_interval_hours = paranoid_hours;
+                self.settings.verify.interval_hours = verify_hours;
+                self.settings.verify.sample_percent = verify_percent;
+                self.settings.worker_threads = workers;
+                self.settings.io_threads = io;
+                self.settings.compression_level = level;
+                self.settings.interval_minutes = interval;
+
+                if self.apply_encryption_setting() {
+                    println!("Settings saved successfully");
+                    // restart daemon to apply new interval if its running
+                    if super::daemon::is_daemon_running() {
+                        let _ = super::daemon::restart_daemon();
                     }
-                } else {
-                    eprintln!("Invalid interval value");
                 }
             }
             Message::StartDaemon => {
@@ -290,25 +823,295 @@ impl Application for Backup {
                     eprintln!("Failed to save dark mode setting: {}", e);
                 }
             }
+            // takes effect once the settings are saved
+            Message::ChooseDestination => {
+                if let Some(path) = super::backup::select_destination(&self.settings.destination) {
+                    self.settings.destination = path;
+                    self.destination_status = self.describe_destination();
+                }
+            }
+            Message::DestinationChanged(value) => self.settings.destination = PathBuf::from(value.trim()),
+            Message::S3InputChanged(index, value) => *s3_field(&mut self.settings.s3, index) = value,
+            Message::SftpInputChanged(index, value) => {
+                let sftp = &mut self.settings.sftp;
+                match index {
+                    0 => sftp.private_key = PathBuf::from(value),
+                    _ => sftp.known_hosts = PathBuf::from(value),
+                }
+            }
+            Message::WebdavInputChanged(index, value) => {
+                let webdav = &mut self.settings.webdav;
+                match index {
+                    0 => webdav.username = value,
+                    _ => webdav.password = value,
+                }
+            }
+            Message::CheckDestination => self.destination_status = self.describe_destination(),
+            Message::AddReplica => {
+                if let Some(path) = super::backup::select_destination(&self.settings.destination)
+                    && path != self.settings.destination
+                    && !self.settings.replicas.contains(&path)
+                {
+                    self.settings.replicas.push(path);
+                }
+            }
+            Message::RemoveReplica(path) => self.settings.replicas.retain(|p| p != &path),
         }
         Command::none()
     }
 
-    fn view(&self) -> Element<Self::Message> {
+    fn view(&self) -> Element<'_, Self::Message> {
         match self.current_page {
             Page::Menu => self.view_menu(),
             Page::Edit => self.view_edit(),
             Page::Upload => self.view_stub("Upload"),
             Page::Settings => self.view_settings(),
+            Page::Unlock => self.view_unlock(),
+            Page::Restore => self.view_restore(),
         }
     }
 }
 
 impl Backup {
-    fn view_menu(&self) -> Element<Message> {
+    fn backup_roots(&self) -> Vec<super::backup::BackupRoot> {
+        self.metadata
+            .as_ref()
+            .map(|meta| meta.lock().unwrap().roots.clone())
+            .unwrap_or_default()
+    }
+
+    //fills the rule editors with the saved rules of the selected target
+    fn load_rules_editors(&mut self) {
+        let (excludes, includes) = match &self.rules_target {
+            RuleTarget::Global => (self.settings.global_excludes.clone(), self.settings.global_includes.clone()),
+            RuleTarget::Root(path) => self
+                .backup_roots()
+                .into_iter()
+                .find(|r| &r.path == path)
+                .map(|r| (r.exclude, r.include))
+                .unwrap_or_default(),
+        };
+        self.exclude_editor = text_editor::Content::with_text(&excludes.join("\n"));
+        self.include_editor = text_editor::Content::with_text(&includes.join("\n"));
+    }
+
+    //lists what the rules in the editors would exclude, without saving them first
+    fn preview_rules(&self) -> String {
+        let mut settings = self.settings.clone();
+        let mut roots = self.backup_roots();
+        let excludes = rules_from_editor(&self.exclude_editor);
+        let includes = rules_from_editor(&self.include_editor);
+        match &self.rules_target {
+            RuleTarget::Global => {
+                settings.global_excludes = excludes;
+                settings.global_includes = includes;
+            }
+            RuleTarget::Root(path) => {
+                roots.retain(|r| &r.path == path);
+                for root in &mut roots {
+                    root.exclude = excludes.clone();
+                    root.include = includes.clone();
+                }
+            }
+        }
+        if roots.is_empty() {
+            return String::from("No backup folders to preview yet");
+        }
+
+        let excluded: Vec<PathBuf> = roots
+            .iter()
+            .flat_map(|root| super::rules::preview_exclusions(root, &settings))
+            .collect();
+        let mut lines = vec![format!("{} path(s) would be excluded", excluded.len())];
+        lines.extend(excluded.iter().take(RULES_PREVIEW_LIMIT).map(|p| format!("  {}", p.display())));
+        if excluded.len() > RULES_PREVIEW_LIMIT {
+            lines.push(format!("  ... and {} more", excluded.len() - RULES_PREVIEW_LIMIT));
+        }
+        lines.join("\n")
+    }
+
+    // only looks, the destination is prepared once the settings are saved
+    fn describe_destination(&self) -> String {
+        self.destination_message(super::backup::inspect_settings_destination(&self.settings))
+    }
+
+    fn destination_message(&self, checked: std::io::Result<()>) -> String {
+        match checked {
+            Ok(_) => format!("{} is ready", self.settings.destination.display()),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    //switches to the restore page with the snapshots of the current metadata to choose from
+    fn open_restore_page(&mut self, folder: String, point: super::backup::RestorePoint) {
+        self.restore_points = vec![super::backup::RestorePoint::Latest];
+        // a time picked on the manage files page comes right after the newest versions
+        if let super::backup::RestorePoint::AsOf(_) = point {
+            self.restore_points.push(point.clone());
+        }
+        if let Ok(meta) = super::backup::BackupMetadata::load_from_file() {
+            // newest first
+            self.restore_points
+                .extend(meta.snapshots.iter().rev().map(|s| super::backup::RestorePoint::Snapshot(s.id.clone())));
+        }
+        self.restore_point = point;
+        self.restore_folder_input = folder;
+        self.current_page = Page::Restore;
+    }
+
+    //the days from the first snapshot until today, newest first, for the point in time picker
+    fn load_restore_dates(&mut self) {
+        let today = chrono::Local::now().date_naive();
+        let first = super::backup::BackupMetadata::load_from_file()
+            .ok()
+            .and_then(|meta| meta.snapshots.first().map(|s| s.timestamp))
+            .and_then(|time| chrono::DateTime::from_timestamp(time, 0))
+            .map(|time| time.with_timezone(&chrono::Local).date_naive())
+            .unwrap_or(today);
+        self.restore_dates = first.iter_days().take_while(|day| *day <= today).collect();
+        self.restore_dates.reverse();
+        if self.restore_date.is_none_or(|day| !self.restore_dates.contains(&day)) {
+            self.restore_date = Some(today);
+        }
+    }
+
+    //restores the selected file to where it was, as it was in the given snapshot
+    fn restore_selected(&mut self, point: super::backup::RestorePoint) -> Command<Message> {
+        let Some(path) = self.selected_file.clone() else {
+            return Command::none();
+        };
+        self.open_restore_page(path.display().to_string(), point);
+        self.restore_to_original = true;
+        self.start_restore()
+    }
+
+    //starts the restore set up on the restore page on its own thread. progress, conflicts
+    //to ask about and the result come back as messages
+    fn start_restore(&mut self) -> Command<Message> {
+        if self.restore_progress.is_some() {
+            return Command::none();
+        }
+        let target = if self.restore_to_original {
+            None
+        } else if let Some(target) = &self.restore_target {
+            Some(target.clone())
+        } else {
+            self.restore_status = String::from("Choose a folder to restore into first");
+            return Command::none();
+        };
+        let folder = self.restore_folder_input.trim();
+        let request = super::backup::RestoreRequest {
+            folder: (!folder.is_empty()).then(|| PathBuf::from(folder)),
+            point: self.restore_point.clone(),
+            target,
+            conflicts: self.conflict_policy,
+        };
+        self.restore_progress = Some((0, 0));
+        self.restore_report = None;
+        self.restore_status.clear();
+        let (reply, replies) = mpsc::channel();
+        self.conflict_reply = Some(reply);
+
+        iced::command::channel(100, move |mut output| async move {
+            let (finished, result) = iced::futures::channel::oneshot::channel();
+            let messages = Mutex::new(output.clone());
+            std::thread::spawn(move || {
+                // one question at a time, an answer for all remaining conflicts is remembered
+                let answers = Mutex::new((replies, None));
+                let ask = |conflict: &super::backup::RestoreConflict| {
+                    let mut answers = answers.lock().unwrap();
+                    if let Some(policy) = answers.1 {
+                        return policy;
+                    }
+                    let question = Message::RestoreConflict(conflict.clone());
+                    if iced::futures::executor::block_on(messages.lock().unwrap().send(question)).is_err() {
+                        return super::backup::ConflictPolicy::Skip;
+                    }
+                    match answers.0.recv() {
+                        Ok((policy, for_all)) => {
+                            if for_all {
+                                answers.1 = Some(policy);
+                            }
+                            policy
+                        }
+                        Err(_) => super::backup::ConflictPolicy::Skip,
+                    }
+                };
+                let progress = |done, total| {
+                    // a full channel only drops this update, the next one catches up
+                    let _ = messages.lock().unwrap().try_send(Message::RestoreProgress(done, total));
+                };
+                let report = super::backup::restore_tree(&request, progress, ask);
+                let _ = finished.send(report.map_err(|e| e.to_string()));
+            });
+            let result = result
+                .await
+                .unwrap_or_else(|_| Err(String::from("Restore stopped unexpectedly")));
+            let _ = output.send(Message::RestoreFinished(result)).await;
+        })
+    }
+
+    //saves the settings, prepares the destination and rewrites the metadata so it matches
+    //the encryption setting. false if the settings couldn't be saved
+    fn apply_encryption_setting(&mut self) -> bool {
+        if let Err(e) = self.settings.save_to_file() {
+            eprintln!("Failed to save settings: {}", e);
+            return false;
+        }
+        // an unplugged drive doesn't keep the settings from being saved, backups just wait for it
+        self.destination_status =
+            self.destination_message(super::backup::check_settings_destination(&self.settings));
+        // the destination may have changed
+        self.encryption_initialized = super::crypto::is_initialized(super::storage::open(&self.settings).as_ref());
+        match super::backup::BackupMetadata::load_from_file() {
+            Ok(meta) => {
+                if let Err(e) = meta.save_to_file(self.settings.encryption_enabled) {
+                    eprintln!("Failed to rewrite metadata: {}", e);
+                }
+            }
+            Err(e) => eprintln!("Failed to load metadata: {}", e),
+        }
+        true
+    }
+
+    fn view_unlock(&self) -> Element<'_, Message> {
+        let content = column![
+            text("Backup Locked").size(32),
+            text("Your backups are encrypted. Enter the passphrase to unlock them.").size(14),
+            text_input("Passphrase", &self.passphrase_input)
+                .on_input(Message::PassphraseChanged)
+                .on_submit(Message::Unlock)
+                .secure(true),
+            row![
+                text("Remember key for the background daemon:").size(14),
+                toggler(String::new(), self.remember_key, Message::ToggleRememberKey),
+            ]
+            .spacing(10)
+            .align_items(Alignment::Center),
+            button("Unlock")
+                .on_press(Message::Unlock)
+                .style(iced::theme::Button::Primary),
+            text(&self.encryption_status).size(14),
+            button("Exit").on_press(Message::Exit),
+        ]
+        .align_items(Alignment::Center)
+        .spacing(16)
+        .padding(16)
+        .max_width(400);
+
+        container(content)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .center_x()
+            .center_y()
+            .into()
+    }
+
+    fn view_menu(&self) -> Element<'_, Message> {
         let upload_button = button("Upload").width(Length::Fill).on_press(Message::ToUpload);
         let update_now_button = button("Backup Now").width(Length::Fill).on_press(Message::UpdateNow);
         let edit_button = button("Manage Files").width(Length::Fill).on_press(Message::ToEdit);
+        let restore_button = button("Restore").width(Length::Fill).on_press(Message::ToRestore);
         let settings_button = button("Settings").width(Length::Fill).on_press(Message::ToSettings);
         let exit_button = button("Exit").width(Length::Fill).on_press(Message::Exit);
 
@@ -317,6 +1120,7 @@ impl Backup {
             upload_button,
             update_now_button,
             edit_button,
+            restore_button,
             settings_button,
             exit_button,
         ]
@@ -333,7 +1137,7 @@ impl Backup {
             .into()
     }
 
-    fn view_settings(&self) -> Element<Message> {
+    fn view_settings(&self) -> Element<'_, Message> {
         let title = text("Backup Settings").size(36);
 
         let auto_backup_toggle = row![
@@ -367,6 +1171,289 @@ impl Backup {
         .spacing(10)
         .align_items(Alignment::Center);
 
+        // with watching on the interval becomes a consistency sweep instead of the only check
+        let watch_toggle = row![
+            text("Watch Folders for Changes:").size(16),
+            toggler(
+                String::new(),
+                self.settings.change_detection == super::backup::ChangeDetection::Watch,
+                Message::ToggleWatchMode
+            ),
+        ]
+        .spacing(10)
+        .align_items(Alignment::Center);
+
+        // files are normally only reread when their size, times or inode change
+        let paranoid_row = row![
+            text("Full Hash Check Every (hours):").size(16),
+            toggler(
+                String::new(),
+                self.settings.paranoid_hash_enabled,
+                Message::ToggleParanoidHash
+            )
+            .width(Length::Shrink),
+            text_input("24", &self.paranoid_interval_input)
+                .on_input(Message::ParanoidIntervalChanged)
+                .width(Length::Fixed(100.0)),
+        ]
+        .spacing(10)
+        .align_items(Alignment::Center);
+
+        // the daemon rereads part of the stored objects on this schedule to catch bit rot
+        let verify_section = column![
+            row![
+                text("Verify Backup Every (hours):").size(16),
+                toggler(
+                    String::new(),
+                    self.settings.verify.enabled,
+                    Message::ToggleScheduledVerify
+                )
+                .width(Length::Shrink),
+                text_input("168", &self.verify_interval_input)
+                    .on_input(Message::VerifyIntervalChanged)
+                    .width(Length::Fixed(100.0)),
+                text("Sample (%):").size(16),
+                text_input("10", &self.verify_sample_input)
+                    .on_input(Message::VerifySampleChanged)
+                    .width(Length::Fixed(60.0)),
+                button("Verify Now").on_press_maybe((!self.verify_running).then_some(Message::VerifyNow)),
+            ]
+            .spacing(10)
+            .align_items(Alignment::Center),
+            text(&self.verify_report).size(12),
+        ]
+        .spacing(10);
+
+        let threads_row = row![
+            text("Hashing Threads:").size(16),
+            text_input("0", &self.worker_threads_input)
+                .on_input(Message::WorkerThreadsChanged)
+                .width(Length::Fixed(60.0)),
+            text("Copy Threads:").size(16),
+            text_input("4", &self.io_threads_input)
+                .on_input(Message::IoThreadsChanged)
+                .width(Length::Fixed(60.0)),
+        ]
+        .spacing(10)
+        .align_items(Alignment::Center);
+
+        // a folder can be picked or typed in, remote destinations are typed in as
+        // s3://bucket/prefix, sftp://user@host/path or dav(s)://host/path
+        let mut destination_section = column![
+            row![
+                text("Backup Destination:").size(16),
+                text_input("~/Backup, s3://bucket/prefix, sftp://user@host/path or davs://host/path", self.settings.destination.to_str().unwrap_or_default())
+                    .on_input(Message::DestinationChanged),
+            ]
+            .spacing(10)
+            .align_items(Alignment::Center),
+        ]
+        .spacing(10);
+        if super::s3::parse_destination(&self.settings.destination).is_some() {
+            let s3 = &self.settings.s3;
+            let values = [&s3.endpoint, &s3.region, &s3.access_key_id, &s3.secret_access_key];
+            for (index, (label, value)) in S3_LABELS.iter().zip(values).enumerate() {
+                destination_section = destination_section.push(
+                    row![
+                        text(*label).size(16),
+                        text_input("", value)
+                            .on_input(move |value| Message::S3InputChanged(index, value))
+                            .secure(index == 3),
+                    ]
+                    .spacing(10)
+                    .align_items(Alignment::Center),
+                );
+            }
+        }
+        if super::sftp::parse_destination(&self.settings.destination).is_some() {
+            let sftp = &self.settings.sftp;
+            let values = [&sftp.private_key, &sftp.known_hosts];
+            for (index, (label, value)) in SFTP_LABELS.iter().zip(values).enumerate() {
+                destination_section = destination_section.push(
+                    row![
+                        text(*label).size(16),
+                        text_input("", value.to_str().unwrap_or_default())
+                            .on_input(move |value| Message::SftpInputChanged(index, value)),
+                    ]
+                    .spacing(10)
+                    .align_items(Alignment::Center),
+                );
+            }
+        }
+        if super::webdav::parse_destination(&self.settings.destination).is_some() {
+            let webdav = &self.settings.webdav;
+            let values = [&webdav.username, &webdav.password];
+            for (index, (label, value)) in WEBDAV_LABELS.iter().zip(values).enumerate() {
+                destination_section = destination_section.push(
+                    row![
+                        text(*label).size(16),
+                        text_input("", value)
+                            .on_input(move |value| Message::WebdavInputChanged(index, value))
+                            .secure(index == 1),
+                    ]
+                    .spacing(10)
+                    .align_items(Alignment::Center),
+                );
+            }
+        }
+        let destination_section = destination_section
+            .push(
+                row![
+                    button("Choose Folder").on_press(Message::ChooseDestination),
+                    button("Check").on_press(Message::CheckDestination),
+                ]
+                .spacing(10),
+            )
+            .push(text(&self.destination_status).size(12));
+
+        // replicas get a full copy after every run, the state comes from the last run
+        let replica_states = self
+            .metadata
+            .as_ref()
+            .map(|meta| meta.lock().unwrap().replicas.clone())
+            .unwrap_or_default();
+        let replica_section = self.settings.replicas.iter().fold(
+            column![text("Replica Destinations (copied after every backup)").size(16)].spacing(8),
+            |col, path| {
+                let status = replica_states
+                    .iter()
+                    .find(|r| &r.path == path)
+                    .map(|r| r.describe())
+                    .unwrap_or_else(|| String::from("not synced yet"));
+                col.push(
+                    row![
+                        column![
+                            text(path.display().to_string()).size(14),
+                            text(status).size(12),
+                        ]
+                        .width(Length::Fill),
+                        button("Remove")
+                            .on_press(Message::RemoveReplica(path.clone()))
+                            .style(iced::theme::Button::Destructive),
+                    ]
+                    .spacing(10)
+                    .align_items(Alignment::Center),
+                )
+            },
+        )
+        .push(button("Add Replica").on_press(Message::AddReplica));
+
+        let compression_toggle = row![
+            text("Compress Backups (zstd):").size(16),
+            toggler(
+                String::new(),
+                self.settings.compression_enabled,
+                Message::ToggleCompression
+            ),
+        ]
+        .spacing(10)
+        .align_items(Alignment::Center);
+
+        let compression_level_input = row![
+            text("Compression Level (1-22):").size(16),
+            text_input("3", &self.compression_level_input)
+                .on_input(Message::CompressionLevelChanged)
+                .width(Length::Fixed(100.0)),
+        ]
+        .spacing(10)
+        .align_items(Alignment::Center);
+
+        // first time setup asks for a passphrase, afterwards it's just a toggle
+        let encryption_section: Element<Message> = if self.encryption_initialized {
+            row![
+                text("Encrypt Backups:").size(16),
+                toggler(
+                    String::new(),
+                    self.settings.encryption_enabled,
+                    Message::ToggleEncryption
+                ),
+            ]
+            .spacing(10)
+            .align_items(Alignment::Center)
+            .into()
+        } else {
+            row![
+                text("Encryption Passphrase:").size(16),
+                text_input("Passphrase", &self.passphrase_input)
+                    .on_input(Message::PassphraseChanged)
+                    .secure(true)
+                    .width(Length::Fixed(200.0)),
+                button("Enable Encryption").on_press(Message::EnableEncryption),
+            ]
+            .spacing(10)
+            .align_items(Alignment::Center)
+            .into()
+        };
+
+        let retention_rows = RETENTION_LABELS.iter().enumerate().fold(
+            column![text("Retention (0 = off, all 0 keeps everything)").size(16)].spacing(8),
+            |col, (index, label)| {
+                col.push(
+                    row![
+                        text(*label).size(14).width(Length::Fixed(200.0)),
+                        text_input("0", &self.retention_inputs[index])
+                            .on_input(move |value| Message::RetentionInputChanged(index, value))
+                            .width(Length::Fixed(100.0)),
+                    ]
+                    .spacing(10)
+                    .align_items(Alignment::Center),
+                )
+            },
+        );
+
+        let retention_section = column![
+            retention_rows,
+            row![
+                button("Preview Pruning").on_press(Message::PreviewPrune),
+                button("Prune Now")
+                    .on_press(Message::PruneNow)
+                    .style(iced::theme::Button::Destructive),
+            ]
+            .spacing(10),
+            text(&self.prune_report).size(12),
+        ]
+        .spacing(10);
+
+        // targets are the global rules plus one entry per backup folder
+        let rule_targets: Vec<RuleTarget> = std::iter::once(RuleTarget::Global)
+            .chain(self.backup_roots().into_iter().map(|r| RuleTarget::Root(r.path)))
+            .collect();
+
+        let rules_section = column![
+            text("Include / Exclude Rules (gitignore syntax, one per line)").size(16),
+            row![
+                text("Rules for:").size(14),
+                pick_list(rule_targets, Some(self.rules_target.clone()), Message::SelectRuleTarget),
+            ]
+            .spacing(10)
+            .align_items(Alignment::Center),
+            text("Exclude:").size(14),
+            text_editor(&self.exclude_editor)
+                .on_action(Message::ExcludeRulesEdited)
+                .height(Length::Fixed(120.0)),
+            text("Include only (leave empty to include everything):").size(14),
+            text_editor(&self.include_editor)
+                .on_action(Message::IncludeRulesEdited)
+                .height(Length::Fixed(80.0)),
+            row![
+                text("Honor .gitignore / .backupignore:").size(14),
+                toggler(
+                    String::new(),
+                    self.settings.honor_ignore_files,
+                    Message::ToggleIgnoreFiles
+                ),
+            ]
+            .spacing(10)
+            .align_items(Alignment::Center),
+            row![
+                button("Preview Exclusions").on_press(Message::PreviewRules),
+                button("Save Rules").on_press(Message::SaveRules),
+            ]
+            .spacing(10),
+            text(&self.rules_preview).size(12),
+        ]
+        .spacing(10);
+
         let save_button = button("Save Settings")
             .on_press(Message::SaveSettings)
             .style(iced::theme::Button::Primary);
@@ -398,9 +1485,22 @@ impl Backup {
 
         let content = column![
             title,
+            destination_section,
+            replica_section,
             auto_backup_toggle,
             dark_mode_toggle,
             interval_input,
+            watch_toggle,
+            paranoid_row,
+            verify_section,
+            threads_row,
+            text("Threads: 0 = one per CPU core, use 1 for both on spinning disks").size(12),
+            compression_toggle,
+            compression_level_input,
+            encryption_section,
+            text(&self.encryption_status).size(12),
+            retention_section,
+            rules_section,
             save_button,
             container(text("")).height(Length::Fixed(20.0)),
             daemon_section,
@@ -414,7 +1514,7 @@ impl Backup {
         .max_width(600)
         .align_items(Alignment::Start);
 
-        container(content)
+        container(scrollable(content))
             .width(Length::Fill)
             .height(Length::Fill)
             .center_x()
@@ -422,7 +1522,7 @@ impl Backup {
             .into()
     }
 
-    fn view_edit(&self) -> Element<Message> {
+    fn view_edit(&self) -> Element<'_, Message> {
         let title = text("Manage Backup Files").size(36);
 
         // sort files alphabeticaly for easier browsing
@@ -451,12 +1551,15 @@ impl Backup {
                     .map(|p| p == &file.original_path)
                     .unwrap_or(false);
 
-                let file_name = file
+                let mut file_name = file
                     .original_path
                     .file_name()
                     .and_then(|n| n.to_str())
                     .unwrap_or("Unknown")
                     .to_string();
+                if file.deleted_in.is_some() {
+                    file_name.push_str(" (deleted)");
+                }
 
                 let file_button = {
                     let path_clone = file.original_path.clone();
@@ -469,15 +1572,48 @@ impl Backup {
 
                 //show file details when file is selected
                 if is_selected {
+                    // newest version first
+                    let versions = file.versions.iter().rev().fold(column![], |col, version| {
+                        let taken = format_time(version.modified);
+                        col.push(
+                            row![
+                                text(format!(
+                                    "{}  (modified {}, {} bytes)",
+                                    version.snapshot_id, taken, version.size
+                                ))
+                                .size(12)
+                                .width(Length::Fill),
+                                button(text("Restore Version").size(12))
+                                    .on_press(Message::RestoreVersion(version.snapshot_id.clone())),
+                            ]
+                            .spacing(10)
+                            .align_items(Alignment::Center),
+                        )
+                    })
+                    .spacing(4);
+
+                    // deleted files get restored back to where they were
+                    let restore_button = match &file.deleted_in {
+                        Some(_) => button("Restore Deleted").on_press(Message::RestoreDeleted),
+                        None => button("Restore").on_press(Message::Restore),
+                    };
+                    let status = match &file.deleted_in {
+                        Some(snapshot_id) => format!("Deleted (noticed in snapshot {})", snapshot_id),
+                        None => String::from("Present"),
+                    };
+
                     let details = column![
                         text(format!("Path: {}", file.original_path.display())).size(12),
                         text(format!("Type: {}", file.file_type)).size(12),
+                        text(format!("Status: {}", status)).size(12),
+                        text(format!("Versions: {}", file.versions.len())).size(12),
+                        versions,
                         row![
                             button("Delete File")
                                 .on_press(Message::DeleteFile)
                                 .style(iced::theme::Button::Destructive),
-                            button("Restore")
-                                .on_press(Message::Restore),
+                            restore_button,
+                            button("Restore Folder...").on_press(Message::RestoreFolder),
                             button("Open File Directory")
                                 .on_press(Message::OpenFolder)
                         ]
@@ -498,10 +1634,31 @@ impl Backup {
         let back_button = button("Back to Menu").on_press(Message::ToMenu);
         let refresh_button = button("Refresh").on_press(Message::RefreshFiles);
 
+        // brings back the selected file's folder, or everything, as it was at the picked time
+        let restore_as_of = row![
+            text("Restore as of:").size(14),
+            pick_list(self.restore_dates.clone(), self.restore_date, Message::RestoreDateSelected),
+            pick_list(
+                (0..24).map(|hour| format!("{:02}", hour)).collect::<Vec<_>>(),
+                Some(format!("{:02}", self.restore_hour)),
+                |hour| Message::RestoreHourSelected(hour.parse().unwrap_or(0))
+            ),
+            text(":").size(14),
+            pick_list(
+                (0..60).step_by(5).map(|minute| format!("{:02}", minute)).collect::<Vec<_>>(),
+                Some(format!("{:02}", self.restore_minute)),
+                |minute| Message::RestoreMinuteSelected(minute.parse().unwrap_or(0))
+            ),
+            button("Restore...").on_press(Message::RestoreAsOf),
+        ]
+        .spacing(8)
+        .align_items(Alignment::Center);
+
         let content = column![
             title,
             row![back_button, container(text("")).width(Length::Fill), refresh_button]
                 .width(Length::Fill),
+            restore_as_of,
             scrollable(file_list).height(Length::Fill),
         ]
         .spacing(20)
@@ -516,7 +1673,137 @@ impl Backup {
             .into()
     }
 
-    fn view_stub(&self, title: &str) -> Element<Message> {
+    fn view_restore(&self) -> Element<'_, Message> {
+        let title = text("Restore Files").size(36);
+
+        let mut options = column![
+            row![
+                text("Restore:").size(16),
+                pick_list(
+                    self.restore_points.clone(),
+                    Some(self.restore_point.clone()),
+                    Message::RestorePointSelected
+                ),
+            ]
+            .spacing(10)
+            .align_items(Alignment::Center),
+            row![
+                text("Folder:").size(16),
+                text_input("Everything, or a backed up folder", &self.restore_folder_input)
+                    .on_input(Message::RestoreFolderChanged),
+            ]
+            .spacing(10)
+            .align_items(Alignment::Center),
+            toggler(
+                String::from("Put files back where they were"),
+                self.restore_to_original,
+                Message::ToggleRestoreToOriginal
+            ),
+        ]
+        .spacing(10);
+        if !self.restore_to_original {
+            let target = match &self.restore_target {
+                Some(path) => path.display().to_string(),
+                None => String::from("no folder chosen"),
+            };
+            options = options.push(
+                row![
+                    text(format!("Restore into: {}", target)).size(16).width(Length::Fill),
+                    button("Choose Folder").on_press(Message::ChooseRestoreTarget),
+                ]
+                .spacing(10)
+                .align_items(Alignment::Center),
+            );
+        }
+        let options = options.push(
+            row![
+                text("If a file exists:").size(16),
+                pick_list(
+                    super::backup::ConflictPolicy::ALL,
+                    Some(self.conflict_policy),
+                    Message::ConflictPolicySelected
+                ),
+            ]
+            .spacing(10)
+            .align_items(Alignment::Center),
+        );
+
+        let restore_button = button("Restore")
+            .on_press_maybe(self.restore_progress.is_none().then_some(Message::StartRestore))
+            .style(iced::theme::Button::Primary);
+
+        let mut results = column![].spacing(10);
+        if let Some((done, total)) = self.restore_progress {
+            results = results
+                .push(progress_bar(0.0..=total.max(1) as f32, done as f32))
+                .push(text(format!("Restoring {} of {} file(s)...", done, total)).size(14));
+        }
+        if let Some(conflict) = &self.conflict_question {
+            let answers = [
+                super::backup::ConflictPolicy::Skip,
+                super::backup::ConflictPolicy::Overwrite,
+                super::backup::ConflictPolicy::KeepBoth,
+                super::backup::ConflictPolicy::NewerWins,
+            ]
+            .into_iter()
+            .fold(row![].spacing(10), |row, policy| {
+                row.push(button(text(policy.to_string()).size(12)).on_press(Message::AnswerConflict(policy)))
+            });
+            results = results.push(
+                container(
+                    column![
+                        text(format!("{} already exists", conflict.destination.display())).size(16),
+                        text(format!(
+                            "The version from snapshot {} was changed {}, the file there {}",
+                            conflict.snapshot_id,
+                            format_time(conflict.backup_modified),
+                            format_time(conflict.existing_modified)
+                        ))
+                        .size(12),
+                        answers,
+                        toggler(
+                            String::from("Do the same for all remaining conflicts"),
+                            self.conflict_for_all,
+                            Message::ToggleConflictForAll
+                        ),
+                    ]
+                    .spacing(10),
+                )
+                .padding(10),
+            );
+        }
+        results = results.push(text(&self.restore_status).size(14));
+        if let Some(report) = &self.restore_report {
+            let mut lines: Vec<String> = report
+                .files
+                .iter()
+                .take(RESTORE_REPORT_LIMIT)
+                .map(|file| file.describe())
+                .collect();
+            if report.files.len() > RESTORE_REPORT_LIMIT {
+                lines.push(format!("... and {} more", report.files.len() - RESTORE_REPORT_LIMIT));
+            }
+            results = results.push(scrollable(text(lines.join("\n")).size(12)).height(Length::Fill));
+        }
+
+        let back_button = button("Back to Menu").on_press_maybe(
+            self.restore_progress.is_none().then_some(Message::ToMenu),
+        );
+
+        let content = column![title, options, restore_button, results, back_button]
+            .spacing(20)
+            .padding(20)
+            .max_width(700);
+
+        container(content)
+            .width(Length::Fill)
+            .height(Length::Fill)
+            .center_x()
+            .center_y()
+            .into()
+    }
+
+    fn view_stub(&self, title: &str) -> Element<'_, Message> {
         container(
             column![
                 text(format!("{} Page", title)).size(36),
@@ -1,7 +1,37 @@
 mod backup;
 mod iced;
 mod daemon;
+mod chunker;
+mod crypto;
+mod retention;
+mod rules;
+mod watcher;
+mod cli;
+mod paths;
+mod replication;
+mod storage;
+mod s3;
+mod sftp;
+mod webdav;
+mod verify;
 
-fn main(){
-    iced::ui();
+use std::process::ExitCode;
+
+// the gui is the default, any arguments go to the command line interface
+fn main() -> ExitCode {
+    if std::env::args_os().len() > 1 {
+        return cli::run();
+    }
+    run_gui()
+}
+
+fn run_gui() -> ExitCode {
+    paths::migrate_from_working_dir();
+    match iced::ui() {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("Failed to start the GUI: {}", e);
+            ExitCode::FAILURE
+        }
+    }
 }
\ No newline at end of file
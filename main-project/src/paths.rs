@@ -0,0 +1,267 @@
+// where the app keeps its own files, following the XDG base directory spec:
+// settings and the key file in $XDG_CONFIG_HOME/fass-backup, the metadata and daemon logs
+// in $XDG_STATE_HOME/fass-backup and the pid file in $XDG_RUNTIME_DIR/fass-backup.
+// with --config-dir all of them live in that one folder instead
+
+use dirs_next::home_dir;
+use nix::errno::Errno;
+use nix::fcntl::{Flock, FlockArg};
+use nix::sys::signal::kill;
+use nix::unistd::Pid;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+const APP_DIR: &str = "fass-backup";
+
+pub const SETTINGS_FILE: &str = "backup_settings.json";
+pub const METADATA_FILE: &str = "backup_metadata.json";
+pub const KEY_FILE: &str = "backup.key";
+pub const PID_FILE: &str = "fass_backup_daemon.pid";
+pub const LOG_FILE: &str = "fass_backup_daemon.log";
+pub const ERR_FILE: &str = "fass_backup_daemon.err";
+pub const LOCK_FILE: &str = "fass_backup.lock";
+// written once the files of older versions have been moved, so that happens only once
+const MIGRATED_MARKER: &str = ".migrated";
+
+static DIR_OVERRIDE: OnceLock<PathBuf> = OnceLock::new();
+
+/// puts every file of the app into one folder, has to be called before anything is loaded
+pub fn set_config_dir(dir: PathBuf) {
+    let dir = std::path::absolute(&dir).unwrap_or(dir);
+    let _ = DIR_OVERRIDE.set(dir);
+}
+
+pub fn config_dir_override() -> Option<&'static Path> {
+    DIR_OVERRIDE.get().map(PathBuf::as_path)
+}
+
+//$var/fass-backup if the variable holds an absolute path, else the fallback below home
+fn xdg_dir(var: &str, fallback: &str) -> PathBuf {
+    if let Some(dir) = DIR_OVERRIDE.get() {
+        return dir.clone();
+    }
+    let base = std::env::var_os(var)
+        .map(PathBuf::from)
+        .filter(|p| p.is_absolute())
+        .unwrap_or_else(|| home_dir().unwrap_or_default().join(fallback));
+    base.join(APP_DIR)
+}
+
+pub fn config_dir() -> PathBuf {
+    xdg_dir("XDG_CONFIG_HOME", ".config")
+}
+
+pub fn state_dir() -> PathBuf {
+    xdg_dir("XDG_STATE_HOME", ".local/state")
+}
+
+// there is no fallback location in the spec, the state folder is the closest thing
+pub fn runtime_dir() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR").map(PathBuf::from) {
+        Some(dir) if DIR_OVERRIDE.get().is_none() && dir.is_absolute() => dir.join(APP_DIR),
+        _ => state_dir(),
+    }
+}
+
+pub fn settings_file() -> PathBuf {
+    config_dir().join(SETTINGS_FILE)
+}
+
+pub fn metadata_file() -> PathBuf {
+    state_dir().join(METADATA_FILE)
+}
+
+pub fn key_file() -> PathBuf {
+    config_dir().join(KEY_FILE)
+}
+
+pub fn pid_file() -> PathBuf {
+    runtime_dir().join(PID_FILE)
+}
+
+pub fn log_file() -> PathBuf {
+    state_dir().join(LOG_FILE)
+}
+
+pub fn err_file() -> PathBuf {
+    state_dir().join(ERR_FILE)
+}
+
+pub fn lock_file() -> PathBuf {
+    state_dir().join(LOCK_FILE)
+}
+
+/// held while something changes the objects in the destination or the metadata, released
+/// when dropped
+pub struct RepositoryLock {
+    _flock: Flock<fs::File>,
+}
+
+/// waits until no other process or thread of the app is working on the backup. backups,
+/// pruning, verify and replication all take it, so e.g. a prune can't delete an object that
+/// a backup running at the same time stored but hasn't recorded in the metadata yet.
+/// it isn't reentrant, only the entry points of those operations take it
+pub fn lock_repository() -> std::io::Result<RepositoryLock> {
+    let path = lock_file();
+    ensure_parent(&path)?;
+    let file = fs::OpenOptions::new().create(true).truncate(false).write(true).open(&path)?;
+    let file = match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
+        Ok(flock) => return Ok(RepositoryLock { _flock: flock }),
+        Err((file, Errno::EWOULDBLOCK)) => file,
+        Err((_, e)) => return Err(e.into()),
+    };
+    println!("Waiting for another backup, prune or verify to finish...");
+    Flock::lock(file, FlockArg::LockExclusive)
+        .map(|flock| RepositoryLock { _flock: flock })
+        .map_err(|(_, e)| e.into())
+}
+
+//creates the folder a file goes into, private to the user since it may hold the key
+pub fn ensure_parent(file: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::DirBuilderExt;
+
+    match file.parent() {
+        Some(dir) if !dir.exists() => fs::DirBuilder::new().recursive(true).mode(0o700).create(dir),
+        _ => Ok(()),
+    }
+}
+
+/// true for files the app writes itself
+pub fn is_app_file(path: &Path) -> bool {
+    [config_dir(), state_dir(), runtime_dir()]
+        .iter()
+        .any(|dir| path.starts_with(dir))
+}
+
+//rename, falling back to copy and delete when the folders are on different file systems
+fn move_file(from: &Path, to: &Path) -> std::io::Result<()> {
+    if fs::rename(from, to).is_err() {
+        fs::copy(from, to)?;
+        fs::remove_file(from)?;
+    }
+    Ok(())
+}
+
+// whether the pid file names a process that is still running
+fn is_live_pid_file(path: &Path) -> bool {
+    let Some(pid) = fs::read_to_string(path).ok().and_then(|text| text.trim().parse::<i32>().ok()) else {
+        return false;
+    };
+    // EPERM means it exists but belongs to someone else
+    matches!(kill(Pid::from_raw(pid), None), Ok(()) | Err(Errno::EPERM))
+}
+
+/// older versions kept everything in the folder they were started from. moves those files
+/// to their new places, files that already exist there are left alone. this only happens
+/// once, later starts from that folder leave whatever is there alone
+pub fn migrate_from_working_dir() {
+    if let Ok(cwd) = std::env::current_dir() {
+        migrate_from(&cwd);
+    }
+}
+
+fn migrate_from(old_dir: &Path) {
+    let marker = state_dir().join(MIGRATED_MARKER);
+    if marker.exists() {
+        return;
+    }
+    let mut moves = vec![
+        (SETTINGS_FILE, settings_file()),
+        (METADATA_FILE, metadata_file()),
+        (KEY_FILE, key_file()),
+    ];
+    // a daemon of an older version still running there keeps its files until it's gone,
+    // the move is tried again on a later start
+    let daemon_running = is_live_pid_file(&old_dir.join(PID_FILE));
+    if !daemon_running {
+        moves.extend([(PID_FILE, pid_file()), (LOG_FILE, log_file()), (ERR_FILE, err_file())]);
+    }
+
+    for (name, destination) in moves {
+        let source = old_dir.join(name);
+        if !source.is_file() || destination.exists() || source == destination {
+            continue;
+        }
+        let result = ensure_parent(&destination).and_then(|_| move_file(&source, &destination));
+        match result {
+            Ok(_) => println!("Moved {} to {}", source.display(), destination.display()),
+            Err(e) => eprintln!("Failed to move {} to {}: {}", source.display(), destination.display(), e),
+        }
+    }
+    if !daemon_running && let Err(e) = ensure_parent(&marker).and_then(|_| fs::write(&marker, "")) {
+        eprintln!("Failed to write {}: {}", marker.display(), e);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::testing::sandbox;
+    use std::sync::mpsc;
+    use std::time::Duration;
+
+    #[test]
+    fn a_second_holder_waits_for_the_repository_lock() {
+        let _sandbox = sandbox();
+        let held = lock_repository().unwrap();
+        let (tx, rx) = mpsc::channel();
+        let waiting = std::thread::spawn(move || {
+            let second = lock_repository().unwrap();
+            tx.send(()).unwrap();
+            drop(second);
+        });
+        assert!(rx.recv_timeout(Duration::from_millis(300)).is_err());
+
+        drop(held);
+        rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        waiting.join().unwrap();
+        // free again once both are done
+        drop(lock_repository().unwrap());
+    }
+
+    #[test]
+    fn files_of_older_versions_are_moved_only_once() {
+        let sandbox = sandbox();
+        let old_dir = sandbox.dir.join("old");
+        fs::create_dir_all(&old_dir).unwrap();
+        fs::write(old_dir.join(METADATA_FILE), "old metadata").unwrap();
+        fs::write(old_dir.join(LOG_FILE), "old log").unwrap();
+
+        migrate_from(&old_dir);
+        assert_eq!(fs::read_to_string(metadata_file()).unwrap(), "old metadata");
+        assert_eq!(fs::read_to_string(log_file()).unwrap(), "old log");
+        assert!(!old_dir.join(METADATA_FILE).exists());
+
+        // an older version started from there again, what it wrote stays where it is
+        fs::write(old_dir.join(METADATA_FILE), "newer old metadata").unwrap();
+        fs::remove_file(metadata_file()).unwrap();
+        migrate_from(&old_dir);
+        assert!(!metadata_file().exists());
+        assert_eq!(fs::read_to_string(old_dir.join(METADATA_FILE)).unwrap(), "newer old metadata");
+    }
+
+    #[test]
+    fn a_running_daemon_keeps_its_files() {
+        let sandbox = sandbox();
+        let old_dir = sandbox.dir.join("old");
+        fs::create_dir_all(&old_dir).unwrap();
+        fs::write(old_dir.join(METADATA_FILE), "old metadata").unwrap();
+        fs::write(old_dir.join(LOG_FILE), "old log").unwrap();
+        // this test stands in for the daemon
+        fs::write(old_dir.join(PID_FILE), std::process::id().to_string()).unwrap();
+
+        migrate_from(&old_dir);
+        assert_eq!(fs::read_to_string(metadata_file()).unwrap(), "old metadata");
+        assert!(old_dir.join(PID_FILE).exists() && old_dir.join(LOG_FILE).exists());
+        assert!(!pid_file().exists() && !log_file().exists());
+
+        // once it's gone they follow
+        let mut finished = std::process::Command::new("true").spawn().unwrap();
+        finished.wait().unwrap();
+        fs::write(old_dir.join(PID_FILE), finished.id().to_string()).unwrap();
+        migrate_from(&old_dir);
+        assert_eq!(fs::read_to_string(log_file()).unwrap(), "old log");
+        assert!(pid_file().exists() && !old_dir.join(PID_FILE).exists());
+    }
+}
@@ -0,0 +1,259 @@
+// copies of the backup on destinations besides the main one, e.g. a second disk and a usb
+// drive. a replica gets every object the metadata references plus its own copy of the
+// metadata, so it can be restored from on its own. replicas that were offline during
+// earlier runs are caught up the next time they're available
+
+use serde::{Serialize, Deserialize};
+use chrono::Local;
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use crate::backup::{self, BackupMetadata, BackupSettings};
+use crate::crypto;
+use crate::storage::{self, StorageBackend};
+
+/// what the metadata remembers about one replica
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ReplicaState {
+    pub path: PathBuf,
+    // unix time of the last complete sync, 0 if there never was one
+    #[serde(default)]
+    pub last_sync: i64,
+    // why the last attempt failed, None if it worked
+    #[serde(default)]
+    pub last_error: Option<String>,
+}
+
+impl ReplicaState {
+    pub fn describe(&self) -> String {
+        let synced = match chrono::DateTime::from_timestamp(self.last_sync, 0) {
+            Some(time) if self.last_sync > 0 => {
+                time.with_timezone(&Local).format("%Y-%m-%d %H:%M").to_string()
+            }
+            _ => String::from("never"),
+        };
+        match &self.last_error {
+            Some(e) => format!("last synced {}, failed: {}", synced, e),
+            None => format!("last synced {}", synced),
+        }
+    }
+}
+
+/// what a sync copied to and removed from one replica
+#[derive(Debug, Clone, Default)]
+pub struct SyncReport {
+    pub copied: usize,
+    pub removed: usize,
+}
+
+/// copies the objects with the given keys that target doesn't have yet from source, on
+/// threads copy threads. returns how many were copied
+pub fn copy_objects(
+    source: &dyn StorageBackend,
+    target: &dyn StorageBackend,
+    keys: &HashSet<String>,
+    threads: usize,
+) -> std::io::Result<usize> {
+    let present: HashSet<String> = target.list("objects/")?.into_iter().collect();
+    let to_copy: Vec<&String> = keys.iter().filter(|key| !present.contains(*key)).collect();
+
+    let failed: Vec<String> = backup::worker_pool(threads).install(|| {
+        to_copy
+            .par_iter()
+            .filter_map(|key| {
+                source
+                    .get(key)
+                    .and_then(|data| target.put(key, &data))
+                    .err()
+                    .map(|e| format!("{}: {}", key, e))
+            })
+            .collect()
+    });
+    if let Some(first) = failed.first() {
+        return Err(std::io::Error::other(format!(
+            "{} object(s) could not be copied to {}, e.g. {}",
+            failed.len(),
+            target.name(),
+            first
+        )));
+    }
+    Ok(to_copy.len())
+}
+
+/// makes the replica hold exactly the objects the metadata references and writes its own
+/// copy of the metadata next to them
+pub fn sync_replica(
+    metadata: &BackupMetadata,
+    source: &dyn StorageBackend,
+    replica: &Path,
+    settings: &BackupSettings,
+    used_before: bool,
+) -> std::io::Result<SyncReport> {
+    let target = storage::open_with(replica, settings);
+    target.check(used_before)?;
+    crypto::copy_key_config(source, target.as_ref())?;
+
+    let needed = metadata.referenced_objects()?;
+    let mut report = SyncReport {
+        copied: copy_objects(source, target.as_ref(), &needed, settings.io_threads)?,
+        removed: 0,
+    };
+
+    // objects pruned from the main destination go away here too
+    for key in target.list("objects/")?.iter().filter(|key| !needed.contains(*key)) {
+        target.delete(key)?;
+        report.removed += 1;
+    }
+
+    metadata.save_copy(target.as_ref(), replica, settings.encryption_enabled)?;
+    Ok(report)
+}
+
+/// syncs every replica from the settings that can be reached from source, the main
+/// destination, and records the outcome in the metadata. replicas that fail keep their old
+/// state and are caught up by a later run. returns true if the metadata changed
+pub fn replicate(metadata: &mut BackupMetadata, source: &dyn StorageBackend, settings: &BackupSettings) -> bool {
+    let before = metadata.replicas.len();
+    metadata.replicas.retain(|r| settings.replicas.contains(&r.path));
+    let mut changed = metadata.replicas.len() != before;
+
+    for replica in &settings.replicas {
+        let used_before = metadata
+            .replicas
+            .iter()
+            .any(|r| &r.path == replica && r.last_sync > 0);
+        let result = sync_replica(metadata, source, replica, settings, used_before);
+
+        let state = match metadata.replicas.iter().position(|r| &r.path == replica) {
+            Some(index) => &mut metadata.replicas[index],
+            None => {
+                metadata.replicas.push(ReplicaState { path: replica.clone(), ..Default::default() });
+                metadata.replicas.last_mut().unwrap()
+            }
+        };
+        match result {
+            Ok(report) => {
+                println!(
+                    "Replicated to {}: {} object(s) copied, {} removed",
+                    replica.display(),
+                    report.copied,
+                    report.removed
+                );
+                state.last_sync = Local::now().timestamp();
+                state.last_error = None;
+                for snapshot in metadata.snapshots.iter_mut().filter(|s| !s.destinations.contains(replica)) {
+                    snapshot.destinations.push(replica.clone());
+                }
+            }
+            Err(e) => {
+                println!("Skipped replica {}: {}", replica.display(), e);
+                state.last_error = Some(e.to_string());
+            }
+        }
+        changed = true;
+    }
+    changed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::{self, BackupSettings};
+    use crate::paths;
+    use std::fs;
+    use std::sync::{Arc, Mutex};
+
+    //the copy of the metadata a destination holds
+    fn stored_metadata(target: &dyn StorageBackend) -> BackupMetadata {
+        serde_json::from_slice(&target.get(paths::METADATA_FILE).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn offline_replicas_are_caught_up_by_a_later_run() {
+        let sandbox = storage::testing::sandbox();
+        let usb = sandbox.dir.join("usb");
+        let settings = BackupSettings { replicas: vec![usb.clone()], ..BackupSettings::load_from_file().unwrap() };
+        settings.save_to_file().unwrap();
+        let folder = sandbox.dir.join("docs");
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(folder.join("a.txt"), "first").unwrap();
+
+        // the usb drive isn't plugged in
+        backup::backup(&folder).unwrap();
+        let metadata = BackupMetadata::load_from_file().unwrap();
+        assert_eq!(metadata.replicas.len(), 1);
+        assert_eq!(metadata.replicas[0].last_sync, 0);
+        assert!(metadata.replicas[0].last_error.as_deref().unwrap().contains("not available"));
+        assert_eq!(metadata.snapshots[0].destinations, vec![settings.destination.clone()]);
+        // the main destination got its copy of the metadata all the same
+        let main_copy = stored_metadata(sandbox.backend.as_ref());
+        assert!(main_copy.files.contains_key(&folder.join("a.txt")));
+        assert!(main_copy.replicas.is_empty());
+
+        // nothing changed since, the next run only catches up the replica
+        fs::create_dir_all(&usb).unwrap();
+        backup::backup_now(Arc::new(Mutex::new(metadata))).unwrap();
+        let metadata = BackupMetadata::load_from_file().unwrap();
+        assert_eq!(metadata.snapshots.len(), 1);
+        assert!(metadata.replicas[0].last_sync > 0);
+        assert_eq!(metadata.replicas[0].last_error, None);
+        assert_eq!(metadata.snapshots[0].destinations, vec![settings.destination.clone(), usb.clone()]);
+
+        let replica = storage::open_with(&usb, &settings);
+        let mut copied = replica.list("objects/").unwrap();
+        copied.sort();
+        let mut expected: Vec<String> = metadata.referenced_objects().unwrap().into_iter().collect();
+        expected.sort();
+        assert_eq!(copied, expected);
+        let replica_copy = stored_metadata(replica.as_ref());
+        assert_eq!(replica_copy.destination, usb);
+        assert!(replica_copy.replicas.is_empty());
+        assert_eq!(replica_copy.snapshots[0].destinations, metadata.snapshots[0].destinations);
+    }
+
+    #[test]
+    fn watched_changes_reach_the_replicas_right_away() {
+        let sandbox = storage::testing::sandbox();
+        let usb = sandbox.dir.join("usb");
+        fs::create_dir_all(&usb).unwrap();
+        let settings = BackupSettings { replicas: vec![usb.clone()], ..BackupSettings::load_from_file().unwrap() };
+        settings.save_to_file().unwrap();
+        let folder = sandbox.dir.join("docs");
+        fs::create_dir_all(&folder).unwrap();
+        fs::write(folder.join("a.txt"), "first").unwrap();
+        backup::backup(&folder).unwrap();
+
+        fs::write(folder.join("b.txt"), "second").unwrap();
+        assert_eq!(backup::backup_changed(&[folder.join("b.txt")]).unwrap(), 1);
+
+        let metadata = BackupMetadata::load_from_file().unwrap();
+        assert_eq!(metadata.snapshots.len(), 2);
+        assert_eq!(metadata.snapshots[1].destinations, vec![settings.destination.clone(), usb.clone()]);
+        let replica = storage::open_with(&usb, &settings);
+        let mut copied = replica.list("objects/").unwrap();
+        copied.sort();
+        let mut expected: Vec<String> = metadata.referenced_objects().unwrap().into_iter().collect();
+        expected.sort();
+        assert_eq!(copied, expected);
+        assert!(stored_metadata(replica.as_ref()).files.contains_key(&folder.join("b.txt")));
+    }
+
+    #[test]
+    fn objects_pruned_from_the_main_destination_leave_the_replica() {
+        let sandbox = storage::testing::sandbox();
+        let usb = sandbox.dir.join("usb");
+        fs::create_dir_all(&usb).unwrap();
+        let settings = BackupSettings::load_from_file().unwrap();
+        let metadata = BackupMetadata::default();
+        sandbox.backend.put("objects/ab/abcd", b"gone from the metadata").unwrap();
+        storage::open_with(&usb, &settings).put("objects/ab/abcd", b"gone from the metadata").unwrap();
+
+        let report = sync_replica(&metadata, sandbox.backend.as_ref(), &usb, &settings, false).unwrap();
+        assert_eq!((report.copied, report.removed), (0, 1));
+        assert!(storage::open_with(&usb, &settings).list("objects/").unwrap().is_empty());
+        // used before, so the replica has to still hold its marker
+        fs::remove_dir_all(&usb).unwrap();
+        fs::create_dir_all(&usb).unwrap();
+        assert!(sync_replica(&metadata, sandbox.backend.as_ref(), &usb, &settings, true).is_err());
+    }
+}
@@ -0,0 +1,421 @@
+use serde::{Serialize, Deserialize};
+use chrono::{Local, TimeZone};
+use std::collections::{HashMap, HashSet};
+use std::path::PathBuf;
+use crate::backup::{BackupMetadata, BackupSettings, Snapshot};
+use crate::storage::{self, StorageBackend};
+
+/// grandfather-father-son style retention. 0 means "don't keep any by this rule",
+/// and with every rule at 0 nothing is ever pruned
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RetentionPolicy {
+    pub keep_last: usize,
+    pub keep_hourly: usize,
+    pub keep_daily: usize,
+    pub keep_weekly: usize,
+    pub keep_monthly: usize,
+}
+
+impl RetentionPolicy {
+    pub fn is_enabled(&self) -> bool {
+        self.keep_last + self.keep_hourly + self.keep_daily + self.keep_weekly + self.keep_monthly > 0
+    }
+}
+
+/// what a prune run removed, or would remove when it's a dry run
+#[derive(Debug, Clone, Default)]
+pub struct PruneReport {
+    pub dry_run: bool,
+    pub removed_snapshots: Vec<String>,
+    pub removed_versions: Vec<(PathBuf, String)>,
+    pub removed_objects: Vec<String>,
+    pub freed_bytes: u64,
+}
+
+impl PruneReport {
+    pub fn summary(&self) -> String {
+        format!(
+            "{}{} snapshot(s), {} version(s), {} object(s), {:.1} MiB",
+            if self.dry_run { "Would remove " } else { "Removed " },
+            self.removed_snapshots.len(),
+            self.removed_versions.len(),
+            self.removed_objects.len(),
+            self.freed_bytes as f64 / (1024.0 * 1024.0)
+        )
+    }
+}
+
+//picks the snapshot ids the policy keeps. snapshots are walked newest first and each
+//rule keeps the newest snapshot of every hour/day/week/month until it has enough
+fn snapshots_to_keep(metadata: &BackupMetadata, policy: &RetentionPolicy) -> HashSet<String> {
+    // newest first, position breaks ties between runs in the same second
+    let mut snapshots: Vec<_> = metadata.snapshots.iter().enumerate().collect();
+    snapshots.sort_by_key(|(i, s)| std::cmp::Reverse((s.timestamp, *i)));
+    let snapshots: Vec<_> = snapshots.into_iter().map(|(_, s)| s).collect();
+
+    let mut keep: HashSet<String> = snapshots
+        .iter()
+        .take(policy.keep_last)
+        .map(|s| s.id.clone())
+        .collect();
+
+    let rules = [
+        (policy.keep_hourly, "%Y-%m-%d %H"),
+        (policy.keep_daily, "%Y-%m-%d"),
+        (policy.keep_weekly, "%G-W%V"),
+        (policy.keep_monthly, "%Y-%m"),
+    ];
+    for (count, bucket_format) in rules {
+        let mut seen_buckets = HashSet::new();
+        for snapshot in &snapshots {
+            if seen_buckets.len() >= count {
+                break;
+            }
+            let Some(time) = Local.timestamp_opt(snapshot.timestamp, 0).single() else {
+                continue;
+            };
+            if seen_buckets.insert(time.format(bucket_format).to_string()) {
+                keep.insert(snapshot.id.clone());
+            }
+        }
+    }
+    keep
+}
+
+//merges the history of a removed snapshot into the kept snapshot taken after it, as if
+//both runs had been one. renames are chained and a deletion undone by a reappearance (or
+//the other way round) cancels out
+fn merge_history(earlier: Snapshot, later: &mut Snapshot) {
+    for rename in earlier.renamed {
+        match later.renamed.iter_mut().find(|r| r.from == rename.to) {
+            Some(next) => next.from = rename.from,
+            None => later.renamed.push(rename),
+        }
+    }
+    later.renamed.retain(|r| r.from != r.to);
+    for path in earlier.deleted {
+        match later.reappeared.iter().position(|p| *p == path) {
+            Some(i) => {
+                later.reappeared.remove(i);
+            }
+            None => later.deleted.push(path),
+        }
+    }
+    for path in earlier.reappeared {
+        match later.deleted.iter().position(|p| *p == path) {
+            Some(i) => {
+                later.deleted.remove(i);
+            }
+            None => later.reappeared.push(path),
+        }
+    }
+}
+
+//folds every removed snapshot into the next kept one and returns which kept snapshot took
+//over each removed id. the newest snapshot is always kept, so every removed one has a
+//successor
+fn fold_removed_snapshots(snapshots: &mut [Snapshot], keep: &HashSet<String>) -> HashMap<String, String> {
+    let mut absorbed_by = HashMap::new();
+    let mut removed: Vec<Snapshot> = Vec::new();
+    for snapshot in snapshots.iter_mut() {
+        if !keep.contains(&snapshot.id) {
+            removed.push(snapshot.clone());
+            continue;
+        }
+        // newest first, each merge puts an older run in front of what's already merged
+        for earlier in removed.drain(..).rev() {
+            absorbed_by.insert(earlier.id.clone(), snapshot.id.clone());
+            merge_history(earlier, snapshot);
+        }
+    }
+    absorbed_by
+}
+
+/// applies the retention policy to the metadata and lists the objects nothing refers to
+/// anymore in the report, deleting them is up to the caller (see prune_now). the newest
+/// version of every file is always kept. with dry_run the metadata isn't changed either
+pub fn prune(
+    storage: &dyn StorageBackend,
+    metadata: &mut BackupMetadata,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+) -> std::io::Result<PruneReport> {
+    let mut report = PruneReport { dry_run, ..Default::default() };
+    if !policy.is_enabled() || metadata.snapshots.is_empty() {
+        return Ok(report);
+    }
+
+    // encrypted object names depend on the key, without it every object would look orphaned
+    let needs_key = metadata
+        .files
+        .values()
+        .flat_map(|f| f.versions.iter())
+        .any(|v| v.encrypted || v.chunks.iter().any(|c| c.encrypted));
+    if needs_key && !crate::crypto::is_unlocked() {
+        return Err(crate::crypto::locked("Backup repository is locked, can't prune encrypted objects"));
+    }
+
+    let keep = snapshots_to_keep(metadata, policy);
+    // snapshots are appended in the order they were taken, so their position is their age
+    // (timestamps alone can tie when two runs happen in the same second)
+    let snapshot_order = metadata.snapshot_order();
+    let kept_order: Vec<i64> = metadata
+        .snapshots
+        .iter()
+        .enumerate()
+        .filter(|(_, s)| keep.contains(&s.id))
+        .map(|(i, _)| i as i64)
+        .collect();
+
+    report.removed_snapshots = metadata
+        .snapshots
+        .iter()
+        .filter(|s| !keep.contains(&s.id))
+        .map(|s| s.id.clone())
+        .collect();
+
+    // a version is still needed if a kept snapshot sees it, i.e. the snapshot was taken
+    // after the version was stored but before the next version (or the deletion) replaced it
+    let mut pruned = metadata.clone();
+    for info in pruned.files.values_mut() {
+        let count = info.versions.len();
+        let deleted_at = info
+            .deleted_in
+            .as_ref()
+            .and_then(|id| snapshot_order.get(id.as_str()).copied());
+        let mut kept_versions = Vec::with_capacity(count);
+        for (i, version) in info.versions.iter().enumerate() {
+            // versions without a snapshot record (legacy copies) count as the oldest ones
+            let from = snapshot_order.get(version.snapshot_id.as_str()).copied().unwrap_or(-1);
+            let until = info.versions
+                .get(i + 1)
+                .and_then(|next| snapshot_order.get(next.snapshot_id.as_str()).copied())
+                .or(deleted_at)
+                .unwrap_or(i64::MAX);
+            let visible = kept_order.iter().any(|&n| n >= from && n < until);
+
+            // the current version of a live file always stays, a deleted file's last
+            // version only as long as some kept snapshot still sees it
+            let is_current = i + 1 == count && info.deleted_in.is_none();
+            if is_current || visible {
+                kept_versions.push(version.clone());
+            } else {
+                report.removed_versions.push((info.original_path.clone(), version.snapshot_id.clone()));
+            }
+        }
+        info.versions = kept_versions;
+    }
+    // deleted files with no versions left are forgotten entirely
+    pruned.files.retain(|_, f| !f.versions.is_empty());
+    // what removed snapshots recorded moves on to the next kept one, so restoring from a later
+    // snapshot still knows the versions they stored and the files they saw deleted or renamed
+    let absorbed_by = fold_removed_snapshots(&mut pruned.snapshots, &keep);
+    for info in pruned.files.values_mut() {
+        for version in &mut info.versions {
+            if let Some(id) = absorbed_by.get(&version.snapshot_id) {
+                version.snapshot_id = id.clone();
+            }
+        }
+        if let Some(id) = info.deleted_in.as_ref().and_then(|d| absorbed_by.get(d)) {
+            info.deleted_in = Some(id.clone());
+        }
+    }
+    pruned.snapshots.retain(|s| keep.contains(&s.id));
+
+    // anything the pruned metadata doesn't reference can go: removed versions as well as
+    // objects orphaned earlier (e.g. by an interrupted run)
+    let still_used = pruned.referenced_objects()?;
+    let mut candidates = metadata.referenced_objects()?;
+    candidates.extend(crate::backup::stored_objects(storage)?);
+
+    for key in candidates {
+        if still_used.contains(&key) || key.is_empty() {
+            continue;
+        }
+        if let Ok(Some(stat)) = storage.stat(&key) {
+            report.freed_bytes += stat.size;
+            report.removed_objects.push(key);
+        }
+    }
+    report.removed_objects.sort();
+
+    if !dry_run {
+        *metadata = pruned;
+    }
+    Ok(report)
+}
+
+/// loads the metadata, prunes it with the policy from the settings, saves it again and
+/// deletes the objects it no longer needs
+pub fn prune_now(dry_run: bool) -> std::io::Result<PruneReport> {
+    // objects a backup stored but hasn't recorded yet would look orphaned
+    let _lock = crate::paths::lock_repository()?;
+    let settings = BackupSettings::load_from_file().unwrap_or_default();
+    let mut metadata = BackupMetadata::load_from_file()?;
+    let storage = storage::open(&settings);
+    let report = prune(storage.as_ref(), &mut metadata, &settings.retention, dry_run)?;
+    if !dry_run {
+        // metadata first: if deleting stops halfway the leftovers are just orphans, the
+        // other way round the metadata would point at objects that are gone
+        metadata.save(storage.as_ref(), &settings)?;
+        for key in &report.removed_objects {
+            crate::backup::delete_selected(storage.as_ref(), key)?;
+        }
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backup::{FileInfo, FileRename, FileVersion};
+    use std::path::Path;
+
+    fn snapshot(id: &str) -> Snapshot {
+        Snapshot { id: id.to_string(), ..Default::default() }
+    }
+
+    // local time, the buckets are local days, weeks and months
+    fn taken(id: &str, y: i32, mo: u32, d: u32, h: u32) -> Snapshot {
+        let timestamp = Local.with_ymd_and_hms(y, mo, d, h, 0, 0).single().unwrap().timestamp();
+        Snapshot { id: id.to_string(), timestamp, ..Default::default() }
+    }
+
+    fn ids(keep: &HashSet<String>) -> Vec<&str> {
+        let mut ids: Vec<&str> = keep.iter().map(|s| s.as_str()).collect();
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn each_rule_keeps_the_newest_snapshot_of_its_buckets() {
+        let metadata = BackupMetadata {
+            snapshots: vec![
+                taken("s1", 2026, 1, 10, 10),
+                taken("s2", 2026, 2, 3, 9),
+                taken("s3", 2026, 2, 20, 8),
+                // monday to thursday of one week
+                taken("s4", 2026, 3, 2, 8),
+                taken("s5", 2026, 3, 2, 20),
+                taken("s6", 2026, 3, 4, 8),
+                taken("s7", 2026, 3, 5, 8),
+                taken("s8", 2026, 3, 5, 9),
+            ],
+            ..Default::default()
+        };
+        let keep = |policy: RetentionPolicy| ids(&snapshots_to_keep(&metadata, &policy)).join(" ");
+
+        assert_eq!(keep(RetentionPolicy { keep_last: 3, ..Default::default() }), "s6 s7 s8");
+        assert_eq!(keep(RetentionPolicy { keep_hourly: 2, ..Default::default() }), "s7 s8");
+        assert_eq!(keep(RetentionPolicy { keep_daily: 3, ..Default::default() }), "s5 s6 s8");
+        // the week before s4 has no snapshot, the next bucket is the one of s3
+        assert_eq!(keep(RetentionPolicy { keep_weekly: 2, ..Default::default() }), "s3 s8");
+        assert_eq!(keep(RetentionPolicy { keep_monthly: 12, ..Default::default() }), "s1 s3 s8");
+        let combined = RetentionPolicy { keep_last: 1, keep_daily: 2, keep_weekly: 2, keep_monthly: 3, ..Default::default() };
+        assert_eq!(keep(combined), "s1 s3 s6 s8");
+    }
+
+    #[test]
+    fn runs_in_the_same_second_are_ordered_by_position() {
+        let mut metadata = BackupMetadata { snapshots: vec![taken("b", 2026, 3, 5, 9), taken("a", 2026, 3, 5, 9)], ..Default::default() };
+        let policy = RetentionPolicy { keep_last: 1, ..Default::default() };
+        assert_eq!(ids(&snapshots_to_keep(&metadata, &policy)), vec!["a"]);
+        metadata.snapshots.reverse();
+        assert_eq!(ids(&snapshots_to_keep(&metadata, &policy)), vec!["b"]);
+    }
+
+    #[test]
+    fn deleted_files_keep_their_last_version_while_a_kept_snapshot_sees_it() {
+        let sandbox = crate::storage::testing::sandbox();
+        let version = |snapshot_id: &str, key: &str| FileVersion {
+            snapshot_id: snapshot_id.to_string(),
+            hash: key.to_string(),
+            backup_path: PathBuf::from(format!("objects/{}", key)),
+            ..Default::default()
+        };
+        let file = |path: &str, versions: Vec<FileVersion>, deleted_in: Option<&str>| {
+            let latest = versions.last().unwrap();
+            let info = FileInfo {
+                original_path: PathBuf::from(path),
+                backup_path: latest.backup_path.clone(),
+                hash: latest.hash.clone(),
+                deleted_in: deleted_in.map(String::from),
+                versions,
+                ..Default::default()
+            };
+            (info.original_path.clone(), info)
+        };
+        let mut metadata = BackupMetadata {
+            snapshots: vec![
+                taken("s1", 2026, 3, 1, 8),
+                taken("s2", 2026, 3, 2, 8),
+                taken("s3", 2026, 3, 3, 8),
+                taken("s4", 2026, 3, 4, 8),
+            ],
+            files: [
+                file("/d/live.txt", vec![version("s1", "live-1"), version("s3", "live-3")], None),
+                // gone before the oldest kept snapshot
+                file("/d/early.txt", vec![version("s1", "early-1")], Some("s2")),
+                // still there when s3 was taken
+                file("/d/late.txt", vec![version("s1", "late-1")], Some("s4")),
+            ]
+            .into_iter()
+            .collect(),
+            ..Default::default()
+        };
+        metadata.snapshots[1].deleted = vec![PathBuf::from("/d/early.txt")];
+        metadata.snapshots[3].deleted = vec![PathBuf::from("/d/late.txt")];
+        for key in ["live-1", "live-3", "early-1", "late-1", "orphan"] {
+            sandbox.backend.put(&format!("objects/{}", key), b"data").unwrap();
+        }
+
+        let policy = RetentionPolicy { keep_last: 2, ..Default::default() };
+        let preview = prune(sandbox.backend.as_ref(), &mut metadata.clone(), &policy, true).unwrap();
+        let report = prune(sandbox.backend.as_ref(), &mut metadata, &policy, false).unwrap();
+        assert_eq!(preview.removed_objects, report.removed_objects);
+
+        assert_eq!(report.removed_snapshots, vec!["s1", "s2"]);
+        assert_eq!(report.removed_objects, vec!["objects/early-1", "objects/live-1", "objects/orphan"]);
+        assert_eq!(report.freed_bytes, 12);
+        assert!(!metadata.files.contains_key(Path::new("/d/early.txt")));
+        let late = &metadata.files[Path::new("/d/late.txt")];
+        // s1 went away, its version now counts as stored in s3
+        assert_eq!(late.versions.len(), 1);
+        assert_eq!(late.versions[0].snapshot_id, "s3");
+        assert_eq!(late.deleted_in.as_deref(), Some("s4"));
+        let live = &metadata.files[Path::new("/d/live.txt")];
+        assert_eq!(live.versions.iter().map(|v| v.hash.as_str()).collect::<Vec<_>>(), vec!["live-3"]);
+        // prune only reports the objects, prune_now deletes them
+        assert_eq!(sandbox.backend.list("objects/").unwrap().len(), 5);
+
+        // with every rule at 0 nothing goes
+        let report = prune(sandbox.backend.as_ref(), &mut metadata, &RetentionPolicy::default(), false).unwrap();
+        assert!(report.removed_snapshots.is_empty() && report.removed_objects.is_empty());
+    }
+
+    #[test]
+    fn removed_snapshots_fold_into_the_next_kept_one() {
+        let path = |p: &str| PathBuf::from(p);
+        let rename = |from: &str, to: &str| FileRename { from: path(from), to: path(to) };
+        let mut snapshots = vec![snapshot("s1"), snapshot("s2"), snapshot("s3"), snapshot("s4"), snapshot("s5")];
+        snapshots[1].deleted = vec![path("/d/gone.txt"), path("/d/back.txt")];
+        snapshots[1].renamed = vec![rename("/d/a.txt", "/d/b.txt")];
+        snapshots[2].reappeared = vec![path("/d/back.txt")];
+        snapshots[2].renamed = vec![rename("/d/b.txt", "/d/c.txt"), rename("/d/x.txt", "/d/y.txt")];
+        snapshots[3].renamed = vec![rename("/d/y.txt", "/d/x.txt")];
+        let keep: HashSet<String> = ["s1", "s4", "s5"].iter().map(|s| s.to_string()).collect();
+
+        let absorbed_by = fold_removed_snapshots(&mut snapshots, &keep);
+
+        assert_eq!(absorbed_by["s2"], "s4");
+        assert_eq!(absorbed_by["s3"], "s4");
+        let s4 = &snapshots[3];
+        assert_eq!(s4.deleted, vec![path("/d/gone.txt")]);
+        assert!(s4.reappeared.is_empty());
+        // a -> b -> c is one rename, x -> y -> x is none
+        assert_eq!(s4.renamed.len(), 1);
+        assert_eq!((&s4.renamed[0].from, &s4.renamed[0].to), (&path("/d/a.txt"), &path("/d/c.txt")));
+        assert!(snapshots[4].deleted.is_empty());
+    }
+}
@@ -0,0 +1,226 @@
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+use crate::backup::{BackupRoot, BackupSettings};
+
+// ignore files picked up from the folders being backed up
+const IGNORE_FILES: [&str; 2] = [".gitignore", ".backupignore"];
+
+//builds a matcher from gitignore style lines, bad patterns are reported and skipped
+fn build_matcher(root: &Path, patterns: &[String]) -> Gitignore {
+    let mut builder = GitignoreBuilder::new(root);
+    for pattern in patterns.iter().map(|p| p.trim()).filter(|p| !p.is_empty()) {
+        if let Err(e) = builder.add_line(None, pattern) {
+            println!("Ignoring invalid rule '{}': {}", pattern, e);
+        }
+    }
+    builder.build().unwrap_or_else(|e| {
+        println!("Failed to build rules for {}: {}", root.display(), e);
+        Gitignore::empty()
+    })
+}
+
+/// include/exclude rules for one backup folder: the global rules from the settings,
+/// the folder's own rules and any .gitignore/.backupignore found inside it
+pub struct RuleSet {
+    root: PathBuf,
+    excludes: Gitignore,
+    includes: Option<Gitignore>,
+    honor_ignore_files: bool,
+    // ignore files per directory, loaded the first time a directory is looked at
+    dir_rules: HashMap<PathBuf, Vec<Gitignore>>,
+}
+
+impl RuleSet {
+    pub fn new(root: &BackupRoot, settings: &BackupSettings) -> Self {
+        let excludes: Vec<String> = settings
+            .global_excludes
+            .iter()
+            .chain(root.exclude.iter())
+            .cloned()
+            .collect();
+        let includes: Vec<String> = settings
+            .global_includes
+            .iter()
+            .chain(root.include.iter())
+            .cloned()
+            .collect();
+
+        Self {
+            root: root.path.clone(),
+            excludes: build_matcher(&root.path, &excludes),
+            includes: (!includes.is_empty()).then(|| build_matcher(&root.path, &includes)),
+            honor_ignore_files: settings.honor_ignore_files,
+            dir_rules: HashMap::new(),
+        }
+    }
+
+    fn load_ignore_files(&mut self, dir: &Path) {
+        self.dir_rules.entry(dir.to_path_buf()).or_insert_with(|| {
+            IGNORE_FILES
+                .iter()
+                .map(|name| dir.join(name))
+                .filter(|path| path.is_file())
+                .map(|path| Gitignore::new(path).0)
+                .collect()
+        });
+    }
+
+    /// true if the path should be left out of the backup. a file is also left out when
+    /// one of its parent directories is excluded
+    pub fn is_excluded(&mut self, path: &Path, is_dir: bool) -> bool {
+        if !path.starts_with(&self.root) {
+            return false;
+        }
+        if path != self.root
+            && let Some(parent) = path.parent()
+            && parent != self.root
+            && self.is_excluded(parent, true)
+        {
+            return true;
+        }
+
+        // configured rules first, ignore files in deeper folders can override them
+        let mut excluded = self.excludes.matched(path, is_dir).is_ignore();
+        if self.honor_ignore_files {
+            let mut dirs: Vec<PathBuf> = path
+                .ancestors()
+                .skip(1)
+                .take_while(|dir| dir.starts_with(&self.root))
+                .map(Path::to_path_buf)
+                .collect();
+            dirs.reverse();
+            for dir in dirs {
+                self.load_ignore_files(&dir);
+                for rules in &self.dir_rules[&dir] {
+                    match rules.matched(path, is_dir) {
+                        Match::None => {}
+                        Match::Ignore(_) => excluded = true,
+                        Match::Whitelist(_) => excluded = false,
+                    }
+                }
+            }
+        }
+        if excluded {
+            return true;
+        }
+
+        // with include rules set only matching files are kept, folders are always walked
+        match &self.includes {
+            Some(includes) if !is_dir => !includes.matched(path, false).is_ignore(),
+            _ => false,
+        }
+    }
+}
+
+/// lists what the rules leave out of a backup folder. excluded folders are listed once
+/// with a trailing slash instead of every file inside them
+pub fn preview_exclusions(root: &BackupRoot, settings: &BackupSettings) -> Vec<PathBuf> {
+    let mut rules = RuleSet::new(root, settings);
+    let mut excluded = Vec::new();
+
+    let mut walker = WalkDir::new(&root.path).into_iter();
+    while let Some(entry) = walker.next() {
+        let Ok(entry) = entry else { continue };
+        let is_dir = entry.file_type().is_dir();
+        if entry.path() == root.path || !rules.is_excluded(entry.path(), is_dir) {
+            continue;
+        }
+        if is_dir {
+            excluded.push(entry.path().join(""));
+            walker.skip_current_dir();
+        } else {
+            excluded.push(entry.path().to_path_buf());
+        }
+    }
+    excluded
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    // a folder tree to back up, removed again on drop
+    struct Tree(PathBuf);
+
+    impl Tree {
+        fn new(name: &str, files: &[(&str, &str)]) -> Self {
+            let dir = std::env::temp_dir().join(format!("fass-rules-test-{}-{}", std::process::id(), name));
+            let _ = fs::remove_dir_all(&dir);
+            for (path, contents) in files {
+                let path = dir.join(path);
+                fs::create_dir_all(path.parent().unwrap()).unwrap();
+                fs::write(path, contents).unwrap();
+            }
+            Self(dir)
+        }
+
+        fn rules(&self, settings: &BackupSettings) -> RuleSet {
+            RuleSet::new(&BackupRoot { path: self.0.clone(), ..Default::default() }, settings)
+        }
+    }
+
+    impl Drop for Tree {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn nested_ignore_files_can_take_exclusions_back() {
+        let tree = Tree::new(
+            "nested",
+            &[
+                (".gitignore", "*.tmp\nbuild/\n"),
+                ("src/.backupignore", "!keep.tmp\n!important.bak\n"),
+                ("src/keep.tmp", ""),
+                ("src/other.tmp", ""),
+                ("src/important.bak", ""),
+                ("src/old.bak", ""),
+                ("top.tmp", ""),
+                ("build/out.bin", ""),
+            ],
+        );
+        let settings = BackupSettings { global_excludes: vec!["*.bak".to_string()], ..Default::default() };
+        let mut rules = tree.rules(&settings);
+        let mut excluded = |path: &str| rules.is_excluded(&tree.0.join(path.trim_end_matches('/')), path.ends_with('/'));
+
+        assert!(excluded("top.tmp"));
+        assert!(excluded("src/other.tmp"));
+        assert!(!excluded("src/keep.tmp"));
+        // a deeper ignore file overrides the configured rules too
+        assert!(!excluded("src/important.bak"));
+        assert!(excluded("src/old.bak"));
+        assert!(excluded("build/"));
+        assert!(excluded("build/out.bin"));
+        assert!(!excluded("src/main.rs"));
+
+        let mut rules = tree.rules(&BackupSettings { honor_ignore_files: false, ..settings });
+        assert!(!rules.is_excluded(&tree.0.join("top.tmp"), false));
+        assert!(rules.is_excluded(&tree.0.join("src/important.bak"), false));
+    }
+
+    #[test]
+    fn include_rules_keep_only_matching_files() {
+        let tree = Tree::new("include", &[("notes/a.txt", ""), ("notes/secret.txt", ""), ("notes/b.rs", "")]);
+        let root = BackupRoot { path: tree.0.clone(), include: vec!["*.txt".to_string()], ..Default::default() };
+        let settings = BackupSettings { global_excludes: vec!["secret.txt".to_string()], ..Default::default() };
+        let mut rules = RuleSet::new(&root, &settings);
+
+        assert!(!rules.is_excluded(&tree.0.join("notes/a.txt"), false));
+        assert!(rules.is_excluded(&tree.0.join("notes/b.rs"), false));
+        // folders are walked even if their name doesn't match
+        assert!(!rules.is_excluded(&tree.0.join("notes"), true));
+        // excludes win over includes
+        assert!(rules.is_excluded(&tree.0.join("notes/secret.txt"), false));
+        // paths outside the folder aren't this rule set's business
+        assert!(!rules.is_excluded(Path::new("/elsewhere/b.rs"), false));
+
+        let mut preview = preview_exclusions(&root, &settings);
+        preview.sort();
+        assert_eq!(preview, vec![tree.0.join("notes/b.rs"), tree.0.join("notes/secret.txt")]);
+    }
+}
@@ -0,0 +1,613 @@
+// a folder on another machine reached over ssh, e.g. a home server. destinations are
+// written sftp://user@host:port/path, a path starting with /~/ is below the login folder.
+// only key based logins are supported (ssh agent first, then the key file from the
+// settings) and the server has to be in known_hosts already, an unknown or changed host
+// key stops the backup instead of being trusted
+
+use serde::{Serialize, Deserialize};
+use dirs_next::home_dir;
+use sha2::{Sha256, Digest};
+use ssh2::{CheckResult, ErrorCode, KnownHostFileKind, OpenFlags, OpenType, RenameFlags, Session, Sftp};
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use crate::storage::{self, ObjectStat, StorageBackend};
+
+const SCHEME: &str = "sftp://";
+const DEFAULT_PORT: u16 = 22;
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// how long one ssh operation may block
+const SESSION_TIMEOUT_MS: u32 = 60_000;
+// sftp error codes, see the sftp draft
+const FX_PERMISSION_DENIED: i32 = 3;
+const FX_FILE_ALREADY_EXISTS: i32 = 11;
+// why a destination that was used before has no marker
+const MISSING_HINT: &str = "backup data not found, is the path right?";
+
+/// how to log in to sftp:// destinations
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SftpSettings {
+    // used when the ssh agent has no key the server accepts
+    pub private_key: PathBuf,
+    pub known_hosts: PathBuf,
+    pub max_retries: u32,
+}
+
+impl Default for SftpSettings {
+    fn default() -> Self {
+        let ssh_dir = home_dir().unwrap_or_default().join(".ssh");
+        Self {
+            private_key: ssh_dir.join("id_ed25519"),
+            known_hosts: ssh_dir.join("known_hosts"),
+            max_retries: 3,
+        }
+    }
+}
+
+/// where an sftp:// destination points to
+#[derive(Debug, Clone, PartialEq)]
+pub struct SftpTarget {
+    pub user: String,
+    pub host: String,
+    pub port: u16,
+    // the folder on the server, relative ones are below the login folder
+    pub root: PathBuf,
+}
+
+/// user, host, port and folder of an sftp://user@host:port/path destination, None for
+/// anything else. the user defaults to the local one
+pub fn parse_destination(destination: &Path) -> Option<SftpTarget> {
+    let rest = destination.to_str()?.strip_prefix(SCHEME)?;
+    let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+    let (user, host_port) = match authority.rsplit_once('@') {
+        Some((user, host_port)) => (user.to_string(), host_port),
+        None => (std::env::var("USER").or_else(|_| std::env::var("LOGNAME")).unwrap_or_default(), authority),
+    };
+    let (host, port) = match host_port.rsplit_once(':') {
+        Some((host, port)) => (host, port.parse().ok()?),
+        None => (host_port, DEFAULT_PORT),
+    };
+    if host.is_empty() {
+        return None;
+    }
+    let root = match path.strip_prefix('~') {
+        Some(relative) => PathBuf::from(relative.trim_start_matches('/')),
+        None => Path::new("/").join(path),
+    };
+    Some(SftpTarget { user, host: host.to_string(), port, root })
+}
+
+//like ssh2's own conversion but keeps permission errors apart, those aren't retried
+fn io_error(e: ssh2::Error) -> Error {
+    let kind = match e.code() {
+        ErrorCode::SFTP(FX_PERMISSION_DENIED) => ErrorKind::PermissionDenied,
+        ErrorCode::SFTP(FX_FILE_ALREADY_EXISTS) => ErrorKind::AlreadyExists,
+        _ => return e.into(),
+    };
+    Error::new(kind, e.message().to_string())
+}
+
+// errors that come back the same no matter how often the operation is tried. NotFound is
+// only ever a missing file, never a missing server, stat relies on that
+fn is_final(e: &Error) -> bool {
+    matches!(e.kind(), ErrorKind::NotFound | ErrorKind::PermissionDenied | ErrorKind::AlreadyExists | ErrorKind::InvalidInput)
+}
+
+// how much of data an earlier upload left in a part file holding existing bytes. the name
+// only says what the upload was meant to be, a write cut off halfway or another program may
+// have left something else, so the bytes are read back and compared. 0 means start over
+fn resumable_prefix<F: Read + Seek>(file: &mut F, existing: u64, data: &[u8]) -> std::io::Result<u64> {
+    if existing == 0 || existing > data.len() as u64 {
+        return Ok(0);
+    }
+    file.seek(SeekFrom::Start(0))?;
+    let mut buffer = vec![0u8; 64 * 1024];
+    let mut checked = 0;
+    while checked < existing as usize {
+        let wanted = buffer.len().min(existing as usize - checked);
+        let read = file.read(&mut buffer[..wanted])?;
+        if read == 0 || buffer[..read] != data[checked..checked + read] {
+            return Ok(0);
+        }
+        checked += read;
+    }
+    Ok(existing)
+}
+
+/// a logged in session with its sftp channel
+struct Connection {
+    // kept for as long as the channel is used
+    _session: Session,
+    sftp: Sftp,
+    // folders known to exist, so they aren't asked for again on every upload
+    folders: Mutex<HashSet<PathBuf>>,
+}
+
+// open connections by user@host:port, shared by every backend and worker thread
+static CONNECTIONS: Mutex<Option<HashMap<String, Arc<Connection>>>> = Mutex::new(None);
+
+pub struct SftpBackend {
+    target: SftpTarget,
+    settings: SftpSettings,
+}
+
+impl SftpBackend {
+    pub fn new(target: SftpTarget, settings: SftpSettings) -> Self {
+        Self { target, settings }
+    }
+
+    fn connection_id(&self) -> String {
+        format!("{}@{}:{}", self.target.user, self.target.host, self.target.port)
+    }
+
+    fn connect(&self) -> std::io::Result<Connection> {
+        let target = &self.target;
+        if target.user.is_empty() {
+            return Err(Error::new(ErrorKind::InvalidInput, "No user name for the sftp destination, use sftp://user@host/path"));
+        }
+        let address = (target.host.as_str(), target.port)
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| Error::other(format!("Can't resolve {}", target.host)))?;
+        let tcp = TcpStream::connect_timeout(&address, CONNECT_TIMEOUT)?;
+        let mut session = Session::new().map_err(io_error)?;
+        session.set_tcp_stream(tcp);
+        session.set_timeout(SESSION_TIMEOUT_MS);
+        session.handshake().map_err(io_error)?;
+        self.verify_host_key(&session)?;
+
+        if session.userauth_agent(&target.user).is_err() || !session.authenticated() {
+            session
+                .userauth_pubkey_file(&target.user, None, &self.settings.private_key, None)
+                .map_err(|e| {
+                    Error::new(
+                        ErrorKind::PermissionDenied,
+                        format!(
+                            "Can't log in to {} as {} with the ssh agent or {}: {}",
+                            target.host,
+                            target.user,
+                            self.settings.private_key.display(),
+                            e.message()
+                        ),
+                    )
+                })?;
+        }
+        let sftp = session.sftp().map_err(io_error)?;
+        Ok(Connection { _session: session, sftp, folders: Mutex::new(HashSet::new()) })
+    }
+
+    // the key the server shows has to be the one known_hosts has for it
+    fn verify_host_key(&self, session: &Session) -> std::io::Result<()> {
+        let (key, _) = session
+            .host_key()
+            .ok_or_else(|| Error::other(format!("{} sent no host key", self.target.host)))?;
+        self.check_host_key(session, key)
+    }
+
+    fn check_host_key(&self, session: &Session, key: &[u8]) -> std::io::Result<()> {
+        let mut known_hosts = session.known_hosts().map_err(io_error)?;
+        known_hosts.read_file(&self.settings.known_hosts, KnownHostFileKind::OpenSSH).map_err(|e| {
+            Error::new(
+                ErrorKind::PermissionDenied,
+                format!("Can't read {}: {}", self.settings.known_hosts.display(), e.message()),
+            )
+        })?;
+        let refused = |reason: &str| Error::new(ErrorKind::PermissionDenied, format!("Host key of {} {}", self.target.host, reason));
+        match known_hosts.check_port(&self.target.host, self.target.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::NotFound => Err(refused("is unknown, connect once with ssh to add it to known_hosts")),
+            CheckResult::Mismatch => Err(refused("does not match known_hosts, it may have been replaced")),
+            CheckResult::Failure => Err(refused("could not be checked")),
+        }
+    }
+
+    fn connection(&self) -> std::io::Result<Arc<Connection>> {
+        let id = self.connection_id();
+        if let Some(connection) = CONNECTIONS.lock().unwrap().get_or_insert_with(HashMap::new).get(&id) {
+            return Ok(connection.clone());
+        }
+        // connecting happens outside the lock, it can take a while
+        let connection = Arc::new(self.connect()?);
+        CONNECTIONS.lock().unwrap().get_or_insert_with(HashMap::new).insert(id, connection.clone());
+        Ok(connection)
+    }
+
+    // a connection that failed is dropped, the next attempt logs in again
+    fn disconnect(&self) {
+        if let Some(connections) = CONNECTIONS.lock().unwrap().as_mut() {
+            connections.remove(&self.connection_id());
+        }
+    }
+
+    /// runs f with a connection, reconnecting and trying again with growing pauses when
+    /// the connection or the server had a problem
+    fn with_retry<T, F>(&self, what: &str, f: F) -> std::io::Result<T>
+    where
+        F: Fn(&Connection) -> std::io::Result<T>,
+    {
+        let mut attempt = 0;
+        loop {
+            let error = match self.connection().and_then(|connection| f(&connection)) {
+                Ok(value) => return Ok(value),
+                Err(e) if is_final(&e) => return Err(e),
+                Err(e) => e,
+            };
+            self.disconnect();
+            if attempt >= self.settings.max_retries {
+                return Err(Error::new(error.kind(), format!("SFTP {} on {} failed: {}", what, self.target.host, error)));
+            }
+            let pause = storage::backoff(Duration::from_secs(1), attempt);
+            println!("SFTP {} on {} failed ({}), retrying in {}s", what, self.target.host, error, pause.as_secs());
+            std::thread::sleep(pause);
+            attempt += 1;
+        }
+    }
+
+    fn path_of(&self, key: &str) -> PathBuf {
+        self.target.root.join(key)
+    }
+
+    // where an upload of data to key is written until it's complete
+    fn part_path(&self, key: &str, data: &[u8]) -> PathBuf {
+        let content_id = format!("{:x}", Sha256::digest(data));
+        PathBuf::from(format!("{}.{}.part", self.path_of(key).display(), &content_id[..16]))
+    }
+
+    // mkdir -p on the server
+    fn create_folders(connection: &Connection, folder: &Path) -> std::io::Result<()> {
+        if folder.as_os_str().is_empty() || connection.folders.lock().unwrap().contains(folder) {
+            return Ok(());
+        }
+        if connection.sftp.stat(folder).is_err() {
+            if let Some(parent) = folder.parent() {
+                Self::create_folders(connection, parent)?;
+            }
+            // another worker may have created it in the meantime
+            if let Err(e) = connection.sftp.mkdir(folder, 0o755)
+                && connection.sftp.stat(folder).is_err()
+            {
+                return Err(io_error(e));
+            }
+        }
+        connection.folders.lock().unwrap().insert(folder.to_path_buf());
+        Ok(())
+    }
+
+    // renames over an existing file, servers without the posix-rename extension refuse that
+    fn replace(connection: &Connection, from: &Path, to: &Path) -> std::io::Result<()> {
+        let flags = Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE);
+        if connection.sftp.rename(from, to, flags).is_ok() {
+            return Ok(());
+        }
+        let _ = connection.sftp.unlink(to);
+        connection.sftp.rename(from, to, flags).map_err(io_error)
+    }
+
+    fn list_folder(connection: &Connection, folder: &Path, files: &mut Vec<PathBuf>) -> std::io::Result<()> {
+        let entries = match connection.sftp.readdir(folder) {
+            Ok(entries) => entries,
+            Err(e) => match io_error(e) {
+                e if e.kind() == ErrorKind::NotFound => return Ok(()),
+                e => return Err(e),
+            },
+        };
+        for (path, stat) in entries {
+            if stat.is_dir() {
+                Self::list_folder(connection, &path, files)?;
+            } else {
+                files.push(path);
+            }
+        }
+        Ok(())
+    }
+}
+
+impl StorageBackend for SftpBackend {
+    fn name(&self) -> String {
+        let root = &self.target.root;
+        if root.is_absolute() {
+            format!("{}{}{}", SCHEME, self.connection_id(), root.display())
+        } else {
+            format!("{}{}/~/{}", SCHEME, self.connection_id(), root.display())
+        }
+    }
+
+    // the folder on the server is created on first use, the server being reachable already
+    // shows it isn't an unplugged drive. after that it has to keep its marker
+    fn check(&self, used_before: bool) -> std::io::Result<()> {
+        storage::probe_destination(self, used_before, MISSING_HINT, || Ok(()))
+    }
+
+    fn check_readonly(&self, used_before: bool) -> std::io::Result<()> {
+        storage::inspect_destination(self, used_before, MISSING_HINT)
+    }
+
+    // uploads go to a .part file named after the content, so an upload cut off by a dropped
+    // connection or an earlier run that died continues where it stopped instead of starting
+    // over, as long as what's there checks out. encrypted data differs on every attempt and
+    // just starts a new part file
+    fn put(&self, key: &str, data: &[u8]) -> std::io::Result<()> {
+        let path = self.path_of(key);
+        let part_path = self.part_path(key, data);
+
+        self.with_retry("upload", |connection| {
+            if let Some(parent) = path.parent() {
+                Self::create_folders(connection, parent)?;
+            }
+            let open = |flags: OpenFlags| {
+                connection.sftp.open_mode(&part_path, flags | OpenFlags::CREATE, 0o644, OpenType::File).map_err(io_error)
+            };
+            let mut file = open(OpenFlags::READ | OpenFlags::WRITE)?;
+            let existing = file.stat().map_err(io_error)?.size.unwrap_or(0);
+            let offset = resumable_prefix(&mut file, existing, data)?;
+            // writing from the start replaces everything up to data.len(), only a longer
+            // file has to be cut
+            if existing > data.len() as u64 {
+                file = open(OpenFlags::WRITE | OpenFlags::TRUNCATE)?;
+            }
+            if offset > 0 {
+                println!("Resuming upload of {} at {} of {} bytes", key, offset, data.len());
+            }
+            file.seek(SeekFrom::Start(offset))?;
+            file.write_all(&data[offset as usize..])?;
+            file.fsync().or_else(|e| match e.code() {
+                // not every server can fsync, the rename below still only shows complete files
+                ErrorCode::SFTP(_) => Ok(()),
+                _ => Err(io_error(e)),
+            })?;
+            drop(file);
+            Self::replace(connection, &part_path, &path)
+        })
+    }
+
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+        self.with_retry("download", |connection| {
+            let mut file = connection.sftp.open(self.path_of(key)).map_err(io_error)?;
+            let mut data = Vec::new();
+            file.read_to_end(&mut data)?;
+            Ok(data)
+        })
+    }
+
+    // unfinished .part files are left out
+    fn list(&self, prefix: &str) -> std::io::Result<Vec<String>> {
+        let files = self.with_retry("listing", |connection| {
+            let mut files = Vec::new();
+            Self::list_folder(connection, &self.path_of(prefix), &mut files)?;
+            Ok(files)
+        })?;
+        Ok(files
+            .iter()
+            .filter(|path| path.extension().map(|ext| ext != "part").unwrap_or(true))
+            .filter_map(|path| Some(path.strip_prefix(&self.target.root).ok()?.to_str()?.to_string()))
+            .collect())
+    }
+
+    fn delete(&self, key: &str) -> std::io::Result<()> {
+        let result = self.with_retry("delete", |connection| connection.sftp.unlink(&self.path_of(key)).map_err(io_error));
+        match result {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn stat(&self, key: &str) -> std::io::Result<Option<ObjectStat>> {
+        let result = self.with_retry("stat", |connection| connection.sftp.stat(&self.path_of(key)).map_err(io_error));
+        match result {
+            Ok(stat) => Ok(Some(ObjectStat { size: stat.size.unwrap_or(0) })),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::Engine;
+    use base64::engine::general_purpose::STANDARD as BASE64;
+
+    const KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIByux3iukCtpghxJtU6s5EsegmApi8qo7OjQfV5j469J";
+    const OTHER_KEY: &str = "AAAAC3NzaC1lZDI1NTE5AAAAIBfvDkma1JwntBtoW7BO4KtrkhgpmNkr9FEyScy1r0bC";
+
+    fn backend(destination: &str, known_hosts: PathBuf) -> SftpBackend {
+        let target = parse_destination(Path::new(destination)).unwrap();
+        SftpBackend::new(target, SftpSettings { known_hosts, max_retries: 0, ..Default::default() })
+    }
+
+    // a known_hosts file with the given lines, removed again on drop
+    struct KnownHosts(PathBuf);
+
+    impl KnownHosts {
+        fn new(name: &str, lines: &[String]) -> Self {
+            let path = std::env::temp_dir().join(format!("fass-known-hosts-{}-{}", std::process::id(), name));
+            std::fs::write(&path, lines.join("\n") + "\n").unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for KnownHosts {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn destinations_name_user_host_port_and_folder() {
+        let parse = |text: &str| parse_destination(Path::new(text));
+        assert_eq!(
+            parse("sftp://alice@nas.local:2222/srv/backup"),
+            Some(SftpTarget { user: "alice".into(), host: "nas.local".into(), port: 2222, root: PathBuf::from("/srv/backup") })
+        );
+        // /~/ is below the login folder
+        let home = parse("sftp://alice@nas.local/~/backup/laptop").unwrap();
+        assert_eq!((home.port, home.root), (DEFAULT_PORT, PathBuf::from("backup/laptop")));
+        assert_eq!(parse("sftp://alice@nas.local/~").unwrap().root, PathBuf::new());
+        assert_eq!(parse("sftp://alice@nas.local").unwrap().root, PathBuf::from("/"));
+        // an @ in the user name stays in it
+        assert_eq!(parse("sftp://alice@home@nas.local/b").unwrap().user, "alice@home");
+        assert_eq!(parse("sftp://alice@nas.local:ssh/b"), None);
+        assert_eq!(parse("sftp://alice@/b"), None);
+        assert_eq!(parse("s3://bucket/b"), None);
+
+        // without a user the local one is used
+        let local = std::env::var("USER").or_else(|_| std::env::var("LOGNAME")).unwrap_or_default();
+        assert_eq!(parse("sftp://nas.local/b").unwrap().user, local);
+    }
+
+    #[test]
+    fn a_destination_without_a_user_is_refused_before_connecting() {
+        let mut backend = backend("sftp://nobody@nas.invalid/b", PathBuf::new());
+        backend.target.user.clear();
+        let error = backend.stat("x").unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+        assert!(error.to_string().contains("No user name"));
+    }
+
+    #[test]
+    fn names_round_trip_through_parse_destination() {
+        for destination in ["sftp://alice@nas.local:22/srv/backup", "sftp://alice@nas.local:2222/~/backup"] {
+            assert_eq!(backend(destination, PathBuf::new()).name(), destination);
+        }
+    }
+
+    #[test]
+    fn part_files_are_named_after_the_content() {
+        let backend = backend("sftp://alice@nas.local/srv/backup", PathBuf::new());
+        let part = backend.part_path("objects/ab/abc", b"hello");
+        assert_eq!(part, PathBuf::from("/srv/backup/objects/ab/abc.2cf24dba5fb0a30e.part"));
+        assert_eq!(backend.part_path("objects/ab/abc", b"hello"), part);
+        assert_ne!(backend.part_path("objects/ab/abc", b"hello!"), part);
+    }
+
+    #[test]
+    fn only_a_part_file_holding_the_start_of_the_data_is_resumed() {
+        let data: Vec<u8> = (0..200 * 1024).map(|i| (i % 251) as u8).collect();
+        let resumable = |contents: &[u8], existing: u64| {
+            resumable_prefix(&mut std::io::Cursor::new(contents.to_vec()), existing, &data).unwrap()
+        };
+
+        assert_eq!(resumable(&data[..150 * 1024], 150 * 1024), 150 * 1024);
+        assert_eq!(resumable(&data, data.len() as u64), data.len() as u64);
+        assert_eq!(resumable(&[], 0), 0);
+
+        let mut damaged = data[..150 * 1024].to_vec();
+        damaged[100 * 1024] ^= 0xff;
+        assert_eq!(resumable(&damaged, 150 * 1024), 0);
+        let mut longer = data.clone();
+        longer.extend_from_slice(b"left over");
+        assert_eq!(resumable(&longer, longer.len() as u64), 0);
+        // the server said there was more than could be read
+        assert_eq!(resumable(&data[..1000], 150 * 1024), 0);
+    }
+
+    #[test]
+    fn unknown_and_changed_host_keys_are_refused() {
+        let key = BASE64.decode(KEY).unwrap();
+        let other_key = BASE64.decode(OTHER_KEY).unwrap();
+        let known_hosts = KnownHosts::new(
+            "check",
+            &[format!("[nas.local]:2222 ssh-ed25519 {}", KEY), format!("backup.example.com ssh-ed25519 {}", KEY)],
+        );
+        let session = Session::new().unwrap();
+        let check = |destination: &str, key: &[u8]| backend(destination, known_hosts.0.clone()).check_host_key(&session, key);
+
+        check("sftp://alice@nas.local:2222/b", &key).unwrap();
+        check("sftp://alice@backup.example.com/b", &key).unwrap();
+
+        let changed = check("sftp://alice@nas.local:2222/b", &other_key).unwrap_err();
+        assert_eq!(changed.kind(), ErrorKind::PermissionDenied);
+        assert!(changed.to_string().contains("does not match known_hosts"));
+        // the same host on another port is another server
+        let unknown = check("sftp://alice@nas.local/b", &key).unwrap_err();
+        assert_eq!(unknown.kind(), ErrorKind::PermissionDenied);
+        assert!(unknown.to_string().contains("is unknown"));
+
+        let missing = backend("sftp://alice@nas.local/b", known_hosts.0.with_extension("missing"))
+            .check_host_key(&session, &key)
+            .unwrap_err();
+        assert_eq!(missing.kind(), ErrorKind::PermissionDenied);
+    }
+
+    // the live tests need an sshd, e.g. on localhost. FASS_TEST_SFTP_DESTINATION is a folder
+    // on it like sftp://me@localhost/~/fass-test, the login uses the ssh agent or
+    // ~/.ssh/id_ed25519 and the host has to be in ~/.ssh/known_hosts
+    fn live_backend() -> SftpBackend {
+        let destination = std::env::var("FASS_TEST_SFTP_DESTINATION").expect("FASS_TEST_SFTP_DESTINATION isn't set");
+        let mut backend = backend(&destination, SftpSettings::default().known_hosts);
+        backend.target.root.push(format!("run-{}", std::process::id()));
+        backend
+    }
+
+    #[test]
+    #[ignore]
+    fn objects_round_trip_through_a_real_server() {
+        let backend = live_backend();
+        backend.check(false).unwrap();
+        backend.check(true).unwrap();
+        backend.put("objects/aa/small", b"hello").unwrap();
+        backend.put("objects/aa/small", b"hello again").unwrap();
+        assert_eq!(backend.get("objects/aa/small").unwrap(), b"hello again");
+        assert_eq!(backend.stat("objects/aa/small").unwrap().map(|s| s.size), Some(11));
+        assert_eq!(backend.list("objects/").unwrap(), vec!["objects/aa/small"]);
+        for key in backend.list("").unwrap() {
+            backend.delete(&key).unwrap();
+        }
+        assert!(backend.stat("objects/aa/small").unwrap().is_none());
+        assert_eq!(backend.get("objects/aa/small").unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    #[ignore]
+    fn uploads_resume_from_a_part_file_that_checks_out() {
+        let backend = live_backend();
+        let data: Vec<u8> = (0..256 * 1024).map(|i| (i % 251) as u8).collect();
+        let part = backend.part_path("objects/bb/big", &data);
+        let mut damaged = data[..data.len() / 2].to_vec();
+        damaged[..6].copy_from_slice(b"broken");
+        let mut longer = data.clone();
+        longer.extend_from_slice(b"left over");
+
+        for left_behind in [&data[..data.len() / 2], &damaged[..], &longer[..]] {
+            backend
+                .with_retry("setup", |connection| {
+                    SftpBackend::create_folders(connection, part.parent().unwrap())?;
+                    connection.sftp.create(&part).map_err(io_error)?.write_all(left_behind)
+                })
+                .unwrap();
+            assert_eq!(backend.list("objects/").unwrap(), Vec::<String>::new());
+
+            backend.put("objects/bb/big", &data).unwrap();
+
+            assert_eq!(backend.get("objects/bb/big").unwrap(), data);
+            let part_left = backend.with_retry("stat", |connection| Ok(connection.sftp.stat(&part).is_ok())).unwrap();
+            assert!(!part_left);
+            backend.delete("objects/bb/big").unwrap();
+        }
+    }
+
+    #[test]
+    #[ignore]
+    fn servers_with_unknown_or_changed_keys_are_refused() {
+        let live = live_backend();
+        let host = match live.target.port {
+            DEFAULT_PORT => live.target.host.clone(),
+            port => format!("[{}]:{}", live.target.host, port),
+        };
+        let changed = KnownHosts::new("changed", &[format!("{} ssh-ed25519 {}", host, KEY)]);
+        let unknown = KnownHosts::new("unknown", &[]);
+        for (known_hosts, reason) in [(&changed, "does not match known_hosts"), (&unknown, "is unknown")] {
+            let backend = SftpBackend::new(
+                live.target.clone(),
+                SftpSettings { known_hosts: known_hosts.0.clone(), ..Default::default() },
+            );
+            let error = backend.connect().err().unwrap();
+            assert_eq!(error.kind(), ErrorKind::PermissionDenied);
+            assert!(error.to_string().contains(reason), "{}", error);
+        }
+    }
+}
@@ -0,0 +1,555 @@
+// where stored objects end up. the backup code only deals in keys like
+// objects/8c/8c105d..., a backend maps them onto a destination, so a new kind of
+// destination only needs a new backend and none of the scanning code changes
+
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, OnceLock};
+#[cfg(test)]
+use std::sync::Mutex;
+use std::time::Duration;
+use walkdir::WalkDir;
+use crate::backup::BackupSettings;
+use crate::s3::{self, S3Backend};
+use crate::sftp::{self, SftpBackend};
+use crate::webdav::{self, WebdavBackend};
+
+// marks a destination as holding a backup, see probe_destination
+const DESTINATION_MARKER: &str = ".fass-backup";
+// why a local destination that was used before has no marker
+const LOCAL_MISSING_HINT: &str = "backup data not found, is the drive mounted?";
+
+/// what a backend knows about a stored object without reading it
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ObjectStat {
+    pub size: u64,
+}
+
+pub trait StorageBackend: Send + Sync {
+    /// where the data goes, for messages
+    fn name(&self) -> String;
+
+    /// makes sure the destination can be reached and written to before a run. used_before
+    /// is set for destinations earlier runs wrote to, they must not look empty now
+    fn check(&self, used_before: bool) -> std::io::Result<()>;
+
+    /// like check but only looks, nothing gets written or created. for showing whether the
+    /// destination is usable, e.g. in a status
+    fn check_readonly(&self, used_before: bool) -> std::io::Result<()>;
+
+    /// stores data under key, replacing what was there. a reader never sees half of it
+    fn put(&self, key: &str, data: &[u8]) -> std::io::Result<()>;
+
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>>;
+
+    /// every key starting with prefix
+    fn list(&self, prefix: &str) -> std::io::Result<Vec<String>>;
+
+    /// deleting a key that doesn't exist is not an error
+    fn delete(&self, key: &str) -> std::io::Result<()>;
+
+    /// None if nothing is stored under key
+    fn stat(&self, key: &str) -> std::io::Result<Option<ObjectStat>>;
+}
+
+/// where an object path ends up below any destination: objects/<prefix>/<name>. also turns
+/// the absolute paths older metadata recorded into keys, None for paths that aren't objects
+pub fn object_key_of(path: &Path) -> Option<String> {
+    let name = path.file_name()?.to_str()?;
+    let prefix_dir = path.parent()?;
+    let prefix = prefix_dir.file_name()?.to_str()?;
+    if prefix_dir.parent()?.file_name()? != "objects" {
+        return None;
+    }
+    Some(format!("objects/{}/{}", prefix, name))
+}
+
+/// the error for a destination that can't be used right now
+pub fn unavailable(backend: &dyn StorageBackend, reason: &str) -> Error {
+    Error::new(
+        ErrorKind::NotFound,
+        format!("Backup destination {} is not available ({})", backend.name(), reason),
+    )
+}
+
+/// the part of StorageBackend::check every backend shares. a destination that was used
+/// before has to still hold its marker, else it's most likely an unmounted drive or a typo
+/// and hint says what to look at. then a probe is written and removed and the marker put in
+/// place. prepare runs first on a destination without a marker, e.g. to create its folder
+pub fn probe_destination(
+    backend: &dyn StorageBackend,
+    used_before: bool,
+    hint: &str,
+    prepare: impl FnOnce() -> std::io::Result<()>,
+) -> std::io::Result<()> {
+    let has_marker = backend
+        .stat(DESTINATION_MARKER)
+        .map_err(|e| unavailable(backend, &e.to_string()))?
+        .is_some();
+    if used_before && !has_marker {
+        return Err(unavailable(backend, hint));
+    }
+    if !has_marker {
+        prepare()?;
+    }
+
+    let probe = format!(".write-test-{}", std::process::id());
+    backend.put(&probe, b"").and_then(|_| backend.delete(&probe)).map_err(|e| {
+        Error::new(e.kind(), format!("Backup destination {} is not writable: {}", backend.name(), e))
+    })?;
+    if !has_marker {
+        backend.put(DESTINATION_MARKER, b"fass-backup destination\n")?;
+    }
+    Ok(())
+}
+
+/// the part of StorageBackend::check_readonly every backend shares, the marker check of
+/// probe_destination without the probe
+pub fn inspect_destination(backend: &dyn StorageBackend, used_before: bool, hint: &str) -> std::io::Result<()> {
+    let has_marker = backend
+        .stat(DESTINATION_MARKER)
+        .map_err(|e| unavailable(backend, &e.to_string()))?
+        .is_some();
+    if used_before && !has_marker {
+        return Err(unavailable(backend, hint));
+    }
+    Ok(())
+}
+
+/// the http client of the s3 and webdav backends, one for the whole process so connections
+/// get reused. no overall timeout, a big upload over a slow line may take a while, only a
+/// stalled one is given up on
+pub fn http_agent() -> &'static ureq::Agent {
+    static AGENT: OnceLock<ureq::Agent> = OnceLock::new();
+    AGENT.get_or_init(|| {
+        ureq::AgentBuilder::new()
+            .timeout_connect(Duration::from_secs(10))
+            .timeout_read(Duration::from_secs(120))
+            .timeout_write(Duration::from_secs(120))
+            .build()
+    })
+}
+
+/// http statuses worth another try: timeouts, rate limits, server hiccups and webdav's
+/// 423 for a resource someone else has locked for a moment
+pub fn is_retryable(status: u16) -> bool {
+    matches!(status, 408 | 423 | 429 | 500 | 502 | 503 | 504)
+}
+
+// longest pause between two tries of a remote operation
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// how long to wait before retry number attempt (counting from 0) of a remote operation,
+/// doubling from first
+pub fn backoff(first: Duration, attempt: u32) -> Duration {
+    (first * 2u32.saturating_pow(attempt)).min(MAX_BACKOFF)
+}
+
+/// percent encoding of a url path or query value. only unreserved characters are kept, as
+/// s3 signatures require, and slashes with keep_slash, for paths
+pub fn percent_encode(text: &str, keep_slash: bool) -> String {
+    let mut out = String::with_capacity(text.len());
+    for byte in text.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => out.push(byte as char),
+            b'/' if keep_slash => out.push('/'),
+            _ => out.push_str(&format!("%{:02X}", byte)),
+        }
+    }
+    out
+}
+
+fn not_found(key: &str) -> Error {
+    Error::new(ErrorKind::NotFound, format!("{} not found in backup", key))
+}
+
+/// a folder on a local or mounted disk
+pub struct LocalBackend {
+    root: PathBuf,
+}
+
+static TMP_COUNTER: AtomicUsize = AtomicUsize::new(0);
+
+impl LocalBackend {
+    pub fn new(root: &Path) -> Self {
+        Self { root: root.to_path_buf() }
+    }
+
+    // only the default folder gets created when it's missing, see check
+    fn is_default(&self) -> bool {
+        self.root == crate::backup::default_destination()
+    }
+}
+
+impl StorageBackend for LocalBackend {
+    fn name(&self) -> String {
+        self.root.display().to_string()
+    }
+
+    // only the default folder is created when it's missing, anything else may be the mount
+    // point of an unplugged drive. for the same reason a folder that was used before has to
+    // still contain its marker file
+    fn check(&self, used_before: bool) -> std::io::Result<()> {
+        if !self.root.exists() && !used_before && self.is_default() {
+            fs::create_dir_all(&self.root)?;
+        }
+        if !self.root.is_dir() {
+            return Err(unavailable(self, "folder not found, is the drive connected?"));
+        }
+        probe_destination(self, used_before, LOCAL_MISSING_HINT, || Ok(()))
+    }
+
+    // a missing default folder is fine, check creates it
+    fn check_readonly(&self, used_before: bool) -> std::io::Result<()> {
+        if !self.root.exists() && !used_before && self.is_default() {
+            return Ok(());
+        }
+        if !self.root.is_dir() {
+            return Err(unavailable(self, "folder not found, is the drive connected?"));
+        }
+        inspect_destination(self, used_before, LOCAL_MISSING_HINT)
+    }
+
+    // written to a temp name first. the name is unique so two workers storing the same
+    // content don't write into each other's file
+    fn put(&self, key: &str, data: &[u8]) -> std::io::Result<()> {
+        let path = self.root.join(key);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let tmp_id = TMP_COUNTER.fetch_add(1, Ordering::Relaxed);
+        let tmp_path = path.with_extension(format!("{}-{}.tmp", std::process::id(), tmp_id));
+        fs::write(&tmp_path, data)?;
+        fs::rename(&tmp_path, &path)
+    }
+
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+        fs::read(self.root.join(key)).map_err(|e| match e.kind() {
+            ErrorKind::NotFound => not_found(key),
+            _ => e,
+        })
+    }
+
+    // half written .tmp files are left out
+    fn list(&self, prefix: &str) -> std::io::Result<Vec<String>> {
+        let keys = WalkDir::new(self.root.join(prefix))
+            .into_iter()
+            .filter_map(|e| e.ok())
+            .filter(|e| e.file_type().is_file())
+            .filter(|e| e.path().extension().map(|ext| ext != "tmp").unwrap_or(true))
+            .filter_map(|e| {
+                let relative = e.path().strip_prefix(&self.root).ok()?;
+                Some(relative.to_str()?.to_string())
+            })
+            .collect();
+        Ok(keys)
+    }
+
+    fn delete(&self, key: &str) -> std::io::Result<()> {
+        match fs::remove_file(self.root.join(key)) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => result,
+        }
+    }
+
+    fn stat(&self, key: &str) -> std::io::Result<Option<ObjectStat>> {
+        match fs::metadata(self.root.join(key)) {
+            Ok(meta) => Ok(Some(ObjectStat { size: meta.len() })),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+/// keeps everything in memory, for tests
+#[cfg(test)]
+#[derive(Default)]
+pub struct MemoryBackend {
+    objects: Mutex<std::collections::HashMap<String, Vec<u8>>>,
+}
+
+#[cfg(test)]
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[cfg(test)]
+impl StorageBackend for MemoryBackend {
+    fn name(&self) -> String {
+        String::from("memory")
+    }
+
+    fn check(&self, _used_before: bool) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn check_readonly(&self, _used_before: bool) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn put(&self, key: &str, data: &[u8]) -> std::io::Result<()> {
+        self.objects.lock().unwrap().insert(key.to_string(), data.to_vec());
+        Ok(())
+    }
+
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+        self.objects.lock().unwrap().get(key).cloned().ok_or_else(|| not_found(key))
+    }
+
+    fn list(&self, prefix: &str) -> std::io::Result<Vec<String>> {
+        Ok(self.objects.lock().unwrap().keys().filter(|k| k.starts_with(prefix)).cloned().collect())
+    }
+
+    fn delete(&self, key: &str) -> std::io::Result<()> {
+        self.objects.lock().unwrap().remove(key);
+        Ok(())
+    }
+
+    fn stat(&self, key: &str) -> std::io::Result<Option<ObjectStat>> {
+        Ok(self.objects.lock().unwrap().get(key).map(|data| ObjectStat { size: data.len() as u64 }))
+    }
+}
+
+// replaces the backend of the configured destination with a MemoryBackend in tests
+#[cfg(test)]
+static OVERRIDE: Mutex<Option<Arc<dyn StorageBackend>>> = Mutex::new(None);
+
+#[cfg(test)]
+pub fn set_override(backend: Option<Arc<dyn StorageBackend>>) {
+    *OVERRIDE.lock().unwrap() = backend;
+}
+
+/// whether a destination is reached over the network instead of being a folder
+pub fn is_remote(destination: &Path) -> bool {
+    s3::parse_destination(destination).is_some()
+        || sftp::parse_destination(destination).is_some()
+        || webdav::parse_destination(destination).is_some()
+}
+
+/// the backend for the destination in settings: an s3://bucket/prefix, sftp://user@host/path
+/// or dav(s)://host/path url or a folder. an operation (a backup run, a prune, a restore)
+/// opens it once and hands it down to everything that reads or writes objects
+pub fn open(settings: &BackupSettings) -> Arc<dyn StorageBackend> {
+    #[cfg(test)]
+    if let Some(backend) = OVERRIDE.lock().unwrap().clone() {
+        return backend;
+    }
+    open_with(&settings.destination, settings)
+}
+
+/// like open, but for another destination than the one in settings, e.g. a replica. the
+/// connection settings still come from settings
+pub fn open_with(destination: &Path, settings: &BackupSettings) -> Arc<dyn StorageBackend> {
+    if let Some((bucket, prefix)) = s3::parse_destination(destination) {
+        return Arc::new(S3Backend::new(bucket, prefix, settings.s3.clone()));
+    }
+    if let Some(target) = sftp::parse_destination(destination) {
+        return Arc::new(SftpBackend::new(target, settings.sftp.clone()));
+    }
+    if let Some(base_url) = webdav::parse_destination(destination) {
+        return Arc::new(WebdavBackend::new(base_url, settings.webdav.clone()));
+    }
+    Arc::new(LocalBackend::new(destination))
+}
+
+#[cfg(test)]
+pub mod testing {
+    use super::*;
+    use std::sync::MutexGuard;
+
+    static SERIAL: Mutex<()> = Mutex::new(());
+    static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+    /// a scratch folder with default settings for a destination inside it, and a
+    /// MemoryBackend standing in for that destination. settings, metadata and the backend
+    /// are global, so tests holding a sandbox run one at a time
+    pub struct Sandbox {
+        pub dir: PathBuf,
+        pub backend: Arc<MemoryBackend>,
+        _serial: MutexGuard<'static, ()>,
+    }
+
+    fn base_dir() -> PathBuf {
+        std::env::temp_dir().join(format!("fass-backup-test-{}", std::process::id()))
+    }
+
+    pub fn sandbox() -> Sandbox {
+        // a test that failed while holding the lock leaves nothing behind that matters
+        let serial = SERIAL.lock().unwrap_or_else(|e| e.into_inner());
+        crate::paths::set_config_dir(base_dir().join("config"));
+        let dir = base_dir().join(format!("run-{}", RUNS.fetch_add(1, Ordering::SeqCst)));
+        fs::create_dir_all(dir.join("dest")).unwrap();
+        let settings = BackupSettings { destination: dir.join("dest"), ..Default::default() };
+        settings.save_to_file().unwrap();
+        let backend = Arc::new(MemoryBackend::new());
+        set_override(Some(backend.clone()));
+        Sandbox { dir, backend, _serial: serial }
+    }
+
+    impl Drop for Sandbox {
+        fn drop(&mut self) {
+            set_override(None);
+            // the key is global too, the next test starts locked
+            crate::crypto::forget_key();
+            let _ = fs::remove_dir_all(base_dir());
+        }
+    }
+
+    /// a request as the http stand-ins of the remote backends see it
+    pub struct HttpRequest {
+        pub method: String,
+        // path and query, as sent
+        pub target: String,
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+    }
+
+    impl HttpRequest {
+        pub fn header(&self, name: &str) -> Option<&str> {
+            self.headers.iter().find(|(n, _)| n.eq_ignore_ascii_case(name)).map(|(_, v)| v.as_str())
+        }
+    }
+
+    pub struct HttpResponse {
+        pub status: u16,
+        pub headers: Vec<(String, String)>,
+        pub body: Vec<u8>,
+    }
+
+    impl HttpResponse {
+        pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+            Self { status, headers: Vec::new(), body: body.into() }
+        }
+
+        pub fn with_header(mut self, name: &str, value: &str) -> Self {
+            self.headers.push((name.to_string(), value.to_string()));
+            self
+        }
+    }
+
+    /// answers http requests on a free local port with handler, for as long as the tests
+    /// run. returns the url of the server, e.g. http://127.0.0.1:40123
+    pub fn serve_http(handler: impl Fn(HttpRequest) -> HttpResponse + Send + Sync + 'static) -> String {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let handler = Arc::new(handler);
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let handler = handler.clone();
+                std::thread::spawn(move || serve_connection(stream, handler.as_ref()));
+            }
+        });
+        url
+    }
+
+    // answers requests on one kept alive connection until the client hangs up
+    fn serve_connection(stream: std::net::TcpStream, handler: &dyn Fn(HttpRequest) -> HttpResponse) {
+        use std::io::{BufRead, BufReader, Read, Write};
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        loop {
+            let mut line = String::new();
+            if reader.read_line(&mut line).unwrap_or(0) == 0 {
+                return;
+            }
+            let mut words = line.split_whitespace();
+            let method = words.next().unwrap_or_default().to_string();
+            let target = words.next().unwrap_or_default().to_string();
+            let mut headers = Vec::new();
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                match header.trim_end().split_once(':') {
+                    Some((name, value)) => headers.push((name.to_string(), value.trim().to_string())),
+                    None => break,
+                }
+            }
+            let mut request = HttpRequest { method, target, headers, body: Vec::new() };
+            let length = request.header("Content-Length").map_or(0, |l| l.parse().unwrap());
+            request.body = vec![0; length];
+            reader.read_exact(&mut request.body).unwrap();
+
+            let response = handler(request);
+            let reason = match response.status {
+                200 => "OK",
+                201 => "Created",
+                204 => "No Content",
+                207 => "Multi-Status",
+                404 => "Not Found",
+                412 => "Precondition Failed",
+                _ => "Error",
+            };
+            let mut head = format!("HTTP/1.1 {} {}\r\nContent-Length: {}\r\n", response.status, reason, response.body.len());
+            for (name, value) in &response.headers {
+                head.push_str(&format!("{}: {}\r\n", name, value));
+            }
+            head.push_str("\r\n");
+            writer.write_all(head.as_bytes()).unwrap();
+            writer.write_all(&response.body).unwrap();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn local_objects_round_trip() {
+        let sandbox = testing::sandbox();
+        let backend = LocalBackend::new(&sandbox.dir.join("dest"));
+
+        backend.put("objects/ab/abc", b"hello").unwrap();
+        backend.put("objects/ab/abc", b"hello again").unwrap();
+        backend.put("objects/cd/cde.zst", b"other").unwrap();
+        // what an interrupted put leaves behind
+        fs::write(sandbox.dir.join("dest/objects/ab/abd.1-2.tmp"), b"half").unwrap();
+
+        assert_eq!(backend.get("objects/ab/abc").unwrap(), b"hello again");
+        assert_eq!(backend.stat("objects/ab/abc").unwrap(), Some(ObjectStat { size: 11 }));
+        let mut keys = backend.list("objects/").unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["objects/ab/abc", "objects/cd/cde.zst"]);
+        assert_eq!(backend.list("objects/ab").unwrap(), vec!["objects/ab/abc"]);
+        assert!(backend.list("nothing/").unwrap().is_empty());
+
+        backend.delete("objects/ab/abc").unwrap();
+        backend.delete("objects/ab/abc").unwrap();
+        assert_eq!(backend.stat("objects/ab/abc").unwrap(), None);
+        assert_eq!(backend.get("objects/ab/abc").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(backend.list("objects/").unwrap(), vec!["objects/cd/cde.zst"]);
+    }
+
+    #[test]
+    fn local_destinations_are_checked_for_their_folder_and_marker() {
+        let sandbox = testing::sandbox();
+        let unplugged = LocalBackend::new(&sandbox.dir.join("usb"));
+        for result in [unplugged.check(false), unplugged.check_readonly(false)] {
+            assert_eq!(result.unwrap_err().kind(), ErrorKind::NotFound);
+        }
+        // not the default folder, so it isn't created
+        assert!(!sandbox.dir.join("usb").exists());
+
+        let folder = sandbox.dir.join("dest");
+        let backend = LocalBackend::new(&folder);
+        backend.check_readonly(false).unwrap();
+        assert_eq!(fs::read_dir(&folder).unwrap().count(), 0);
+        assert!(backend.check_readonly(true).is_err());
+
+        backend.check(false).unwrap();
+        assert_eq!(backend.list("").unwrap(), vec![DESTINATION_MARKER]);
+        backend.check(true).unwrap();
+        backend.check_readonly(true).unwrap();
+
+        // e.g. the drive's mount point without the drive
+        fs::remove_file(folder.join(DESTINATION_MARKER)).unwrap();
+        let error = backend.check(true).unwrap_err();
+        assert!(error.to_string().contains("is the drive mounted?"), "{}", error);
+        assert!(backend.check_readonly(true).is_err());
+        assert_eq!(fs::read_dir(&folder).unwrap().count(), 0);
+    }
+}
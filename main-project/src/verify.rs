@@ -0,0 +1,320 @@
+// checks that the backup can still be restored. every object the metadata references must
+// exist, and a sample of them is read back, decoded and rehashed against the hash recorded
+// at backup time. objects nothing references are reported, never deleted, pruning does that
+
+use serde::{Serialize, Deserialize};
+use chacha20poly1305::aead::{OsRng, rand_core::RngCore};
+use rayon::prelude::*;
+use sha2::{Sha256, Digest};
+use std::collections::{HashMap, HashSet};
+use std::io::{ErrorKind, Read};
+use std::path::PathBuf;
+use crate::backup::{self, BackupMetadata, BackupSettings, Codec};
+use crate::storage::{self, StorageBackend};
+
+/// scheduled verify runs of the daemon. off by default, reading objects back is slow on
+/// remote destinations
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct VerifySettings {
+    pub enabled: bool,
+    pub interval_hours: u64,
+    // share of the stored objects reread per run, so every object gets checked over a few runs
+    pub sample_percent: u8,
+}
+
+impl Default for VerifySettings {
+    fn default() -> Self {
+        Self {
+            enabled: false,
+            interval_hours: 168,
+            sample_percent: 10,
+        }
+    }
+}
+
+impl VerifySettings {
+    pub fn is_due(&self, last_verify: i64) -> bool {
+        let interval = self.interval_hours.max(1) as i64 * 3600;
+        self.enabled && chrono::Local::now().timestamp() - last_verify >= interval
+    }
+}
+
+/// an object that is missing or doesn't match its hash, with the file versions that need it
+#[derive(Debug, Clone)]
+pub struct DamagedObject {
+    pub key: String,
+    pub reason: String,
+    pub files: Vec<(PathBuf, String)>,
+}
+
+/// what a verify run found
+#[derive(Debug, Clone, Default)]
+pub struct VerifyReport {
+    pub total: usize,
+    pub checked: usize,
+    pub checked_bytes: u64,
+    pub missing: Vec<DamagedObject>,
+    pub corrupted: Vec<DamagedObject>,
+    // objects that couldn't be read for reasons that say nothing about the data, e.g. the
+    // network went away
+    pub unreadable: Vec<DamagedObject>,
+    pub orphaned: Vec<String>,
+}
+
+impl VerifyReport {
+    pub fn is_healthy(&self) -> bool {
+        self.missing.is_empty() && self.corrupted.is_empty() && self.unreadable.is_empty()
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "Checked {} of {} object(s) ({:.1} MiB): {} missing, {} corrupted, {} unreadable, {} orphaned",
+            self.checked,
+            self.total,
+            self.checked_bytes as f64 / (1024.0 * 1024.0),
+            self.missing.len(),
+            self.corrupted.len(),
+            self.unreadable.len(),
+            self.orphaned.len()
+        )
+    }
+
+    //one line per problem, for logs and the GUI
+    pub fn problems(&self) -> Vec<String> {
+        let damaged = [("Missing", &self.missing), ("Corrupted", &self.corrupted), ("Unreadable", &self.unreadable)];
+        let mut lines = Vec::new();
+        for (label, objects) in damaged {
+            for object in objects {
+                let files: Vec<String> = object
+                    .files
+                    .iter()
+                    .map(|(path, snapshot)| format!("{} @ {}", path.display(), snapshot))
+                    .collect();
+                lines.push(format!("{} {}: {} (needed by {})", label, object.key, object.reason, files.join(", ")));
+            }
+        }
+        lines.extend(self.orphaned.iter().map(|key| format!("Orphaned {}", key)));
+        lines
+    }
+}
+
+// what the metadata says an object should contain
+struct ExpectedObject {
+    hash: String,
+    size: u64,
+    codec: Codec,
+    encrypted: bool,
+    files: Vec<(PathBuf, String)>,
+}
+
+//every object referenced by some version, by key
+fn expected_objects(metadata: &BackupMetadata) -> std::io::Result<HashMap<String, ExpectedObject>> {
+    let mut expected: HashMap<String, ExpectedObject> = HashMap::new();
+    for info in metadata.files.values() {
+        for version in &info.versions {
+            let parts: Vec<(String, &str, u64, Codec, bool)> = if version.chunks.is_empty() {
+                let key = version.backup_path.to_string_lossy().into_owned();
+                vec![(key, &version.hash, version.size, version.codec, version.encrypted)]
+            } else {
+                let mut parts = Vec::new();
+                for c in &version.chunks {
+                    parts.push((backup::object_key(&c.hash, c.codec, c.encrypted)?, c.hash.as_str(), c.size, c.codec, c.encrypted));
+                }
+                parts
+            };
+            for (key, hash, size, codec, encrypted) in parts {
+                expected
+                    .entry(key)
+                    .or_insert_with(|| ExpectedObject { hash: hash.to_string(), size, codec, encrypted, files: Vec::new() })
+                    .files
+                    .push((info.original_path.clone(), version.snapshot_id.clone()));
+            }
+        }
+    }
+    Ok(expected)
+}
+
+//picks count keys at random, a partial fisher-yates shuffle
+fn sample(mut keys: Vec<String>, count: usize) -> Vec<String> {
+    let count = count.min(keys.len());
+    for i in 0..count {
+        let j = i + (OsRng.next_u64() % (keys.len() - i) as u64) as usize;
+        keys.swap(i, j);
+    }
+    keys.truncate(count);
+    keys
+}
+
+// how reading one object back went
+enum Check {
+    Ok(u64),
+    Missing,
+    Corrupted(String),
+    Unreadable(String),
+}
+
+//reads an object back and rehashes its decoded contents
+fn check_object(storage: &dyn StorageBackend, key: &str, expected: &ExpectedObject) -> Check {
+    let data = match storage.get(key) {
+        Ok(data) => data,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Check::Missing,
+        Err(e) => return Check::Unreadable(e.to_string()),
+    };
+    let stored_bytes = data.len() as u64;
+    let mut reader = match backup::decode_object(data, expected.codec, expected.encrypted) {
+        Ok(reader) => reader,
+        Err(e) => return Check::Corrupted(e.to_string()),
+    };
+    let mut hasher = Sha256::new();
+    let mut size = 0u64;
+    let mut buffer = vec![0u8; 64 * 1024];
+    loop {
+        match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => {
+                hasher.update(&buffer[..n]);
+                size += n as u64;
+            }
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Check::Corrupted(e.to_string()),
+        }
+    }
+    if size != expected.size {
+        return Check::Corrupted(format!("{} bytes instead of {}", size, expected.size));
+    }
+    if format!("{:x}", hasher.finalize()) != expected.hash {
+        return Check::Corrupted("contents don't match the recorded hash".to_string());
+    }
+    Check::Ok(stored_bytes)
+}
+
+/// checks that every referenced object exists and rehashes sample_percent of them
+pub fn verify(
+    storage: &dyn StorageBackend,
+    metadata: &BackupMetadata,
+    settings: &BackupSettings,
+    sample_percent: u8,
+) -> std::io::Result<VerifyReport> {
+    // encrypted object names depend on the key, without it every object would look orphaned
+    let needs_key = metadata
+        .files
+        .values()
+        .flat_map(|f| f.versions.iter())
+        .any(|v| v.encrypted || v.chunks.iter().any(|c| c.encrypted));
+    if needs_key && !crate::crypto::is_unlocked() {
+        return Err(crate::crypto::locked("Backup repository is locked, can't verify encrypted objects"));
+    }
+
+    let mut expected = expected_objects(metadata)?;
+    let stored: HashSet<String> = backup::stored_objects(storage)?.into_iter().collect();
+    let mut report = VerifyReport { total: expected.len(), ..Default::default() };
+
+    let mut orphaned: Vec<String> = stored.iter().filter(|k| !expected.contains_key(*k)).cloned().collect();
+    orphaned.sort();
+    report.orphaned = orphaned;
+
+    let mut present = Vec::new();
+    let mut missing: Vec<String> = Vec::new();
+    for key in expected.keys() {
+        if stored.contains(key) {
+            present.push(key.clone());
+        } else {
+            missing.push(key.clone());
+        }
+    }
+
+    let count = (present.len() * sample_percent.min(100) as usize).div_ceil(100);
+    let sampled = sample(present, count);
+    let results: Vec<(String, Check)> = backup::worker_pool(settings.io_threads).install(|| {
+        sampled
+            .into_par_iter()
+            .map(|key| {
+                let check = check_object(storage, &key, &expected[&key]);
+                (key, check)
+            })
+            .collect()
+    });
+
+    let mut damaged = |key: String, reason: String| DamagedObject {
+        files: expected.remove(&key).map(|e| e.files).unwrap_or_default(),
+        key,
+        reason,
+    };
+    for key in missing {
+        report.missing.push(damaged(key, "not in the backup".to_string()));
+    }
+    for (key, check) in results {
+        report.checked += 1;
+        match check {
+            Check::Ok(bytes) => report.checked_bytes += bytes,
+            // listed a moment ago, gone now
+            Check::Missing => report.missing.push(damaged(key, "not in the backup".to_string())),
+            Check::Corrupted(reason) => report.corrupted.push(damaged(key, reason)),
+            Check::Unreadable(reason) => report.unreadable.push(damaged(key, reason)),
+        }
+    }
+    for list in [&mut report.missing, &mut report.corrupted, &mut report.unreadable] {
+        list.sort_by(|a, b| a.key.cmp(&b.key));
+    }
+    Ok(report)
+}
+
+/// verifies the backup and remembers when, for the daemon's schedule
+pub fn verify_now(sample_percent: u8) -> std::io::Result<VerifyReport> {
+    // a backup storing objects it hasn't recorded yet would make them look orphaned
+    let _lock = crate::paths::lock_repository()?;
+    let settings = BackupSettings::load_from_file().unwrap_or_default();
+    let mut metadata = BackupMetadata::load_from_file()?;
+    let storage = storage::open(&settings);
+    let report = verify(storage.as_ref(), &metadata, &settings, sample_percent)?;
+    metadata.last_verify = chrono::Local::now().timestamp();
+    metadata.save(storage.as_ref(), &settings)?;
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn damaged_and_orphaned_objects_are_reported() {
+        let sandbox = storage::testing::sandbox();
+        let folder = sandbox.dir.join("docs");
+        fs::create_dir_all(&folder).unwrap();
+        for (name, contents) in [("a.txt", "first file"), ("b.txt", "second file"), ("c.txt", "third file")] {
+            fs::write(folder.join(name), contents).unwrap();
+        }
+        backup::backup(&folder).unwrap();
+        let metadata = BackupMetadata::load_from_file().unwrap();
+        let settings = BackupSettings::load_from_file().unwrap();
+        let key_of = |name: &str| metadata.files[&folder.join(name)].versions[0].object_keys().unwrap().remove(0);
+
+        let healthy = verify(sandbox.backend.as_ref(), &metadata, &settings, 100).unwrap();
+        assert!(healthy.is_healthy() && healthy.orphaned.is_empty());
+        assert_eq!((healthy.total, healthy.checked), (3, 3));
+
+        sandbox.backend.put(&key_of("a.txt"), b"flipped bits").unwrap();
+        sandbox.backend.delete(&key_of("b.txt")).unwrap();
+        sandbox.backend.put("objects/ff/ff00", b"left by an interrupted run").unwrap();
+        let report = verify(sandbox.backend.as_ref(), &metadata, &settings, 100).unwrap();
+
+        assert!(!report.is_healthy());
+        assert_eq!(report.checked, 2);
+        let keys = |objects: &[DamagedObject]| objects.iter().map(|o| o.key.clone()).collect::<Vec<_>>();
+        assert_eq!(keys(&report.corrupted), vec![key_of("a.txt")]);
+        assert_eq!(keys(&report.missing), vec![key_of("b.txt")]);
+        assert!(report.unreadable.is_empty());
+        assert_eq!(report.orphaned, vec!["objects/ff/ff00"]);
+        let snapshot = metadata.snapshots[0].id.clone();
+        assert_eq!(report.corrupted[0].files, vec![(folder.join("a.txt"), snapshot)]);
+        assert_eq!(report.problems().len(), 3);
+        // reported, never deleted
+        assert!(sandbox.backend.stat("objects/ff/ff00").unwrap().is_some());
+
+        // a sample only rereads that share of the objects, missing ones are found either way
+        let sampled = verify(sandbox.backend.as_ref(), &metadata, &settings, 50).unwrap();
+        assert_eq!((sampled.checked, sampled.missing.len()), (1, 1));
+    }
+}
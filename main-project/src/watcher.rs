@@ -0,0 +1,185 @@
+// watches the backup folders and collects the paths that changed, so the daemon can
+// back up just those instead of rehashing every tracked file
+
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use std::collections::HashSet;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
+
+// a batch is handed out once no new event came in for this long
+const DEBOUNCE: Duration = Duration::from_secs(2);
+// but never later than this after the first event, so a file that is written to
+// constantly still gets backed up
+const MAX_DELAY: Duration = Duration::from_secs(30);
+const POLL_STEP: Duration = Duration::from_millis(200);
+
+//files the app writes itself, changes to them must not trigger another backup
+fn is_own_file(path: &Path, destination: &Path) -> bool {
+    path.starts_with(destination) || crate::paths::is_app_file(path)
+}
+
+// the paths an event is about, none for events that don't change anything
+fn changed_paths(event: Event, roots: &[PathBuf], destination: &Path) -> Vec<PathBuf> {
+    if matches!(event.kind, EventKind::Access(_)) {
+        return Vec::new();
+    }
+    // the kernel queue overflowed, events were lost so the whole folders need a look
+    let paths = if event.need_rescan() { roots.to_vec() } else { event.paths };
+    paths.into_iter().filter(|p| !is_own_file(p, destination)).collect()
+}
+
+// collects changed paths and decides when a burst of them has settled down. kept apart from
+// the notify side so the timing can be checked without a real watcher
+#[derive(Default)]
+struct Batch {
+    pending: HashSet<PathBuf>,
+    first_event: Option<Instant>,
+    last_event: Option<Instant>,
+}
+
+impl Batch {
+    fn add(&mut self, paths: Vec<PathBuf>, now: Instant) {
+        if paths.is_empty() {
+            return;
+        }
+        self.pending.extend(paths);
+        self.first_event.get_or_insert(now);
+        self.last_event = Some(now);
+    }
+
+    // the collected paths once no event came in for DEBOUNCE, or MAX_DELAY after the first
+    fn take_ready(&mut self, now: Instant) -> Option<Vec<PathBuf>> {
+        let (first, last) = (self.first_event?, self.last_event?);
+        if now.duration_since(last) < DEBOUNCE && now.duration_since(first) < MAX_DELAY {
+            return None;
+        }
+        self.first_event = None;
+        self.last_event = None;
+        Some(self.pending.drain().collect())
+    }
+}
+
+pub struct ChangeWatcher {
+    // events stop once this is dropped
+    _watcher: RecommendedWatcher,
+    events: Receiver<notify::Result<Event>>,
+    roots: Vec<PathBuf>,
+    // the backup destination, in case it is inside a watched folder
+    destination: PathBuf,
+    batch: Batch,
+}
+
+impl ChangeWatcher {
+    /// starts watching every root recursively. roots that can't be watched (e.g. an
+    /// unmounted drive) are reported and left to the periodic scan
+    pub fn new(roots: &[PathBuf], destination: &Path) -> notify::Result<Self> {
+        let (tx, events) = mpsc::channel();
+        let mut watcher = notify::recommended_watcher(tx)?;
+        for root in roots {
+            if let Err(e) = watcher.watch(root, RecursiveMode::Recursive) {
+                println!("Can't watch {}: {}", root.display(), e);
+            }
+        }
+        Ok(Self {
+            _watcher: watcher,
+            events,
+            roots: roots.to_vec(),
+            destination: destination.to_path_buf(),
+            batch: Batch::default(),
+        })
+    }
+
+    pub fn roots(&self) -> &[PathBuf] {
+        &self.roots
+    }
+
+    /// waits up to timeout for changes and returns the changed paths once a burst of
+    /// events has settled down. None means nothing is ready yet
+    pub fn next_batch(&mut self, timeout: Duration) -> Option<Vec<PathBuf>> {
+        let deadline = Instant::now() + timeout;
+        loop {
+            if let Some(paths) = self.batch.take_ready(Instant::now()) {
+                return Some(paths);
+            }
+
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return None;
+            }
+            match self.events.recv_timeout(remaining.min(POLL_STEP)) {
+                Ok(Ok(event)) => {
+                    let paths = changed_paths(event, &self.roots, &self.destination);
+                    self.batch.add(paths, Instant::now());
+                }
+                Ok(Err(e)) => println!("Watch error: {}", e),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return None,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{AccessKind, Flag, ModifyKind};
+
+    fn sorted(mut paths: Vec<PathBuf>) -> Vec<PathBuf> {
+        paths.sort();
+        paths
+    }
+
+    #[test]
+    fn a_burst_of_events_becomes_one_batch_once_it_settles() {
+        let start = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+        let mut batch = Batch::default();
+        assert_eq!(batch.take_ready(at(0)), None);
+
+        batch.add(vec![PathBuf::from("/home/a/notes.txt")], at(0));
+        batch.add(vec![PathBuf::from("/home/a/todo.txt")], at(1000));
+        // the same file saved again only counts once
+        batch.add(vec![PathBuf::from("/home/a/notes.txt")], at(1500));
+        batch.add(Vec::new(), at(2500));
+        assert_eq!(batch.take_ready(at(3000)), None);
+        assert_eq!(
+            batch.take_ready(at(3500)).map(sorted),
+            Some(vec![PathBuf::from("/home/a/notes.txt"), PathBuf::from("/home/a/todo.txt")])
+        );
+        assert_eq!(batch.take_ready(at(10_000)), None);
+    }
+
+    #[test]
+    fn a_file_that_keeps_changing_is_still_handed_out() {
+        let start = Instant::now();
+        let mut batch = Batch::default();
+        let log = PathBuf::from("/home/a/build.log");
+        for second in 0..MAX_DELAY.as_secs() {
+            batch.add(vec![log.clone()], start + Duration::from_secs(second));
+            assert_eq!(batch.take_ready(start + Duration::from_secs(second)), None);
+        }
+        assert_eq!(batch.take_ready(start + MAX_DELAY), Some(vec![log.clone()]));
+
+        // the next batch starts its own clock
+        batch.add(vec![log.clone()], start + MAX_DELAY);
+        assert_eq!(batch.take_ready(start + MAX_DELAY + Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn reads_and_the_apps_own_files_are_left_out() {
+        let roots = vec![PathBuf::from("/home/a"), PathBuf::from("/data")];
+        let destination = Path::new("/home/a/Backup");
+        let event = |kind: EventKind, path: &str| Event::new(kind).add_path(PathBuf::from(path));
+
+        let read = event(EventKind::Access(AccessKind::Any), "/home/a/notes.txt");
+        assert!(changed_paths(read, &roots, destination).is_empty());
+        let stored = event(EventKind::Modify(ModifyKind::Any), "/home/a/Backup/objects/ab/abc");
+        assert!(changed_paths(stored, &roots, destination).is_empty());
+        let edited = event(EventKind::Modify(ModifyKind::Any), "/home/a/notes.txt");
+        assert_eq!(changed_paths(edited, &roots, destination), vec![PathBuf::from("/home/a/notes.txt")]);
+
+        let overflow = Event::new(EventKind::Other).set_flag(Flag::Rescan);
+        assert_eq!(changed_paths(overflow, &roots, destination), roots);
+    }
+}
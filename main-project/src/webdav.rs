@@ -0,0 +1,667 @@
+// a folder on a webdav share like nextcloud as the backup destination. destinations are
+// written dav://host/path for http and davs://host/path for https, e.g.
+// davs://cloud.example.com/remote.php/dav/files/alice/backup. the login comes from the
+// webdav part of the settings and is sent as basic auth, so use davs for anything that
+// leaves the house
+
+use serde::{Serialize, Deserialize};
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use quick_xml::events::Event;
+use quick_xml::Reader;
+use std::collections::{HashMap, HashSet};
+use std::io::{Error, ErrorKind, Read};
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::Duration;
+use crate::storage::{self, percent_encode, ObjectStat, StorageBackend};
+
+const SCHEMES: [(&str, &str); 2] = [("dav://", "http"), ("davs://", "https")];
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?><d:propfind xmlns:d="DAV:"><d:prop><d:resourcetype/><d:getetag/><d:getcontentlength/></d:prop></d:propfind>"#;
+// why a destination that was used before has no marker
+const MISSING_HINT: &str = "backup data not found, is the path right?";
+
+/// how to log in to dav:// and davs:// destinations
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct WebdavSettings {
+    pub username: String,
+    // empty falls back to the FASS_WEBDAV_PASSWORD environment variable. nextcloud app
+    // passwords work here
+    pub password: String,
+    pub max_retries: u32,
+}
+
+impl Default for WebdavSettings {
+    fn default() -> Self {
+        Self { username: String::new(), password: String::new(), max_retries: 5 }
+    }
+}
+
+/// the http(s) url of the folder a dav:// or davs:// destination points to, None for
+/// anything else. never ends with a slash
+pub fn parse_destination(destination: &Path) -> Option<String> {
+    let text = destination.to_str()?;
+    let (rest, scheme) = SCHEMES.iter().find_map(|(prefix, scheme)| Some((text.strip_prefix(prefix)?, scheme)))?;
+    let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+    if host.is_empty() {
+        return None;
+    }
+    let path = path.trim_matches('/');
+    if path.is_empty() {
+        return Some(format!("{}://{}", scheme, host));
+    }
+    Some(format!("{}://{}/{}", scheme, host, percent_encode(path, true)))
+}
+
+//%xx escapes in an href back to text
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%'
+            && let Some(byte) = text.get(i + 1..i + 3).and_then(|hex| u8::from_str_radix(hex, 16).ok())
+        {
+            out.push(byte);
+            i += 3;
+            continue;
+        }
+        out.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&out).into_owned()
+}
+
+/// one entry of a propfind answer
+#[derive(Debug, Clone, Default)]
+struct DavEntry {
+    // decoded path as the server sent it, without the host
+    path: String,
+    etag: Option<String>,
+    is_collection: bool,
+    // None if the server didn't say
+    size: Option<u64>,
+}
+
+// an etag without the W/ of weak ones, some servers mark the same etag weak in one answer
+// and not in another
+fn strong_etag(etag: &str) -> &str {
+    etag.trim().trim_start_matches("W/")
+}
+
+//the entries of a propfind multistatus answer
+fn parse_multistatus(body: &str) -> Vec<DavEntry> {
+    let mut reader = Reader::from_str(body);
+    let mut entries = Vec::new();
+    let mut entry = DavEntry::default();
+    let mut element = Vec::new();
+    loop {
+        match reader.read_event() {
+            Ok(Event::Start(e)) => {
+                element = e.local_name().as_ref().to_vec();
+                if element == b"response" {
+                    entry = DavEntry::default();
+                } else if element == b"collection" {
+                    entry.is_collection = true;
+                }
+            }
+            Ok(Event::Empty(e)) if e.local_name().as_ref() == b"collection" => entry.is_collection = true,
+            Ok(Event::Text(t)) => {
+                let Ok(text) = t.unescape() else { continue };
+                match element.as_slice() {
+                    b"href" => {
+                        // some servers send full urls, others just the path
+                        let href = text.trim();
+                        let path = match href.split_once("://") {
+                            Some((_, rest)) => rest.find('/').map(|i| &rest[i..]).unwrap_or("/"),
+                            None => href,
+                        };
+                        entry.path = percent_decode(path);
+                    }
+                    b"getetag" => entry.etag = Some(text.trim().to_string()),
+                    b"getcontentlength" => entry.size = text.trim().parse().ok(),
+                    _ => {}
+                }
+            }
+            Ok(Event::End(e)) => {
+                if e.local_name().as_ref() == b"response" {
+                    entries.push(std::mem::take(&mut entry));
+                }
+                element.clear();
+            }
+            Ok(Event::Eof) | Err(_) => break,
+            _ => {}
+        }
+    }
+    entries
+}
+
+// what a folder contained when it had a certain etag. the server gives a folder a new etag
+// when its contents change, so a listing is only fetched again for folders that changed.
+// etags can repeat though (apache's are mtime based, two changes in one second look the
+// same), so this backend's own uploads and deletes drop the folder's entry. keyed by folder url
+type Listing = (String, Vec<DavEntry>);
+static LISTINGS: Mutex<Option<HashMap<String, Listing>>> = Mutex::new(None);
+// folders known to exist, by url, so uploads don't create them again
+static COLLECTIONS: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+pub struct WebdavBackend {
+    // url of the destination folder, without a trailing slash
+    base_url: String,
+    settings: WebdavSettings,
+}
+
+impl WebdavBackend {
+    pub fn new(base_url: String, settings: WebdavSettings) -> Self {
+        Self { base_url, settings }
+    }
+
+    fn url_of(&self, key: &str) -> String {
+        format!("{}/{}", self.base_url, percent_encode(key, true))
+    }
+
+    // collections are addressed with a trailing slash
+    fn folder_url(&self, folder: &str) -> String {
+        let folder = folder.trim_matches('/');
+        if folder.is_empty() {
+            return format!("{}/", self.base_url);
+        }
+        format!("{}/{}/", self.base_url, percent_encode(folder, true))
+    }
+
+    // the decoded path of the destination folder, to turn hrefs back into keys
+    fn base_path(&self) -> String {
+        let rest = self.base_url.split_once("://").map(|(_, rest)| rest).unwrap_or(&self.base_url);
+        let path = rest.find('/').map(|i| &rest[i..]).unwrap_or("");
+        format!("{}/", percent_decode(path))
+    }
+
+    fn authorization(&self) -> Option<String> {
+        if self.settings.username.is_empty() {
+            return None;
+        }
+        let password = if self.settings.password.is_empty() {
+            std::env::var("FASS_WEBDAV_PASSWORD").unwrap_or_default()
+        } else {
+            self.settings.password.clone()
+        };
+        Some(format!("Basic {}", BASE64.encode(format!("{}:{}", self.settings.username, password))))
+    }
+
+    /// sends one request and returns the response for any 2xx status. statuses the caller
+    /// wants to handle itself are returned as they are, 404 becomes a NotFound error.
+    /// network errors and server side errors are retried with growing pauses
+    fn send(
+        &self,
+        method: &str,
+        url: &str,
+        headers: &[(&str, &str)],
+        body: &[u8],
+        accept: &[u16],
+    ) -> std::io::Result<ureq::Response> {
+        let authorization = self.authorization();
+        let mut attempt = 0;
+        loop {
+            let mut request = storage::http_agent().request(method, url);
+            for (name, value) in headers {
+                request = request.set(name, value);
+            }
+            if let Some(authorization) = &authorization {
+                request = request.set("Authorization", authorization);
+            }
+            let result = if body.is_empty() && method != "PUT" { request.call() } else { request.send_bytes(body) };
+
+            let error = match result {
+                Ok(response) => return Ok(response),
+                Err(ureq::Error::Status(status, response)) if accept.contains(&status) => return Ok(response),
+                Err(ureq::Error::Status(404, _)) => {
+                    return Err(Error::new(ErrorKind::NotFound, format!("{} not found", percent_decode(url))));
+                }
+                Err(ureq::Error::Status(status, response)) => {
+                    let text = format!("WebDAV {} {} failed: {} {}", method, percent_decode(url), status, response.status_text());
+                    if !storage::is_retryable(status) {
+                        let kind = if matches!(status, 401 | 403) { ErrorKind::PermissionDenied } else { ErrorKind::Other };
+                        return Err(Error::new(kind, text));
+                    }
+                    text
+                }
+                Err(e) => format!("WebDAV {} {} failed: {}", method, percent_decode(url), e),
+            };
+
+            if attempt >= self.settings.max_retries {
+                return Err(Error::other(error));
+            }
+            let pause = storage::backoff(Duration::from_millis(500), attempt);
+            println!("{}, retrying in {:.1}s", error, pause.as_secs_f64());
+            std::thread::sleep(pause);
+            attempt += 1;
+        }
+    }
+
+    fn propfind(&self, url: &str, depth: &str) -> std::io::Result<Vec<DavEntry>> {
+        let headers = [("Depth", depth), ("Content-Type", "application/xml; charset=utf-8")];
+        let response = self.send("PROPFIND", url, &headers, PROPFIND_BODY.as_bytes(), &[])?;
+        Ok(parse_multistatus(&response.into_string()?))
+    }
+
+    // creates the folder for a key and the ones above it up to the destination folder,
+    // which check() creates
+    fn ensure_collection(&self, folder: &str) -> std::io::Result<()> {
+        if folder.is_empty() {
+            return Ok(());
+        }
+        let url = self.folder_url(folder);
+        if COLLECTIONS.lock().unwrap().get_or_insert_with(HashSet::new).contains(&url) {
+            return Ok(());
+        }
+        if let Some((parent, _)) = folder.rsplit_once('/') {
+            self.ensure_collection(parent)?;
+        }
+        // 405 means it exists already
+        self.send("MKCOL", &url, &[], &[], &[405])?;
+        self.forget_listing(folder.rsplit_once('/').map_or("", |(parent, _)| parent));
+        COLLECTIONS.lock().unwrap().get_or_insert_with(HashSet::new).insert(url);
+        Ok(())
+    }
+
+    // the cached listing of a folder is stale once this backend changed what's in it
+    fn forget_listing(&self, folder: &str) {
+        if let Some(listings) = LISTINGS.lock().unwrap().as_mut() {
+            listings.remove(&self.folder_url(folder));
+        }
+    }
+
+    // every file below the folder at url, using the cached listing of folders whose etag
+    // is still the same
+    fn list_collection(&self, url: &str, etag: Option<&str>, files: &mut Vec<DavEntry>) -> std::io::Result<()> {
+        let cached = LISTINGS
+            .lock()
+            .unwrap()
+            .get_or_insert_with(HashMap::new)
+            .get(url)
+            .filter(|(known, _)| Some(known.as_str()) == etag)
+            .map(|(_, entries)| entries.clone());
+        let entries = match cached {
+            Some(entries) => entries,
+            None => {
+                let mut entries = match self.propfind(url, "1") {
+                    Ok(entries) => entries,
+                    Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+                    Err(e) => return Err(e),
+                };
+                // the folder itself comes first, the rest is what's in it
+                let own_path = self.path_of_url(url);
+                let own = entries.iter().position(|e| e.path.trim_end_matches('/') == own_path.trim_end_matches('/'));
+                let own_etag = own.map(|index| entries.remove(index)).and_then(|e| e.etag);
+                if let Some(own_etag) = own_etag {
+                    LISTINGS.lock().unwrap().get_or_insert_with(HashMap::new).insert(url.to_string(), (own_etag, entries.clone()));
+                }
+                entries
+            }
+        };
+
+        for entry in entries {
+            if entry.is_collection {
+                let child_url = self.folder_url(&self.key_of(&entry.path));
+                self.list_collection(&child_url, entry.etag.as_deref(), files)?;
+            } else {
+                files.push(entry);
+            }
+        }
+        Ok(())
+    }
+
+    fn path_of_url(&self, url: &str) -> String {
+        let key = url.strip_prefix(&self.base_url).unwrap_or_default().trim_start_matches('/');
+        format!("{}{}", self.base_path(), percent_decode(key))
+    }
+
+    fn key_of(&self, path: &str) -> String {
+        path.strip_prefix(&self.base_path()).unwrap_or(path).to_string()
+    }
+}
+
+impl StorageBackend for WebdavBackend {
+    fn name(&self) -> String {
+        percent_decode(&self.base_url)
+    }
+
+    // the destination folder itself is created on first use, its parent has to exist. after
+    // that it has to keep its marker
+    fn check(&self, used_before: bool) -> std::io::Result<()> {
+        storage::probe_destination(self, used_before, MISSING_HINT, || {
+            let response = self
+                .send("MKCOL", &self.folder_url(""), &[], &[], &[405, 409])
+                .map_err(|e| storage::unavailable(self, &e.to_string()))?;
+            if response.status() == 409 {
+                return Err(storage::unavailable(self, "the folder above it doesn't exist"));
+            }
+            Ok(())
+        })
+    }
+
+    // a folder that doesn't exist yet is fine, check creates it
+    fn check_readonly(&self, used_before: bool) -> std::io::Result<()> {
+        storage::inspect_destination(self, used_before, MISSING_HINT)
+    }
+
+    // webdav servers write uploads to a temp file and only show them once complete, so a
+    // reader never sees half of one. big objects are sent as they are, in one request. a
+    // proxy or a full disk can still cut an upload short without an error, so the size the
+    // server lists afterwards is checked, and its etag against the one the PUT answered with
+    fn put(&self, key: &str, data: &[u8]) -> std::io::Result<()> {
+        if let Some((folder, _)) = key.rsplit_once('/') {
+            self.ensure_collection(folder)?;
+        }
+        let url = self.url_of(key);
+        let response = self.send("PUT", &url, &[], data, &[409])?;
+        self.forget_listing(key.rsplit_once('/').map_or("", |(folder, _)| folder));
+        if response.status() == 409 {
+            // the folder went away since it was created, e.g. removed by hand
+            COLLECTIONS.lock().unwrap().take();
+            return Err(Error::other(format!("WebDAV PUT {} failed: the folder for it is missing", key)));
+        }
+        let sent_etag = response.header("ETag").map(strong_etag);
+
+        let stored = self.propfind(&url, "0")?.into_iter().next().unwrap_or_default();
+        if let Some(size) = stored.size
+            && size != data.len() as u64
+        {
+            return Err(Error::other(format!(
+                "WebDAV PUT {} failed: the server stored {} of {} bytes",
+                key,
+                size,
+                data.len()
+            )));
+        }
+        if let (Some(sent), Some(listed)) = (sent_etag, stored.etag.as_deref().map(strong_etag))
+            && sent != listed
+        {
+            return Err(Error::other(format!("WebDAV PUT {} failed: it was replaced while uploading", key)));
+        }
+        Ok(())
+    }
+
+    // the size and etag are looked up first. the download is tied to that etag, so an
+    // object replaced in between isn't read half old and half new, and has to be complete
+    fn get(&self, key: &str) -> std::io::Result<Vec<u8>> {
+        let url = self.url_of(key);
+        let expected = self.propfind(&url, "0")?.into_iter().next().unwrap_or_default();
+        // weak etags never match an If-Match
+        let if_match = expected.etag.filter(|etag| !etag.starts_with("W/"));
+        let headers: Vec<(&str, &str)> = if_match.iter().map(|etag| ("If-Match", etag.as_str())).collect();
+
+        let response = self.send("GET", &url, &headers, &[], &[412])?;
+        if response.status() == 412 {
+            return Err(Error::other(format!("WebDAV GET {} failed: it changed while downloading", key)));
+        }
+        let mut data = Vec::new();
+        response.into_reader().read_to_end(&mut data)?;
+        if let Some(size) = expected.size
+            && size != data.len() as u64
+        {
+            return Err(Error::other(format!(
+                "WebDAV GET {} failed: got {} of {} bytes",
+                key,
+                data.len(),
+                size
+            )));
+        }
+        Ok(data)
+    }
+
+    fn list(&self, prefix: &str) -> std::io::Result<Vec<String>> {
+        let mut files = Vec::new();
+        self.list_collection(&self.folder_url(prefix), None, &mut files)?;
+        Ok(files.iter().map(|entry| self.key_of(&entry.path)).filter(|key| key.starts_with(prefix)).collect())
+    }
+
+    fn delete(&self, key: &str) -> std::io::Result<()> {
+        self.forget_listing(key.rsplit_once('/').map_or("", |(folder, _)| folder));
+        match self.send("DELETE", &self.url_of(key), &[], &[], &[]) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
+            result => result.map(|_| ()),
+        }
+    }
+
+    fn stat(&self, key: &str) -> std::io::Result<Option<ObjectStat>> {
+        match self.propfind(&self.url_of(key), "0") {
+            Ok(entries) => Ok(entries.first().map(|entry| ObjectStat { size: entry.size.unwrap_or(0) })),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::testing::{serve_http, HttpRequest, HttpResponse};
+    use std::sync::Arc;
+
+    #[test]
+    fn escapes_are_decoded() {
+        assert_eq!(percent_decode("/dav/a%20b%2Bc"), "/dav/a b+c");
+        assert_eq!(percent_decode("%C3%A4rger%2fx"), "ärger/x");
+        // anything that isn't an escape is kept as it is
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz%4"), "%zz%4");
+        assert_eq!(percent_decode("plain"), "plain");
+    }
+
+    #[test]
+    fn destinations_become_urls() {
+        let parse = |text: &str| parse_destination(Path::new(text));
+        assert_eq!(parse("davs://cloud.example.com/remote.php/dav/files/alice/backup/").as_deref(),
+            Some("https://cloud.example.com/remote.php/dav/files/alice/backup"));
+        assert_eq!(parse("dav://127.0.0.1:8080/my backups").as_deref(), Some("http://127.0.0.1:8080/my%20backups"));
+        assert_eq!(parse("dav://nas.local").as_deref(), Some("http://nas.local"));
+        assert_eq!(parse("dav:///backup"), None);
+        assert_eq!(parse("s3://bucket/backup"), None);
+    }
+
+    #[test]
+    fn multistatus_answers_are_parsed() {
+        // nextcloud style, full urls for hrefs and an empty collection element
+        let nextcloud = r#"<?xml version="1.0"?>
+<d:multistatus xmlns:d="DAV:" xmlns:s="http://sabredav.org/ns">
+ <d:response>
+  <d:href>https://cloud.example.com/remote.php/dav/files/alice/backup/objects/</d:href>
+  <d:propstat><d:prop><d:resourcetype><d:collection/></d:resourcetype><d:getetag>&quot;6523a2c1&quot;</d:getetag></d:prop>
+   <d:status>HTTP/1.1 200 OK</d:status></d:propstat>
+ </d:response>
+ <d:response>
+  <d:href>https://cloud.example.com/remote.php/dav/files/alice/backup/objects/a%20b.zst</d:href>
+  <d:propstat><d:prop><d:resourcetype/><d:getetag>"9f2c"</d:getetag><d:getcontentlength>1234</d:getcontentlength></d:prop>
+   <d:status>HTTP/1.1 200 OK</d:status></d:propstat>
+ </d:response>
+</d:multistatus>"#;
+        let entries = parse_multistatus(nextcloud);
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, "/remote.php/dav/files/alice/backup/objects/");
+        assert!(entries[0].is_collection);
+        assert_eq!(entries[0].etag.as_deref(), Some("\"6523a2c1\""));
+        assert_eq!(entries[1].path, "/remote.php/dav/files/alice/backup/objects/a b.zst");
+        assert!(!entries[1].is_collection);
+        assert_eq!((entries[1].etag.as_deref(), entries[1].size), (Some("\"9f2c\""), Some(1234)));
+
+        // apache style, hrefs as paths, another prefix and a collection with content
+        let apache = r#"<?xml version="1.0" encoding="utf-8"?>
+<D:multistatus xmlns:D="DAV:">
+<D:response><D:href>/backup/</D:href><D:propstat><D:prop>
+<D:resourcetype><D:collection></D:collection></D:resourcetype></D:prop></D:propstat></D:response>
+<D:response><D:href>/backup/metadata.json</D:href><D:propstat><D:prop>
+<D:resourcetype/><D:getcontentlength>17</D:getcontentlength></D:prop></D:propstat></D:response>
+</D:multistatus>"#;
+        let entries = parse_multistatus(apache);
+        assert_eq!(entries.len(), 2);
+        assert_eq!((entries[0].path.as_str(), entries[0].is_collection), ("/backup/", true));
+        assert_eq!((entries[1].path.as_str(), entries[1].is_collection, entries[1].size), ("/backup/metadata.json", false, Some(17)));
+        assert_eq!(entries[1].etag, None);
+    }
+
+    #[test]
+    fn hrefs_map_back_to_keys() {
+        let backend = WebdavBackend::new("https://cloud.example.com/dav/my%20backup".to_string(), WebdavSettings::default());
+        assert_eq!(backend.base_path(), "/dav/my backup/");
+        assert_eq!(backend.key_of("/dav/my backup/objects/ab/c d"), "objects/ab/c d");
+        assert_eq!(backend.url_of("objects/ab/c d"), "https://cloud.example.com/dav/my%20backup/objects/ab/c%20d");
+        assert_eq!(backend.folder_url("objects/ab"), "https://cloud.example.com/dav/my%20backup/objects/ab/");
+        assert_eq!(backend.path_of_url(&backend.folder_url("objects/ab")), "/dav/my backup/objects/ab/");
+    }
+
+    // a stand-in webdav server keeping files in memory. the flags make it misbehave the
+    // ways transfers have to notice
+    #[derive(Default)]
+    struct DavState {
+        // contents and how often each file was written, which makes up its etag
+        files: HashMap<String, (Vec<u8>, u32)>,
+        requests: Vec<String>,
+        // keeps all but the last byte of uploads
+        truncate_uploads: bool,
+        // sends all but the last byte of downloads
+        short_downloads: bool,
+        // replaces the file right after an upload, like another client would
+        change_after_upload: bool,
+        // replaces the file right before a download
+        change_before_download: bool,
+    }
+
+    fn etag_of(file: &(Vec<u8>, u32)) -> String {
+        format!("\"{}-{}\"", file.0.len(), file.1)
+    }
+
+    fn answer(state: &mut DavState, request: HttpRequest) -> HttpResponse {
+        let line = match request.header("If-Match") {
+            Some(etag) => format!("{} {} If-Match {}", request.method, request.target, etag),
+            None => format!("{} {}", request.method, request.target),
+        };
+        state.requests.push(line);
+        let path = request.target.clone();
+        match request.method.as_str() {
+            "MKCOL" => HttpResponse::new(201, ""),
+            "PUT" => {
+                let mut body = request.body;
+                if state.truncate_uploads {
+                    body.pop();
+                }
+                let writes = state.files.get(&path).map_or(0, |f| f.1) + 1;
+                state.files.insert(path.clone(), (body, writes));
+                let response = HttpResponse::new(201, "").with_header("ETag", &etag_of(&state.files[&path]));
+                if state.change_after_upload {
+                    state.files.get_mut(&path).unwrap().1 += 1;
+                }
+                response
+            }
+            "PROPFIND" => match state.files.get(&path) {
+                Some(file) => HttpResponse::new(
+                    207,
+                    format!(
+                        "<d:multistatus xmlns:d=\"DAV:\"><d:response><d:href>{}</d:href><d:propstat><d:prop>\
+                         <d:resourcetype/><d:getetag>{}</d:getetag><d:getcontentlength>{}</d:getcontentlength>\
+                         </d:prop></d:propstat></d:response></d:multistatus>",
+                        path,
+                        etag_of(file),
+                        file.0.len()
+                    ),
+                ),
+                None => HttpResponse::new(404, ""),
+            },
+            "GET" => {
+                if state.change_before_download
+                    && let Some(file) = state.files.get_mut(&path)
+                {
+                    file.1 += 1;
+                }
+                let Some(file) = state.files.get(&path) else { return HttpResponse::new(404, "") };
+                if request.header("If-Match").is_some_and(|etag| etag != etag_of(file)) {
+                    return HttpResponse::new(412, "");
+                }
+                let mut body = file.0.clone();
+                if state.short_downloads {
+                    body.pop();
+                }
+                HttpResponse::new(200, body)
+            }
+            _ => HttpResponse::new(501, ""),
+        }
+    }
+
+    #[test]
+    fn transfers_are_checked_against_what_the_server_lists() {
+        let state = Arc::new(Mutex::new(DavState::default()));
+        let server_state = state.clone();
+        let url = serve_http(move |request| answer(&mut server_state.lock().unwrap(), request));
+        let backend = WebdavBackend::new(format!("{}/dav", url), WebdavSettings { max_retries: 0, ..Default::default() });
+        let requests = || std::mem::take(&mut state.lock().unwrap().requests);
+
+        backend.put("objects/aa/one", b"hello").unwrap();
+        assert_eq!(requests(), vec![
+            "MKCOL /dav/objects/",
+            "MKCOL /dav/objects/aa/",
+            "PUT /dav/objects/aa/one",
+            "PROPFIND /dav/objects/aa/one",
+        ]);
+        assert_eq!(backend.get("objects/aa/one").unwrap(), b"hello");
+        assert_eq!(requests(), vec!["PROPFIND /dav/objects/aa/one", "GET /dav/objects/aa/one If-Match \"5-1\""]);
+
+        state.lock().unwrap().truncate_uploads = true;
+        let error = backend.put("objects/aa/two", b"hello").unwrap_err();
+        assert!(error.to_string().contains("stored 4 of 5 bytes"), "{}", error);
+        state.lock().unwrap().truncate_uploads = false;
+
+        state.lock().unwrap().change_after_upload = true;
+        let error = backend.put("objects/aa/two", b"hello").unwrap_err();
+        assert!(error.to_string().contains("replaced while uploading"), "{}", error);
+        state.lock().unwrap().change_after_upload = false;
+
+        state.lock().unwrap().short_downloads = true;
+        let error = backend.get("objects/aa/one").unwrap_err();
+        assert!(error.to_string().contains("got 4 of 5 bytes"), "{}", error);
+        state.lock().unwrap().short_downloads = false;
+
+        state.lock().unwrap().change_before_download = true;
+        let error = backend.get("objects/aa/one").unwrap_err();
+        assert!(error.to_string().contains("changed while downloading"), "{}", error);
+
+        assert_eq!(backend.get("objects/aa/missing").unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    // runs against a real server. FASS_TEST_WEBDAV_DESTINATION is an existing folder on it
+    // like dav://localhost:8080/backup, the login comes from FASS_TEST_WEBDAV_USER and
+    // FASS_WEBDAV_PASSWORD
+    #[test]
+    #[ignore]
+    fn objects_round_trip_through_a_real_server() {
+        let destination = std::env::var("FASS_TEST_WEBDAV_DESTINATION").expect("FASS_TEST_WEBDAV_DESTINATION isn't set");
+        let url = parse_destination(Path::new(&destination)).expect("not a dav:// or davs:// destination");
+        let settings = WebdavSettings {
+            username: std::env::var("FASS_TEST_WEBDAV_USER").unwrap_or_default(),
+            max_retries: 1,
+            ..Default::default()
+        };
+        let backend = WebdavBackend::new(format!("{}/fass-test-{}", url, std::process::id()), settings);
+
+        backend.check(false).unwrap();
+        backend.check(true).unwrap();
+        backend.put("objects/aa/one", b"hello").unwrap();
+        assert_eq!(backend.list("objects/").unwrap(), vec!["objects/aa/one"]);
+        // the listing cached above has to notice this backend's own changes
+        backend.put("objects/aa/two two", b"hello again").unwrap();
+        let mut keys = backend.list("objects/").unwrap();
+        keys.sort();
+        assert_eq!(keys, vec!["objects/aa/one", "objects/aa/two two"]);
+        backend.delete("objects/aa/one").unwrap();
+        assert_eq!(backend.list("objects/").unwrap(), vec!["objects/aa/two two"]);
+
+        assert_eq!(backend.get("objects/aa/two two").unwrap(), b"hello again");
+        assert_eq!(backend.stat("objects/aa/two two").unwrap().map(|s| s.size), Some(11));
+        assert!(backend.stat("objects/aa/one").unwrap().is_none());
+        assert_eq!(backend.get("objects/aa/one").unwrap_err().kind(), ErrorKind::NotFound);
+        for key in backend.list("").unwrap() {
+            backend.delete(&key).unwrap();
+        }
+        assert!(backend.list("").unwrap().is_empty());
+    }
+}